@@ -0,0 +1,82 @@
+//! Benchmarks for writing DEFLATE streams, which takes a noticeable share of
+//! the compression time of large inputs when few iterations are run.
+
+use std::num::NonZeroU64;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use zopfli::{compress_to_vec, lz77, Format, Options};
+
+/// Generates text made up of pseudorandom words, which compresses about as
+/// well as typical text files.
+fn text_data(len: usize) -> Vec<u8> {
+    const WORDS: [&[u8]; 16] = [
+        b"the ",
+        b"zopfli ",
+        b"deflate ",
+        b"block ",
+        b"of ",
+        b"a ",
+        b"huffman ",
+        b"tree ",
+        b"length ",
+        b"and ",
+        b"distance ",
+        b"window ",
+        b"is ",
+        b"to ",
+        b"data ",
+        b"stream.\n",
+    ];
+
+    let mut state = 1u32;
+    let mut data = Vec::with_capacity(len + 16);
+    while data.len() < len {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        data.extend_from_slice(WORDS[(state >> 16) as usize % WORDS.len()]);
+    }
+    data.truncate(len);
+    data
+}
+
+fn one_iteration_options() -> Options {
+    Options {
+        iteration_count: NonZeroU64::new(1).unwrap(),
+        ..Options::default()
+    }
+}
+
+fn write_blocks(c: &mut Criterion) {
+    let data = text_data(1 << 20);
+    let (store, splitpoints) = lz77::optimal_blocks(one_iteration_options(), &data, 0, data.len());
+
+    let mut group = c.benchmark_group("output");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("write_blocks", |b| {
+        b.iter(|| {
+            lz77::write_blocks(
+                one_iteration_options(),
+                &store,
+                &splitpoints,
+                &data,
+                Vec::with_capacity(data.len()),
+            )
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn compress_one_iteration(c: &mut Criterion) {
+    let data = text_data(256 * 1024);
+
+    let mut group = c.benchmark_group("compression");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+    group.bench_function("one_iteration", |b| {
+        b.iter(|| compress_to_vec(one_iteration_options(), Format::Deflate, black_box(&data)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, write_blocks, compress_one_iteration);
+criterion_main!(benches);
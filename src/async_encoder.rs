@@ -0,0 +1,483 @@
+use std::{
+    io,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{DeflateEncoder, Error, Options, Write};
+
+/// The amount of pending compressed bytes above which no new job is started
+/// until they are written to the sink.
+const OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// A blocking encoder that jobs feed with input.
+pub(crate) trait BlockingEncoder: Write + Send {
+    /// Finishes the compressed stream, writing its remaining bytes.
+    fn finish(self: Box<Self>) -> Result<(), Error>;
+
+    /// Drops the encoder without compressing the input it still holds, which
+    /// dropping it would otherwise do.
+    fn discard(self: Box<Self>);
+}
+
+impl BlockingEncoder for DeflateEncoder<OutputWriter> {
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        (*self).finish().map(drop)
+    }
+
+    fn discard(mut self: Box<Self>) {
+        DeflateEncoder::discard(&mut self);
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl BlockingEncoder for crate::GzipEncoder<OutputWriter> {
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        (*self).finish().map(drop)
+    }
+
+    fn discard(mut self: Box<Self>) {
+        crate::GzipEncoder::discard(&mut self);
+    }
+}
+
+#[cfg(feature = "zlib")]
+impl BlockingEncoder for crate::ZlibEncoder<OutputWriter> {
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        (*self).finish().map(drop)
+    }
+
+    fn discard(mut self: Box<Self>) {
+        crate::ZlibEncoder::discard(&mut self);
+    }
+}
+
+/// Creates the blocking encoder of an async encoder, which writes to the
+/// specified output.
+pub(crate) type MakeEncoder =
+    Box<dyn FnOnce(OutputWriter) -> Result<Box<dyn BlockingEncoder>, Error> + Send>;
+
+/// Runs a job on a thread where blocking is allowed.
+pub(crate) type SpawnBlocking = fn(Box<dyn FnOnce() + Send>);
+
+/// Writes bytes to the sink of an async encoder.
+pub(crate) type WriteSink<'a> = dyn FnMut(&mut Context<'_>, &[u8]) -> Poll<io::Result<usize>> + 'a;
+
+/// The state shared by an [`AsyncEncoder`] and its compression jobs.
+struct State {
+    /// The input that was not handed to a job yet.
+    input: Vec<u8>,
+    /// Whether all the input was written.
+    input_closed: bool,
+    /// The blocking encoder, while no job is compressing with it. It is
+    /// `None` while a job runs, and once the stream is finished or failed.
+    encoder: Option<Box<dyn BlockingEncoder>>,
+    /// The compressed bytes that were not written to the sink yet.
+    output: Vec<u8>,
+    /// Whether the compressed stream was finished.
+    stream_done: bool,
+    /// The error a job failed with, if any.
+    job_error: Option<Error>,
+    /// Whether the async encoder was dropped, so jobs should stop.
+    abandoned: bool,
+    /// The task that waits for a job to finish or produce output.
+    waker: Option<Waker>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl State {
+    fn wake_task(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The sink of a blocking encoder, which hands the compressed bytes to the
+/// async side.
+pub(crate) struct OutputWriter {
+    shared: Arc<Shared>,
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut state = self.shared.lock();
+        if state.abandoned {
+            return Err(abandoned_error());
+        }
+
+        state.output.extend_from_slice(buf);
+        state.wake_task();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The runtime-agnostic core of the async encoders: it buffers input, hands
+/// each master block of it to a blocking encoder in a job spawned on a
+/// blocking thread, and writes the compressed bytes to the sink through the
+/// provided polling functions. No thread is held between jobs.
+pub(crate) struct AsyncEncoder {
+    shared: Arc<Shared>,
+    spawn_blocking: SpawnBlocking,
+    /// The maximum amount of input bytes that are buffered before being
+    /// handed to a job, which compresses them as a master block.
+    input_limit: usize,
+    /// Compressed bytes taken from the shared state, which are being written
+    /// to the sink.
+    output: Vec<u8>,
+    output_written: usize,
+    failed: bool,
+}
+
+impl AsyncEncoder {
+    /// Creates an encoder that compresses data with the encoder returned by
+    /// `make_encoder`, in jobs started by `spawn_blocking`, each of which gets
+    /// a master block of the size set in `options`.
+    pub(crate) fn new(
+        options: &Options,
+        make_encoder: MakeEncoder,
+        spawn_blocking: SpawnBlocking,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                input: Vec::new(),
+                input_closed: false,
+                encoder: None,
+                output: Vec::new(),
+                stream_done: false,
+                job_error: None,
+                abandoned: false,
+                waker: None,
+            }),
+        });
+
+        // Creating the encoder only writes the container header, which does
+        // not need a blocking thread
+        let encoder = make_encoder(OutputWriter {
+            shared: Arc::clone(&shared),
+        });
+        {
+            let mut state = shared.lock();
+            match encoder {
+                Ok(encoder) => state.encoder = Some(encoder),
+                Err(error) => state.job_error = Some(error),
+            }
+        }
+
+        AsyncEncoder {
+            shared,
+            spawn_blocking,
+            input_limit: match options.master_block_size {
+                0 => usize::MAX,
+                size => size,
+            },
+            output: Vec::new(),
+            output_written: 0,
+            failed: false,
+        }
+    }
+
+    /// Starts a job that compresses the buffered input if there is a master
+    /// block of it, or the input was closed, unless a job is already running
+    /// or too much of its output is pending.
+    fn start_job(&self, state: &mut State) {
+        if state.input.len() < self.input_limit && !state.input_closed
+            || state.output.len() >= OUTPUT_LIMIT
+        {
+            return;
+        }
+        let encoder = match state.encoder.take() {
+            Some(encoder) => encoder,
+            None => return,
+        };
+
+        let input = core::mem::take(&mut state.input);
+        let is_last = state.input_closed;
+        let shared = Arc::clone(&self.shared);
+        (self.spawn_blocking)(Box::new(move || {
+            run_job(&shared, encoder, &input, is_last);
+        }));
+    }
+
+    /// Writes the compressed bytes produced so far to the sink. Returns
+    /// `Poll::Ready` once there are none left.
+    fn poll_write_output(
+        &mut self,
+        cx: &mut Context<'_>,
+        write_sink: &mut WriteSink<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.output_written == self.output.len() {
+                let mut state = self.shared.lock();
+                if state.output.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+
+                self.output.clear();
+                self.output_written = 0;
+                core::mem::swap(&mut self.output, &mut state.output);
+                self.start_job(&mut state);
+            }
+
+            match write_sink(cx, &self.output[self.output_written..]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                Poll::Ready(Ok(written)) => self.output_written += written,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Fails if a job failed, taking its error.
+    fn check_jobs(failed: &mut bool, state: &mut State) -> io::Result<()> {
+        if let Some(error) = state.job_error.take() {
+            *failed = true;
+            return Err(into_io_error(error));
+        }
+        if *failed {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "a previous compression error happened",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Buffers input for the next job, failing if a job failed.
+    pub(crate) fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        write_sink: &mut WriteSink<'_>,
+    ) -> Poll<io::Result<usize>> {
+        // Keep the output moving while accepting input. If the sink is not
+        // ready, it will wake the task when it is
+        if let Poll::Ready(Err(error)) = self.poll_write_output(cx, write_sink) {
+            return Poll::Ready(Err(error));
+        }
+
+        let mut state = self.shared.lock();
+        Self::check_jobs(&mut self.failed, &mut state)?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        self.start_job(&mut state);
+        let room = self.input_limit - state.input.len();
+        if room == 0 {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let accepted = room.min(buf.len());
+        state.input.extend_from_slice(&buf[..accepted]);
+        self.start_job(&mut state);
+        Poll::Ready(Ok(accepted))
+    }
+
+    /// Writes the compressed bytes produced so far to the sink, which should
+    /// then be flushed. Like the blocking encoders, this does not compress the
+    /// buffered input, as that would end a master block early.
+    pub(crate) fn poll_flush(
+        &mut self,
+        cx: &mut Context<'_>,
+        write_sink: &mut WriteSink<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_write_output(cx, write_sink)
+    }
+
+    /// Finishes the compressed stream and writes it to the sink, which should
+    /// then be closed.
+    pub(crate) fn poll_close(
+        &mut self,
+        cx: &mut Context<'_>,
+        write_sink: &mut WriteSink<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.shared.lock().input_closed = true;
+
+        loop {
+            match self.poll_write_output(cx, write_sink) {
+                Poll::Ready(Ok(())) => {}
+                poll => return poll,
+            }
+
+            let mut state = self.shared.lock();
+            Self::check_jobs(&mut self.failed, &mut state)?;
+            self.start_job(&mut state);
+            if state.output.is_empty() {
+                if state.stream_done {
+                    return Poll::Ready(Ok(()));
+                }
+
+                state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl Drop for AsyncEncoder {
+    fn drop(&mut self) {
+        let encoder = {
+            let mut state = self.shared.lock();
+            state.abandoned = true;
+            state.encoder.take()
+        };
+        // Nobody will read the output, so the pending input is not compressed.
+        // A running job discards the encoder once it is done instead
+        if let Some(encoder) = encoder {
+            encoder.discard();
+        }
+    }
+}
+
+/// Compresses some input of an [`AsyncEncoder`] with its blocking encoder,
+/// finishing the stream if it is the last input, and hands the encoder back
+/// for the next job otherwise.
+fn run_job(shared: &Shared, mut encoder: Box<dyn BlockingEncoder>, input: &[u8], is_last: bool) {
+    if shared.lock().abandoned {
+        encoder.discard();
+        return;
+    }
+
+    let result = match encoder.write_all(input) {
+        Ok(()) if is_last => encoder.finish().map(|()| None),
+        Ok(()) => Ok(Some(encoder)),
+        Err(error) => {
+            encoder.discard();
+            Err(error)
+        }
+    };
+
+    let mut state = shared.lock();
+    match result {
+        Ok(Some(encoder)) if state.abandoned => encoder.discard(),
+        Ok(Some(encoder)) => state.encoder = Some(encoder),
+        Ok(None) => state.stream_done = true,
+        Err(error) => state.job_error = Some(error),
+    }
+    state.wake_task();
+}
+
+fn abandoned_error() -> Error {
+    #[cfg(not(doc))]
+    {
+        Error::new(io::ErrorKind::Other, "async encoder dropped")
+    }
+    #[cfg(doc)]
+    {
+        crate::ErrorKind::Other.into()
+    }
+}
+
+/// Converts an error of the blocking encoders, which is a `std::io` error
+/// unless documentation is being generated.
+fn into_io_error(error: Error) -> io::Error {
+    #[cfg(not(doc))]
+    {
+        error
+    }
+    #[cfg(doc)]
+    {
+        let _ = error;
+        io::ErrorKind::Other.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        ops::ControlFlow,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Wake,
+    };
+
+    use super::*;
+    use crate::BlockType;
+
+    thread_local! {
+        static JOBS: RefCell<Vec<Box<dyn FnOnce() + Send>>> = RefCell::new(Vec::new());
+    }
+
+    /// Queues jobs to be run by [`run_jobs`], so that tests decide when they
+    /// run.
+    fn queue_job(job: Box<dyn FnOnce() + Send>) {
+        JOBS.with(|jobs| jobs.borrow_mut().push(job));
+    }
+
+    fn run_jobs() {
+        for job in JOBS.with(|jobs| jobs.take()) {
+            job();
+        }
+    }
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Creates an encoder with master blocks of 1000 bytes whose blocking
+    /// encoder counts its progress updates, and writes a master block to it.
+    fn encoder_with_a_master_block(updates: &Arc<AtomicUsize>) -> AsyncEncoder {
+        let options = Options {
+            master_block_size: 1000,
+            ..Options::default()
+        };
+        let progress_updates = Arc::clone(updates);
+        let mut encoder = AsyncEncoder::new(
+            &options,
+            Box::new(move |output| {
+                let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, output);
+                encoder.set_progress(move |_| {
+                    progress_updates.fetch_add(1, Ordering::Relaxed);
+                    ControlFlow::Continue(())
+                });
+                Ok(Box::new(encoder))
+            }),
+            queue_job,
+        );
+
+        let waker = Arc::new(NoopWaker).into();
+        let poll = encoder.poll_write(
+            &mut Context::from_waker(&waker),
+            &[b'a'; 1000],
+            &mut |_, buf| Poll::Ready(Ok(buf.len())),
+        );
+        assert!(matches!(poll, Poll::Ready(Ok(1000))));
+        encoder
+    }
+
+    #[test]
+    fn dropping_discards_the_input_of_the_blocking_encoder() {
+        let updates = Arc::new(AtomicUsize::new(0));
+
+        // The blocking encoder holds the master block once the job ran
+        let encoder = encoder_with_a_master_block(&updates);
+        run_jobs();
+        drop(encoder);
+        assert_eq!(updates.load(Ordering::Relaxed), 0);
+
+        // A job that runs after the encoder was dropped discards it
+        let encoder = encoder_with_a_master_block(&updates);
+        drop(encoder);
+        run_jobs();
+        assert_eq!(updates.load(Ordering::Relaxed), 0);
+    }
+}
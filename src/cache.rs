@@ -4,6 +4,7 @@ use core::cmp;
 use crate::{
     lz77::LongestMatch,
     util::{ZOPFLI_CACHE_LENGTH, ZOPFLI_MAX_MATCH, ZOPFLI_MIN_MATCH},
+    Options,
 };
 
 // Cache used by ZopfliFindLongestMatch to remember previously found length/dist
@@ -12,24 +13,72 @@ use crate::{
 // the same position.
 // Uses large amounts of memory, since it has to remember the distance belonging
 // to every possible shorter-than-the-best length (the so called "sublen" array).
+// The cache may be limited to the first positions of the block to save memory, in
+// which case it behaves like NoCache for the rest.
 pub struct ZopfliLongestMatchCache {
     length: Vec<u16>,
     dist: Vec<u16>,
     sublen: Vec<u8>,
+    /// The amount of length and distance pairs of the sublen array that are
+    /// stored for each position.
+    sublen_count: usize,
 }
 
 impl ZopfliLongestMatchCache {
     pub fn new(blocksize: usize) -> ZopfliLongestMatchCache {
+        Self::with_sublen_count(blocksize, ZOPFLI_CACHE_LENGTH)
+    }
+
+    /// Creates a cache for a block of `blocksize` bytes that fits in the memory
+    /// allowed by [`Options::maximum_cache_memory`], which is shared by the
+    /// caches used by different threads at the same time.
+    pub fn for_options(blocksize: usize, options: &Options) -> ZopfliLongestMatchCache {
+        if options.maximum_cache_memory == 0 {
+            return Self::new(blocksize);
+        }
+
+        #[cfg(feature = "parallel")]
+        let memory_limit = options.maximum_cache_memory / options.threads.get();
+        #[cfg(not(feature = "parallel"))]
+        let memory_limit = options.maximum_cache_memory;
+
+        Self::with_memory_limit(blocksize, memory_limit)
+    }
+
+    /// Creates a cache for a block of `blocksize` bytes that takes at most
+    /// `memory_limit` bytes. Fewer sublen pairs are stored for each position
+    /// first, and then only the first positions are cached.
+    fn with_memory_limit(blocksize: usize, memory_limit: usize) -> ZopfliLongestMatchCache {
+        let cache_size = |positions: usize, sublen_count: usize| {
+            positions.saturating_mul(2 * 2 + sublen_count * 3)
+        };
+
+        let sublen_count = (1..=ZOPFLI_CACHE_LENGTH)
+            .rev()
+            .find(|&sublen_count| cache_size(blocksize, sublen_count) <= memory_limit)
+            .unwrap_or(1);
+        let positions = cmp::min(blocksize, memory_limit / cache_size(1, sublen_count));
+
+        Self::with_sublen_count(positions, sublen_count)
+    }
+
+    fn with_sublen_count(positions: usize, sublen_count: usize) -> ZopfliLongestMatchCache {
         ZopfliLongestMatchCache {
             /* length > 0 and dist 0 is invalid combination, which indicates on purpose
             that this cache value is not filled in yet. */
-            length: vec![1; blocksize],
-            dist: vec![0; blocksize],
+            length: vec![1; positions],
+            dist: vec![0; positions],
             /* Rather large amount of memory. */
-            sublen: vec![0; ZOPFLI_CACHE_LENGTH * blocksize * 3],
+            sublen: vec![0; sublen_count * positions * 3],
+            sublen_count,
         }
     }
 
+    /// Returns whether values for the specified position can be cached.
+    fn has_position(&self, pos: usize) -> bool {
+        pos < self.length.len()
+    }
+
     fn length_at(&self, pos: usize) -> u16 {
         self.length[pos]
     }
@@ -48,11 +97,11 @@ impl ZopfliLongestMatchCache {
 
     /// Returns the length up to which could be stored in the cache.
     fn max_sublen(&self, pos: usize) -> u32 {
-        let start = ZOPFLI_CACHE_LENGTH * pos * 3;
+        let start = self.sublen_count * pos * 3;
         if self.sublen[start + 1] == 0 && self.sublen[start + 2] == 0 {
             return 0; // No sublen cached.
         }
-        self.sublen[start + ((ZOPFLI_CACHE_LENGTH - 1) * 3)] as u32 + 3
+        self.sublen[start + ((self.sublen_count - 1) * 3)] as u32 + 3
     }
 
     /// Stores sublen array in the cache.
@@ -61,7 +110,7 @@ impl ZopfliLongestMatchCache {
             return;
         }
 
-        let start = ZOPFLI_CACHE_LENGTH * pos * 3;
+        let start = self.sublen_count * pos * 3;
         let mut i = 3;
         let mut j = 0;
         let mut bestlength = 0;
@@ -72,16 +121,16 @@ impl ZopfliLongestMatchCache {
                 self.sublen[start + (j * 3 + 2)] = (sublen[i] >> 8).wrapping_rem(256) as u8;
                 bestlength = i as u32;
                 j += 1;
-                if j >= ZOPFLI_CACHE_LENGTH {
+                if j >= self.sublen_count {
                     break;
                 }
             }
             i += 1;
         }
 
-        if j < ZOPFLI_CACHE_LENGTH {
+        if j < self.sublen_count {
             debug_assert_eq!(bestlength, length as u32);
-            self.sublen[start + ((ZOPFLI_CACHE_LENGTH - 1) * 3)] = (bestlength - 3) as u8;
+            self.sublen[start + ((self.sublen_count - 1) * 3)] = (bestlength - 3) as u8;
         } else {
             debug_assert!(bestlength <= length as u32);
         }
@@ -94,11 +143,11 @@ impl ZopfliLongestMatchCache {
             return;
         }
 
-        let start = ZOPFLI_CACHE_LENGTH * pos * 3;
+        let start = self.sublen_count * pos * 3;
         let maxlength = self.max_sublen(pos) as usize;
         let mut prevlength = 0;
 
-        for j in 0..ZOPFLI_CACHE_LENGTH {
+        for j in 0..self.sublen_count {
             let length = self.sublen[start + (j * 3)] as usize + 3;
             let dist = self.sublen[start + (j * 3 + 1)] as u16
                 + 256 * self.sublen[start + (j * 3 + 2)] as u16;
@@ -166,6 +215,9 @@ impl Cache for ZopfliLongestMatchCache {
         /* The LMC cache starts at the beginning of the block rather than the
         beginning of the whole array. */
         let lmcpos = pos - blockstart;
+        if !self.has_position(lmcpos) {
+            return longest_match;
+        }
 
         /* Length > 0 and dist 0 is invalid combination, which indicates on purpose
         that this cache value is not filled in yet. */
@@ -218,6 +270,9 @@ impl Cache for ZopfliLongestMatchCache {
             /* Length > 0 and dist 0 is invalid combination, which indicates on purpose
             that this cache value is not filled in yet. */
             let lmcpos = pos - blockstart;
+            if !self.has_position(lmcpos) {
+                return;
+            }
             let cache_available = self.length_at(lmcpos) == 0 || self.dist_at(lmcpos) != 0;
 
             if limit == ZOPFLI_MAX_MATCH && !cache_available {
@@ -235,3 +290,52 @@ impl Cache for ZopfliLongestMatchCache {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_limit_shrinks_the_cache() {
+        let cache_size = |cache: &ZopfliLongestMatchCache| {
+            cache.length.len() * 2 + cache.dist.len() * 2 + cache.sublen.len()
+        };
+
+        let cache = ZopfliLongestMatchCache::with_memory_limit(1000, usize::MAX);
+        assert_eq!(
+            (cache.length.len(), cache.sublen_count),
+            (1000, ZOPFLI_CACHE_LENGTH)
+        );
+
+        let cache = ZopfliLongestMatchCache::with_memory_limit(1000, 20_000);
+        assert_eq!((cache.length.len(), cache.sublen_count), (1000, 5));
+        assert!(cache_size(&cache) <= 20_000);
+
+        let cache = ZopfliLongestMatchCache::with_memory_limit(1000, 3_500);
+        assert_eq!((cache.length.len(), cache.sublen_count), (500, 1));
+        assert!(cache_size(&cache) <= 3_500);
+
+        let cache = ZopfliLongestMatchCache::with_memory_limit(1000, 5);
+        assert_eq!(cache.length.len(), 0);
+        assert!(!cache.try_get(0, ZOPFLI_MAX_MATCH, &mut None, 0).from_cache);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn memory_limit_does_not_change_the_output() {
+        let data = crate::test_util::block_splittable_data(100_000);
+        let compress = |maximum_cache_memory| {
+            let options = Options {
+                iteration_count: core::num::NonZeroU64::new(5).unwrap(),
+                maximum_cache_memory,
+                ..Options::default()
+            };
+            crate::deflate_slice(options, crate::BlockType::Dynamic, &data, vec![]).unwrap()
+        };
+
+        let expected = compress(0);
+        for maximum_cache_memory in [2_000_000, 500_000, 200_000, 1] {
+            assert_eq!(compress(maximum_cache_memory), expected);
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use alloc::vec::Vec;
-use core::{cmp, iter};
+use alloc::{boxed::Box, vec::Vec};
+use core::{cmp, iter, ops::ControlFlow};
 
 use log::{debug, log_enabled};
 
@@ -9,7 +9,9 @@ use crate::{
     iter::ToFlagLastIterator,
     katajainen::length_limited_code_lengths,
     lz77::{LitLen, Lz77Store},
+    progress::{cancelled_error, ProgressReporter, ProgressStage},
     squeeze::{lz77_optimal, lz77_optimal_fixed},
+    stats::{CompressionStats, Stopwatch},
     symbols::{
         get_dist_extra_bits, get_dist_extra_bits_value, get_dist_symbol,
         get_dist_symbol_extra_bits, get_length_extra_bits, get_length_extra_bits_value,
@@ -17,27 +19,33 @@ use crate::{
     },
     tree::lengths_to_symbols,
     util::{ZOPFLI_NUM_D, ZOPFLI_NUM_LL, ZOPFLI_WINDOW_SIZE},
-    Error, Options, Write,
+    Error, Options, Progress, Write,
 };
 
 /// A DEFLATE encoder powered by the Zopfli algorithm that compresses data written
 /// to it to the specified sink. Most users will find using [`compress`](crate::compress)
 /// easier and more performant.
 ///
-/// The data will be compressed as soon as possible, without trying to fill a
-/// backreference window. As a consequence, frequent short writes may cause more
-/// DEFLATE blocks to be emitted with less optimal Huffman trees, which can hurt
-/// compression and runtime. If they are a concern, short writes can be conveniently
-/// dealt with by wrapping this encoder with a [`BufWriter`](std::io::BufWriter), as done
-/// by the [`new_buffered`](DeflateEncoder::new_buffered) method. An adequate write size
-/// would be >32 KiB, which allows the second complete chunk to leverage a full-sized
-/// backreference window.
+/// Written data is collected into master blocks of
+/// [`Options::master_block_size`] bytes, which are compressed independently as
+/// soon as they are complete, so the size of the writes does not affect the
+/// output and short writes need no further buffering.
+///
+/// [Flushing](Write::flush) the encoder only flushes its sink by default, so the
+/// data written so far may not be decodable yet. A [`FlushMode`] can be set with
+/// [`set_flush_mode`](DeflateEncoder::set_flush_mode) to make flushes compress it.
 pub struct DeflateEncoder<W: Write> {
     options: Options,
     btype: BlockType,
     have_chunk: bool,
     chunk_start: usize,
     window_and_chunk: Vec<u8>,
+    #[cfg(feature = "parallel")]
+    pending_chunks: Vec<(Vec<u8>, usize)>,
+    progress: Option<Box<dyn Progress + Send>>,
+    bytes_processed: u64,
+    cancelled: bool,
+    flush_mode: FlushMode,
     bitwise_writer: Option<BitwiseWriter<W>>,
 }
 
@@ -51,14 +59,43 @@ impl<W: Write> DeflateEncoder<W> {
             have_chunk: false,
             chunk_start: 0,
             window_and_chunk: Vec::with_capacity(ZOPFLI_WINDOW_SIZE),
+            #[cfg(feature = "parallel")]
+            pending_chunks: Vec::new(),
+            progress: None,
+            bytes_processed: 0,
+            cancelled: false,
+            flush_mode: FlushMode::None,
             bitwise_writer: Some(BitwiseWriter::new(sink)),
         }
     }
 
+    /// Creates a new Zopfli DEFLATE encoder that will operate according to the
+    /// specified options, using the specified preset dictionary as initial
+    /// backreference window. Only the last 32 KiB of the dictionary can be
+    /// referenced, so any bytes before them are ignored.
+    ///
+    /// The generated stream can only be decompressed by decoders that are
+    /// given the same dictionary. Preset dictionaries help compressing small
+    /// inputs that share content with the dictionary, such as messages that
+    /// follow a common schema.
+    pub fn new_with_dictionary(
+        options: Options,
+        btype: BlockType,
+        dictionary: &[u8],
+        sink: W,
+    ) -> Self {
+        let dictionary = &dictionary[dictionary.len().saturating_sub(ZOPFLI_WINDOW_SIZE)..];
+
+        let mut encoder = Self::new(options, btype, sink);
+        encoder.window_and_chunk.extend_from_slice(dictionary);
+        encoder.chunk_start = dictionary.len();
+        encoder
+    }
+
     /// Creates a new Zopfli DEFLATE encoder that operates according to the
-    /// specified options and is wrapped with a buffer to guarantee that
-    /// data is compressed in large chunks, which is necessary for decent
-    /// performance and good compression ratio.
+    /// specified options and is wrapped with a buffer of the default master
+    /// block size. The encoder collects written data into master blocks by
+    /// itself, so this buffer only reduces the number of calls to it.
     #[cfg(feature = "std")]
     pub fn new_buffered(options: Options, btype: BlockType, sink: W) -> std::io::BufWriter<Self> {
         std::io::BufWriter::with_capacity(
@@ -67,6 +104,23 @@ impl<W: Write> DeflateEncoder<W> {
         )
     }
 
+    /// Creates a new Zopfli DEFLATE encoder that operates according to the
+    /// specified options and preset dictionary, as described in
+    /// [`new_with_dictionary`](Self::new_with_dictionary), and is wrapped with
+    /// a buffer of the default master block size.
+    #[cfg(feature = "std")]
+    pub fn new_buffered_with_dictionary(
+        options: Options,
+        btype: BlockType,
+        dictionary: &[u8],
+        sink: W,
+    ) -> std::io::BufWriter<Self> {
+        std::io::BufWriter::with_capacity(
+            crate::util::ZOPFLI_MASTER_BLOCK_SIZE,
+            Self::new_with_dictionary(options, btype, dictionary, sink),
+        )
+    }
+
     /// Encodes any pending chunks of data and writes them to the sink,
     /// consuming the encoder and returning the wrapped sink. The sink
     /// will have received a complete DEFLATE stream when this method
@@ -76,33 +130,170 @@ impl<W: Write> DeflateEncoder<W> {
     /// dropped, but explicitly finishing it with this method allows
     /// handling I/O errors.
     pub fn finish(mut self) -> Result<W, Error> {
-        self._finish().map(|sink| sink.unwrap())
+        self._finish().map(|finished| finished.unwrap().0)
+    }
+
+    /// Like [`finish`](Self::finish), but also returns statistics about the
+    /// compressed stream.
+    pub fn finish_with_stats(mut self) -> Result<(W, CompressionStats), Error> {
+        self._finish().map(Option::unwrap)
+    }
+
+    /// Sets a callback that will be notified of the progress of the
+    /// compression, and may cancel it, as described in [`Progress`].
+    /// This replaces any previously set callback.
+    pub fn set_progress(&mut self, progress: impl Progress + Send + 'static) {
+        self.progress = Some(Box::new(progress));
+    }
+
+    /// Sets what [flushing](Write::flush) the encoder does with the data
+    /// written to it before flushing its sink, as described in [`FlushMode`].
+    pub fn set_flush_mode(&mut self, flush_mode: FlushMode) {
+        self.flush_mode = flush_mode;
+    }
+
+    /// Gets a mutable reference to the sink, which has received the
+    /// compressed data of the chunks encoded so far.
+    #[cfg(feature = "std")]
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.bitwise_writer.as_mut().unwrap().out
+    }
+
+    /// Drops the data written so far without compressing it, leaving the
+    /// stream unfinished, for when its output is no longer wanted. The
+    /// encoder then does nothing when dropped.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub(crate) fn discard(&mut self) {
+        self.bitwise_writer = None;
+        #[cfg(feature = "parallel")]
+        self.pending_chunks.clear();
+        self.window_and_chunk = Vec::new();
+    }
+
+    /// Finishes the DEFLATE stream written so far and starts a new one on
+    /// the same sink, without a backreference window. `between` may write
+    /// other data to the sink after the finished stream. The progress
+    /// callback keeps counting processed bytes across streams.
+    ///
+    /// Returns the statistics of the finished stream.
+    #[cfg(feature = "gzip")]
+    pub(crate) fn restart(
+        &mut self,
+        between: impl FnOnce(&mut W) -> Result<(), Error>,
+    ) -> Result<CompressionStats, Error> {
+        self.compress_chunk(true)?;
+
+        let mut bitwise_writer = self.bitwise_writer.take().unwrap();
+        bitwise_writer.finish_partial_bits()?;
+        let (sink, stats) = bitwise_writer.into_inner_with_stats()?;
+
+        self.have_chunk = false;
+        self.chunk_start = 0;
+        self.window_and_chunk.clear();
+        let bitwise_writer = self.bitwise_writer.insert(BitwiseWriter::new(sink));
+        between(&mut bitwise_writer.out)?;
+
+        Ok(stats)
     }
 
     /// Compresses the chunk stored at `window_and_chunk`. This includes
     /// a rolling window of the last `ZOPFLI_WINDOW_SIZE` data bytes, if
     /// available.
+    ///
+    /// When several threads are available, the chunk is queued instead,
+    /// and the queue is compressed in parallel once it has a chunk for
+    /// every thread or the last chunk is reached.
     #[inline]
     fn compress_chunk(&mut self, is_last: bool) -> Result<(), Error> {
-        deflate_part(
+        if self.cancelled {
+            return Err(cancelled_error());
+        }
+
+        #[cfg(feature = "parallel")]
+        if self.options.threads.get() > 1 {
+            self.pending_chunks
+                .push((self.window_and_chunk.clone(), self.chunk_start));
+
+            return if is_last || self.pending_chunks.len() >= self.options.threads.get() {
+                self.compress_pending_chunks(is_last)
+            } else {
+                Ok(())
+            };
+        }
+
+        let progress = Self::progress_reporter(&mut self.progress, self.bytes_processed);
+        let result = deflate_part(
             &self.options,
             self.btype,
             is_last,
             &self.window_and_chunk,
             self.chunk_start,
             self.window_and_chunk.len(),
+            &progress,
             self.bitwise_writer.as_mut().unwrap(),
+        );
+        let totals = progress.into_totals();
+        self.save_progress(totals);
+        result?;
+
+        // Hand the compressed data over to the sink once per chunk, so that
+        // it can be read from there
+        self.bitwise_writer.as_mut().unwrap().write_buffer()
+    }
+
+    /// Returns a reporter for the progress of compressing the next chunks,
+    /// which continues counting processed bytes where the previous one
+    /// left off.
+    fn progress_reporter(
+        progress: &mut Option<Box<dyn Progress + Send>>,
+        bytes_processed: u64,
+    ) -> ProgressReporter<'_> {
+        ProgressReporter::new(
+            progress
+                .as_deref_mut()
+                .map(|progress| -> &mut (dyn Progress + Send) { progress }),
+            bytes_processed,
         )
     }
 
-    /// Sets the next chunk that will be compressed by the next
-    /// call to `compress_chunk` and updates the rolling data window
-    /// accordingly.
-    fn set_chunk(&mut self, chunk: &[u8]) {
+    /// Saves the totals of a reporter returned by `progress_reporter` once
+    /// it is no longer used. A cancellation makes the encoder unusable.
+    fn save_progress(&mut self, totals: Option<(u64, bool)>) {
+        if let Some((bytes_processed, cancelled)) = totals {
+            self.bytes_processed = bytes_processed;
+            self.cancelled = cancelled;
+        }
+    }
+
+    /// Compresses all the chunks queued by `compress_chunk` at once, in
+    /// parallel.
+    #[cfg(feature = "parallel")]
+    fn compress_pending_chunks(&mut self, is_last: bool) -> Result<(), Error> {
+        let chunks = core::mem::take(&mut self.pending_chunks);
+        let progress = Self::progress_reporter(&mut self.progress, self.bytes_processed);
+        let result = deflate_parts(
+            &self.options,
+            self.btype,
+            &chunks,
+            is_last,
+            &progress,
+            self.bitwise_writer.as_mut().unwrap(),
+        );
+        let totals = progress.into_totals();
+        self.save_progress(totals);
+        result?;
+
+        self.bitwise_writer.as_mut().unwrap().write_buffer()
+    }
+
+    /// Starts a new, empty chunk that written data is appended to until
+    /// it is compressed by `compress_chunk`, and updates the rolling data
+    /// window accordingly.
+    fn start_chunk(&mut self) {
         // Remove bytes exceeding the window size. Start with the
         // oldest bytes, which are at the beginning of the buffer.
-        // The buffer length is then the position where the chunk
-        // we've just received starts
+        // The buffer length is then the position where the new
+        // chunk starts
         self.window_and_chunk.drain(
             ..self
                 .window_and_chunk
@@ -111,17 +302,41 @@ impl<W: Write> DeflateEncoder<W> {
         );
         self.chunk_start = self.window_and_chunk.len();
 
-        self.window_and_chunk.extend_from_slice(chunk);
-
         self.have_chunk = true;
     }
 
+    /// Compresses the pending chunk, if any, as described in [`FlushMode`].
+    fn compress_flushed_chunk(&mut self) -> Result<(), Error> {
+        if self.flush_mode == FlushMode::None || !self.have_chunk {
+            return Ok(());
+        }
+
+        self.compress_chunk(false)?;
+        #[cfg(feature = "parallel")]
+        if !self.pending_chunks.is_empty() {
+            self.compress_pending_chunks(false)?;
+        }
+        let bitwise_writer = self.bitwise_writer.as_mut().unwrap();
+        add_flush_marker(bitwise_writer)?;
+        bitwise_writer.write_buffer()?;
+
+        // The chunk becomes part of the window of the next one, unless
+        // the window is reset
+        self.have_chunk = false;
+        if self.flush_mode == FlushMode::Full {
+            self.window_and_chunk.clear();
+        }
+        self.chunk_start = self.window_and_chunk.len();
+
+        Ok(())
+    }
+
     /// Encodes the last chunk and finishes any partial bits.
     /// The encoder will be unusable for further compression
     /// after this method returns. This is intended to be an
     /// implementation detail of the `Drop` trait and
     /// [`finish`](Self::finish) method.
-    fn _finish(&mut self) -> Result<Option<W>, Error> {
+    fn _finish(&mut self) -> Result<Option<(W, CompressionStats)>, Error> {
         if self.bitwise_writer.is_none() {
             return Ok(None);
         }
@@ -131,27 +346,51 @@ impl<W: Write> DeflateEncoder<W> {
         let mut bitwise_writer = self.bitwise_writer.take().unwrap();
         bitwise_writer.finish_partial_bits()?;
 
-        Ok(Some(bitwise_writer.out))
+        bitwise_writer.into_inner_with_stats().map(Some)
     }
 }
 
 impl<W: Write> Write for DeflateEncoder<W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        // Any previous chunk is known to be non-last at this point,
-        // so compress it now
-        if self.have_chunk {
-            self.compress_chunk(false)?;
-        }
+        let master_block_size = match self.options.master_block_size {
+            0 => usize::MAX,
+            size => size,
+        };
+
+        let mut rest = buf;
+        while !rest.is_empty() {
+            // A full chunk is known to be non-last once there is more data
+            // to write, so compress it now. Report the data that was already
+            // appended to it as written if that fails
+            if self.have_chunk
+                && self.window_and_chunk.len() - self.chunk_start >= master_block_size
+            {
+                match self.compress_chunk(false) {
+                    Ok(()) => self.have_chunk = false,
+                    Err(_) if rest.len() < buf.len() => break,
+                    Err(err) => return Err(err),
+                }
+            }
 
-        // Set the chunk to be used for the next compression operation
-        // to this chunk. We don't know whether it's last or not yet
-        self.set_chunk(buf);
+            // We don't know whether the chunk is last or not yet, so keep
+            // collecting data into it until it is full
+            if !self.have_chunk {
+                self.start_chunk();
+            }
+            let space = master_block_size - (self.window_and_chunk.len() - self.chunk_start);
+            let (data, remaining) = rest.split_at(cmp::min(space, rest.len()));
+            self.window_and_chunk.extend_from_slice(data);
+            rest = remaining;
+        }
 
-        Ok(buf.len())
+        Ok(buf.len() - rest.len())
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        self.bitwise_writer.as_mut().unwrap().out.flush()
+        self.compress_flushed_chunk()?;
+        let bitwise_writer = self.bitwise_writer.as_mut().unwrap();
+        bitwise_writer.write_buffer()?;
+        bitwise_writer.out.flush()
     }
 }
 
@@ -173,6 +412,81 @@ impl<W: crate::io::Write> std::io::Write for DeflateEncoder<W> {
     }
 }
 
+/// Compresses data that is entirely in memory with the Zopfli algorithm,
+/// writing a DEFLATE stream to the specified sink and returning it.
+///
+/// This generates the same stream as a [`DeflateEncoder`] that is written
+/// the data in master block sized chunks, as [`compress`](crate::compress)
+/// does, but the data is compressed in place instead of being copied to the
+/// encoder buffers. It does not depend on the standard library.
+///
+/// ```
+/// # use zopfli::{deflate_slice, BlockType, Options};
+/// let compressed_data = deflate_slice(
+///     Options::default(),
+///     BlockType::Dynamic,
+///     b"Hello, Zopfli!",
+///     Vec::new(),
+/// )
+/// .expect("Writing to a Vec should not fail");
+/// ```
+pub fn deflate_slice<W: Write>(
+    options: Options,
+    btype: BlockType,
+    data: &[u8],
+    sink: W,
+) -> Result<W, Error> {
+    let mut bitwise_writer = BitwiseWriter::new(sink);
+    let progress = ProgressReporter::new(None, 0);
+
+    // Each part is a master block, preceded by the window of bytes that
+    // its backreferences may point to. Empty data still gets a part, so
+    // that a final block is written
+    let master_block_size = match options.master_block_size {
+        0 => cmp::max(data.len(), 1),
+        size => size,
+    };
+    let parts = (0..cmp::max(data.len(), 1))
+        .step_by(master_block_size)
+        .map(|start| {
+            let window_start = start.saturating_sub(ZOPFLI_WINDOW_SIZE);
+            let end = cmp::min(start.saturating_add(master_block_size), data.len());
+            (&data[window_start..end], start - window_start)
+        });
+
+    #[cfg(feature = "parallel")]
+    if options.threads.get() > 1 {
+        let parts = parts.collect::<Vec<_>>();
+        for (parts, is_last) in parts.chunks(options.threads.get()).flag_last() {
+            deflate_parts(
+                &options,
+                btype,
+                parts,
+                is_last,
+                &progress,
+                &mut bitwise_writer,
+            )?;
+        }
+        bitwise_writer.finish_partial_bits()?;
+        return bitwise_writer.into_inner();
+    }
+
+    for ((part, part_start), is_last) in parts.flag_last() {
+        deflate_part(
+            &options,
+            btype,
+            is_last,
+            part,
+            part_start,
+            part.len(),
+            &progress,
+            &mut bitwise_writer,
+        )?;
+    }
+    bitwise_writer.finish_partial_bits()?;
+    bitwise_writer.into_inner()
+}
+
 /// Deflate a part, to allow for chunked, streaming compression with [`DeflateEncoder`].
 /// It is possible to call this function multiple times in a row, shifting
 /// instart and inend to next bytes of the data. If instart is larger than 0, then
@@ -182,6 +496,7 @@ impl<W: crate::io::Write> std::io::Write for DeflateEncoder<W> {
 /// Like deflate, but allows to specify start and end byte with instart and
 /// inend. Only that part is compressed, but earlier bytes are still used for the
 /// back window.
+#[allow(clippy::too_many_arguments)] // Not feasible to refactor in a more readable way
 fn deflate_part<W: Write>(
     options: &Options,
     btype: BlockType,
@@ -189,25 +504,31 @@ fn deflate_part<W: Write>(
     in_data: &[u8],
     instart: usize,
     inend: usize,
+    progress: &ProgressReporter,
     bitwise_writer: &mut BitwiseWriter<W>,
 ) -> Result<(), Error> {
+    let bits_before = bitwise_writer.bits_written();
+
     /* If btype=Dynamic is specified, it tries all block types. If a lesser btype is
     given, then however it forces that one. Neither of the lesser types needs
     block splitting as they have no dynamic huffman trees. */
-    match btype {
+    let processed_bytes = match btype {
         BlockType::Uncompressed => {
-            add_non_compressed_block(final_block, in_data, instart, inend, bitwise_writer)
+            add_non_compressed_block(final_block, in_data, instart, inend, bitwise_writer)?;
+            inend - instart
         }
         BlockType::Fixed => {
             let mut store = Lz77Store::new();
 
+            let stopwatch = Stopwatch::start();
             lz77_optimal_fixed(
-                &mut ZopfliLongestMatchCache::new(inend - instart),
+                &mut ZopfliLongestMatchCache::for_options(inend - instart, options),
                 in_data,
                 instart,
                 inend,
                 &mut store,
             );
+            bitwise_writer.stats.squeeze_time += stopwatch.elapsed();
             add_lz77_block(
                 btype,
                 final_block,
@@ -217,21 +538,158 @@ fn deflate_part<W: Write>(
                 store.size(),
                 0,
                 bitwise_writer,
-            )
+            )?;
+            inend - instart
         }
-        BlockType::Dynamic => blocksplit_attempt(
+        BlockType::Dynamic => {
+            let (lz77, splitpoints, stats) =
+                blocksplit_attempt(options, in_data, instart, inend, progress);
+            if progress.is_cancelled() {
+                return Err(cancelled_error());
+            }
+            bitwise_writer.stats.add_optimization(stats);
+            add_all_blocks(
+                options,
+                &splitpoints,
+                &lz77,
+                final_block,
+                in_data,
+                bitwise_writer,
+            )?;
+            // Already reported by each block split segment
+            0
+        }
+    };
+
+    let bits = bitwise_writer.bits_written() - bits_before;
+    match progress.report(
+        ProgressStage::MasterBlock,
+        processed_bytes as u64,
+        bits as f64,
+    ) {
+        ControlFlow::Continue(()) => Ok(()),
+        ControlFlow::Break(()) => Err(cancelled_error()),
+    }
+}
+
+/// Like [`deflate_part`], but compresses several consecutive parts, each
+/// given with its back window and start position, running the expensive
+/// LZ77 optimization and block splitting of each part on its own thread.
+/// The resulting blocks are written in order, so the output is the same as
+/// if every part was compressed sequentially.
+#[cfg(feature = "parallel")]
+fn deflate_parts<W: Write, D: AsRef<[u8]> + Sync>(
+    options: &Options,
+    btype: BlockType,
+    chunks: &[(D, usize)],
+    is_last: bool,
+    progress: &ProgressReporter,
+    bitwise_writer: &mut BitwiseWriter<W>,
+) -> Result<(), Error> {
+    if btype != BlockType::Dynamic {
+        // The other block types do not do block splitting, so there is
+        // no expensive work worth parallelizing
+        for ((data, chunk_start), is_last_chunk) in chunks.iter().flag_last() {
+            let data = data.as_ref();
+            deflate_part(
+                options,
+                btype,
+                is_last && is_last_chunk,
+                data,
+                *chunk_start,
+                data.len(),
+                progress,
+                bitwise_writer,
+            )?;
+        }
+        return Ok(());
+    }
+
+    // Share the available threads between the chunks, so that the
+    // remaining ones can be used for optimizing block split segments
+    let mut chunk_options = *options;
+    chunk_options.threads =
+        core::num::NonZeroUsize::new(cmp::max(options.threads.get() / chunks.len(), 1)).unwrap();
+    if options.maximum_cache_memory > 0 {
+        chunk_options.maximum_cache_memory =
+            cmp::max(options.maximum_cache_memory / chunks.len(), 1);
+    }
+
+    let options = &chunk_options;
+    let results = std::thread::scope(|scope| {
+        chunks
+            .iter()
+            .map(|(data, chunk_start)| {
+                let data = data.as_ref();
+                scope.spawn(move || {
+                    blocksplit_attempt(options, data, *chunk_start, data.len(), progress)
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    if progress.is_cancelled() {
+        return Err(cancelled_error());
+    }
+
+    for (((data, _), (lz77, splitpoints, stats)), is_last_chunk) in
+        chunks.iter().zip(results).flag_last()
+    {
+        bitwise_writer.stats.add_optimization(stats);
+        let bits_before = bitwise_writer.bits_written();
+        add_all_blocks(
             options,
-            final_block,
-            in_data,
-            instart,
-            inend,
+            &splitpoints,
+            &lz77,
+            is_last && is_last_chunk,
+            data.as_ref(),
             bitwise_writer,
-        ),
+        )?;
+        let bits = bitwise_writer.bits_written() - bits_before;
+
+        if progress
+            .report(ProgressStage::MasterBlock, 0, bits as f64)
+            .is_break()
+        {
+            return Err(cancelled_error());
+        }
     }
+
+    Ok(())
+}
+
+/// What [flushing](Write::flush) a [`DeflateEncoder`], or the encoders for
+/// other formats that use it, does with the data written to it.
+///
+/// Flushes that compress data let streaming consumers, such as network peers,
+/// decode everything written so far without waiting for the end of the stream.
+/// As the data of each flush is compressed on its own, frequent flushes hurt
+/// compression.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum FlushMode {
+    /// Only flush the sink, leaving the data written since the last write
+    /// pending until more data is written or the encoder is finished.
+    #[default]
+    None,
+    /// Compress the pending data into non-final blocks followed by an empty
+    /// uncompressed block, which aligns the stream to a byte boundary, like
+    /// `Z_SYNC_FLUSH` does in zlib.
+    Sync,
+    /// Like [`Sync`](Self::Sync), but also reset the backreference window,
+    /// like `Z_FULL_FLUSH` does in zlib. Decompression can then start at the
+    /// flush point, given the state of the container format, if any.
+    Full,
 }
 
 /// The type of data blocks to generate for a DEFLATE stream.
-#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
 #[cfg_attr(all(test, feature = "std"), derive(proptest_derive::Arbitrary))]
 pub enum BlockType {
     /// Non-compressed blocks (BTYPE=00).
@@ -254,15 +712,10 @@ pub enum BlockType {
     /// types that minimizes data size. The emitted block types may be
     /// [`Uncompressed`](Self::Uncompressed) or [`Fixed`](Self::Fixed), in
     /// addition to compressed with dynamic Huffman codes (BTYPE=10).
+    #[default]
     Dynamic,
 }
 
-impl Default for BlockType {
-    fn default() -> Self {
-        Self::Dynamic
-    }
-}
-
 fn fixed_tree() -> (Vec<u32>, Vec<u32>) {
     let mut ll = Vec::with_capacity(ZOPFLI_NUM_LL);
     ll.resize(144, 8);
@@ -726,7 +1179,7 @@ fn encode_tree<W: Write>(
     }
 
     let clcl = length_limited_code_lengths(&clcounts, 7);
-    let clsymbols = lengths_to_symbols(&clcl, 7);
+    let clsymbols = reversed_huffman_codes(&clcl, 7);
 
     let mut hclen = 15;
     /* Trim zeros. */
@@ -746,7 +1199,7 @@ fn encode_tree<W: Write>(
         let rle_i = rle[i] as usize;
         let rle_bits_i = rle_bits[i] as u32;
         let sym = clsymbols[rle_i];
-        bitwise_writer.add_huffman_bits(sym, clcl[rle_i])?;
+        bitwise_writer.add_bits(sym, clcl[rle_i])?;
         /* Extra bits. */
         if rle_i == 16 {
             bitwise_writer.add_bits(rle_bits_i, 2)?;
@@ -828,6 +1281,9 @@ fn add_lz77_block<W: Write>(
         return add_non_compressed_block(final_block, in_data, pos, end, bitwise_writer);
     }
 
+    bitwise_writer
+        .stats
+        .add_block(btype, lz77.get_byte_range(lstart, lend));
     bitwise_writer.add_bit(final_block as u8)?;
 
     let (ll_lengths, d_lengths) = match btype {
@@ -852,8 +1308,8 @@ fn add_lz77_block<W: Write>(
         }
     };
 
-    let ll_symbols = lengths_to_symbols(&ll_lengths, 15);
-    let d_symbols = lengths_to_symbols(&d_lengths, 15);
+    let ll_symbols = reversed_huffman_codes(&ll_lengths, 15);
+    let d_symbols = reversed_huffman_codes(&d_lengths, 15);
 
     let detect_block_size = bitwise_writer.bytes_written();
     add_lz77_data(
@@ -869,7 +1325,7 @@ fn add_lz77_block<W: Write>(
     )?;
 
     /* End symbol. */
-    bitwise_writer.add_huffman_bits(ll_symbols[256], ll_lengths[256])?;
+    bitwise_writer.add_bits(ll_symbols[256], ll_lengths[256])?;
 
     if log_enabled!(log::Level::Debug) {
         let uncompressed_size = lz77.litlens[lstart..lend]
@@ -1016,7 +1472,7 @@ fn add_lz77_data<W: Write>(
                 let litlen = lit as usize;
                 debug_assert!(litlen < 256);
                 debug_assert!(ll_lengths[litlen] > 0);
-                bitwise_writer.add_huffman_bits(ll_symbols[litlen], ll_lengths[litlen])?;
+                bitwise_writer.add_bits(ll_symbols[litlen], ll_lengths[litlen])?;
                 testlength += 1;
             }
             LitLen::LengthDist(len, dist) => {
@@ -1026,12 +1482,12 @@ fn add_lz77_data<W: Write>(
                 debug_assert!((3..=288).contains(&litlen));
                 debug_assert!(ll_lengths[lls] > 0);
                 debug_assert!(d_lengths[ds] > 0);
-                bitwise_writer.add_huffman_bits(ll_symbols[lls], ll_lengths[lls])?;
+                bitwise_writer.add_bits(ll_symbols[lls], ll_lengths[lls])?;
                 bitwise_writer.add_bits(
                     get_length_extra_bits_value(litlen),
                     get_length_extra_bits(litlen) as u32,
                 )?;
-                bitwise_writer.add_huffman_bits(d_symbols[ds], d_lengths[ds])?;
+                bitwise_writer.add_bits(d_symbols[ds], d_lengths[ds])?;
                 bitwise_writer.add_bits(
                     get_dist_extra_bits_value(dist) as u32,
                     get_dist_extra_bits(dist) as u32,
@@ -1046,6 +1502,7 @@ fn add_lz77_data<W: Write>(
 
 #[allow(clippy::too_many_arguments)] // Not feasible to refactor in a more readable way
 fn add_lz77_block_auto_type<W: Write>(
+    options: &Options,
     final_block: bool,
     in_data: &[u8],
     lz77: &Lz77Store,
@@ -1066,6 +1523,7 @@ fn add_lz77_block_auto_type<W: Write>(
     let mut fixedstore = Lz77Store::new();
     if lstart == lend {
         /* Smallest empty block is represented by fixed block */
+        bitwise_writer.stats.add_block(BlockType::Fixed, 0);
         bitwise_writer.add_bits(final_block as u32, 1)?;
         bitwise_writer.add_bits(1, 2)?; /* btype 01 */
         bitwise_writer.add_bits(0, 7)?; /* end symbol has code 0000000 */
@@ -1077,7 +1535,7 @@ fn add_lz77_block_auto_type<W: Write>(
         let inend = instart + lz77.get_byte_range(lstart, lend);
 
         lz77_optimal_fixed(
-            &mut ZopfliLongestMatchCache::new(inend - instart),
+            &mut ZopfliLongestMatchCache::for_options(inend - instart, options),
             in_data,
             instart,
             inend,
@@ -1150,6 +1608,7 @@ pub fn calculate_block_size_auto_type(lz77: &Lz77Store, lstart: usize, lend: usi
 }
 
 fn add_all_blocks<W: Write>(
+    options: &Options,
     splitpoints: &[usize],
     lz77: &Lz77Store,
     final_block: bool,
@@ -1158,10 +1617,11 @@ fn add_all_blocks<W: Write>(
 ) -> Result<(), Error> {
     let mut last = 0;
     for &item in splitpoints.iter() {
-        add_lz77_block_auto_type(false, in_data, lz77, last, item, 0, bitwise_writer)?;
+        add_lz77_block_auto_type(options, false, in_data, lz77, last, item, 0, bitwise_writer)?;
         last = item;
     }
     add_lz77_block_auto_type(
+        options,
         final_block,
         in_data,
         lz77,
@@ -1172,20 +1632,131 @@ fn add_all_blocks<W: Write>(
     )
 }
 
-fn blocksplit_attempt<W: Write>(
+/// Writes a complete DEFLATE stream with the LZ77 data of `lz77`, split into
+/// blocks at `splitpoints` as [`add_all_blocks`] does.
+pub(crate) fn write_lz77_blocks<W: Write>(
+    options: &Options,
+    lz77: &Lz77Store,
+    splitpoints: &[usize],
+    in_data: &[u8],
+    sink: W,
+) -> Result<W, Error> {
+    let mut bitwise_writer = BitwiseWriter::new(sink);
+    add_all_blocks(
+        options,
+        splitpoints,
+        lz77,
+        true,
+        in_data,
+        &mut bitwise_writer,
+    )?;
+    bitwise_writer.finish_partial_bits()?;
+    bitwise_writer.into_inner()
+}
+
+/// Runs `lz77_optimal` on each segment of `in_data` delimited by two consecutive
+/// `segment_bounds`, returning the resulting stores in order, along with their
+/// cost and the statistics of their optimization. The segments do not depend on
+/// each other, so they are optimized in parallel when several threads are
+/// available, at the cost of having a longest match cache for each of them in
+/// memory at the same time. Once the compression is cancelled, the remaining
+/// segments are left empty.
+fn lz77_optimal_segments(
+    options: &Options,
+    in_data: &[u8],
+    segment_bounds: &[usize],
+    progress: &ProgressReporter,
+) -> Vec<(Lz77Store, f64, CompressionStats)> {
+    let optimize_segment = |start: usize, end: usize| {
+        if progress.is_cancelled() {
+            return (Lz77Store::new(), 0.0, CompressionStats::default());
+        }
+
+        let stopwatch = Stopwatch::start();
+        let (store, iterations) = lz77_optimal(
+            &mut ZopfliLongestMatchCache::for_options(end - start, options),
+            in_data,
+            start,
+            end,
+            options,
+            progress,
+        );
+        let cost = calculate_block_size_auto_type(&store, 0, store.size());
+        // A cancellation is noticed by the caller, so it can be ignored here
+        let _ = progress.report(ProgressStage::BlockSplitSegment, (end - start) as u64, cost);
+
+        let stats = CompressionStats {
+            iterations: vec![iterations],
+            squeeze_time: stopwatch.elapsed(),
+            ..CompressionStats::default()
+        };
+        (store, cost, stats)
+    };
+
+    #[cfg(feature = "parallel")]
+    if options.threads.get() > 1 && segment_bounds.len() > 2 {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        let segment_count = segment_bounds.len() - 1;
+        let next_segment = AtomicUsize::new(0);
+
+        let mut stores = std::thread::scope(|scope| {
+            (0..cmp::min(options.threads.get(), segment_count))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut stores = vec![];
+                        loop {
+                            let i = next_segment.fetch_add(1, Ordering::Relaxed);
+                            if i >= segment_count {
+                                return stores;
+                            }
+                            stores.push((
+                                i,
+                                optimize_segment(segment_bounds[i], segment_bounds[i + 1]),
+                            ));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .flat_map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        stores.sort_unstable_by_key(|&(i, _)| i);
+        return stores.into_iter().map(|(_, store)| store).collect();
+    }
+
+    segment_bounds
+        .windows(2)
+        .map(|bounds| optimize_segment(bounds[0], bounds[1]))
+        .collect()
+}
+
+/// Splits the data between `instart` and `inend` into blocks and computes their
+/// optimal LZ77 representation. Returns the LZ77 data of the whole range and the
+/// split points, as indices in that LZ77 data, that [`add_all_blocks`] expects,
+/// along with the statistics of the process. This does not depend on the state
+/// of the output stream, so several parts can be processed concurrently.
+pub(crate) fn blocksplit_attempt(
     options: &Options,
-    final_block: bool,
     in_data: &[u8],
     instart: usize,
     inend: usize,
-    bitwise_writer: &mut BitwiseWriter<W>,
-) -> Result<(), Error> {
+    progress: &ProgressReporter,
+) -> (Lz77Store, Vec<usize>, CompressionStats) {
     let mut totalcost = 0.0;
     let mut lz77 = Lz77Store::new();
+    let mut stats = CompressionStats::default();
 
     /* byte coordinates rather than lz77 index */
     let mut splitpoints_uncompressed = Vec::with_capacity(options.maximum_block_splits as usize);
 
+    let stopwatch = Stopwatch::start();
     blocksplit(
         in_data,
         instart,
@@ -1193,50 +1764,37 @@ fn blocksplit_attempt<W: Write>(
         options.maximum_block_splits,
         &mut splitpoints_uncompressed,
     );
+    stats.block_splitting_time += stopwatch.elapsed();
     let npoints = splitpoints_uncompressed.len();
     let mut splitpoints = Vec::with_capacity(npoints);
 
-    let mut last = instart;
-    for &item in &splitpoints_uncompressed {
-        let store = lz77_optimal(
-            &mut ZopfliLongestMatchCache::new(item - last),
-            in_data,
-            last,
-            item,
-            options.iteration_count.get(),
-            options.iterations_without_improvement.get(),
-        );
-        totalcost += calculate_block_size_auto_type(&store, 0, store.size());
+    let mut segment_bounds = Vec::with_capacity(npoints + 2);
+    segment_bounds.push(instart);
+    segment_bounds.extend_from_slice(&splitpoints_uncompressed);
+    segment_bounds.push(inend);
+
+    for ((store, cost, segment_stats), is_last_segment) in
+        lz77_optimal_segments(options, in_data, &segment_bounds, progress)
+            .into_iter()
+            .flag_last()
+    {
+        totalcost += cost;
+        stats.add_optimization(segment_stats);
 
         // ZopfliAppendLZ77Store(&store, &lz77);
-        debug_assert!(instart == inend || store.size() > 0);
+        debug_assert!(instart == inend || store.size() > 0 || progress.is_cancelled());
         for (&litlens, &pos) in store.litlens.iter().zip(store.pos.iter()) {
             lz77.append_store_item(litlens, pos);
         }
 
-        splitpoints.push(lz77.size());
-
-        last = item;
-    }
-
-    let store = lz77_optimal(
-        &mut ZopfliLongestMatchCache::new(inend - last),
-        in_data,
-        last,
-        inend,
-        options.iteration_count.get(),
-        options.iterations_without_improvement.get(),
-    );
-    totalcost += calculate_block_size_auto_type(&store, 0, store.size());
-
-    // ZopfliAppendLZ77Store(&store, &lz77);
-    debug_assert!(instart == inend || store.size() > 0);
-    for (&litlens, &pos) in store.litlens.iter().zip(store.pos.iter()) {
-        lz77.append_store_item(litlens, pos);
+        if !is_last_segment {
+            splitpoints.push(lz77.size());
+        }
     }
 
     /* Second block splitting attempt */
-    if npoints > 1 {
+    if npoints > 1 && !progress.is_cancelled() {
+        let stopwatch = Stopwatch::start();
         let mut splitpoints2 = Vec::with_capacity(splitpoints_uncompressed.len());
         let mut totalcost2 = 0.0;
 
@@ -1252,9 +1810,21 @@ fn blocksplit_attempt<W: Write>(
         if totalcost2 < totalcost {
             splitpoints = splitpoints2;
         }
+        stats.block_splitting_time += stopwatch.elapsed();
     }
 
-    add_all_blocks(&splitpoints, &lz77, final_block, in_data, bitwise_writer)
+    (lz77, splitpoints, stats)
+}
+
+/// Writes the empty, non-final uncompressed block that ends the data of a
+/// flush, which byte-aligns the stream so that it can be handed over as is.
+fn add_flush_marker<W: Write>(bitwise_writer: &mut BitwiseWriter<W>) -> Result<(), Error> {
+    bitwise_writer.stats.add_block(BlockType::Uncompressed, 0);
+    /* BFINAL 0, BTYPE 00 */
+    bitwise_writer.add_bits(0, 3)?;
+    bitwise_writer.finish_partial_bits()?;
+
+    bitwise_writer.add_bytes(&[0, 0, 0xFF, 0xFF]) // LEN and NLEN
 }
 
 /// Since an uncompressed block can be max 65535 in size, it actually adds
@@ -1284,6 +1854,10 @@ fn add_non_compressed_block<W: Write>(
         let blocksize = chunk.len();
         let nlen = !blocksize;
 
+        bitwise_writer
+            .stats
+            .add_block(BlockType::Uncompressed, blocksize);
+
         bitwise_writer.add_bit((final_block && is_final) as u8)?;
         /* BTYPE 00 */
         bitwise_writer.add_bit(0)?;
@@ -1303,24 +1877,58 @@ fn add_non_compressed_block<W: Write>(
 }
 
 struct BitwiseWriter<W> {
-    bit: u8,
-    bp: u8,
+    /// Bits that have not been moved to `buffer` yet, starting from the least
+    /// significant one.
+    bits: u64,
+    /// The amount of bits held in `bits`, which is less than 32 between calls.
+    bit_count: u32,
+    /// Complete bytes that have not been written to the sink yet.
+    buffer: Vec<u8>,
+    /// The amount of complete bytes, written to the sink or not.
     len: usize,
     out: W,
+    /// Statistics about the blocks written so far.
+    stats: CompressionStats,
 }
 
 impl<W: Write> BitwiseWriter<W> {
+    /// The amount of bytes collected before writing them to the sink at once.
+    const BUFFER_SIZE: usize = 16 * 1024;
+
     fn new(out: W) -> BitwiseWriter<W> {
         BitwiseWriter {
-            bit: 0,
-            bp: 0,
+            bits: 0,
+            bit_count: 0,
+            buffer: Vec::with_capacity(Self::BUFFER_SIZE),
             len: 0,
             out,
+            stats: CompressionStats::default(),
         }
     }
 
+    /// Writes any pending complete bytes and returns the sink.
+    fn into_inner(mut self) -> Result<W, Error> {
+        self.write_buffer()?;
+        Ok(self.out)
+    }
+
+    /// Like `into_inner`, but also returns the statistics of the blocks
+    /// written to the sink.
+    fn into_inner_with_stats(mut self) -> Result<(W, CompressionStats), Error> {
+        self.write_buffer()?;
+        let stats = CompressionStats {
+            output_size: self.len as u64,
+            ..self.stats
+        };
+        Ok((self.out, stats))
+    }
+
     fn bytes_written(&self) -> usize {
-        self.len + if self.bp > 0 { 1 } else { 0 }
+        self.len + (self.bit_count as usize + 7) / 8
+    }
+
+    fn bits_written(&self) -> usize {
+        self.len * 8 + self.bit_count as usize
     }
 
     /// For when you want to add a full byte.
@@ -1328,53 +1936,85 @@ impl<W: Write> BitwiseWriter<W> {
         self.add_bytes(&[byte])
     }
 
-    /// For adding a slice of bytes.
+    /// For adding a slice of bytes, which must start at a byte boundary.
     fn add_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
-        self.len += bytes.len();
-        self.out.write_all(bytes)
+        debug_assert_eq!(self.bit_count % 8, 0, "bytes must be byte-aligned");
+        self.finish_partial_bits()?;
+        self.push_bytes(bytes)
     }
 
     fn add_bit(&mut self, bit: u8) -> Result<(), Error> {
-        self.bit |= bit << self.bp;
-        self.bp += 1;
-        if self.bp == 8 {
-            self.finish_partial_bits()
+        self.add_bits(bit.into(), 1)
+    }
+
+    /// Adds the `length` least significant bits of `symbol`, starting from the
+    /// least significant one. Huffman codes are stored starting from their most
+    /// significant bit instead, so they must be reversed beforehand, as done by
+    /// `reversed_huffman_codes`.
+    #[inline]
+    fn add_bits(&mut self, symbol: u32, length: u32) -> Result<(), Error> {
+        debug_assert!(length <= 32);
+        let symbol = u64::from(symbol) & ((1 << length) - 1);
+        self.bits |= symbol << self.bit_count;
+        self.bit_count += length;
+
+        if self.bit_count >= 32 {
+            let bytes = (self.bits as u32).to_le_bytes();
+            self.bits >>= 32;
+            self.bit_count -= 32;
+            self.push_bytes(&bytes)
         } else {
             Ok(())
         }
     }
 
-    fn add_bits(&mut self, symbol: u32, length: u32) -> Result<(), Error> {
-        // TODO: make more efficient (add more bits at once)
-        for i in 0..length {
-            let bit = ((symbol >> i) & 1) as u8;
-            self.add_bit(bit)?;
+    /// Moves the pending bits to the byte buffer, padding the last byte
+    /// with zeros.
+    fn finish_partial_bits(&mut self) -> Result<(), Error> {
+        let byte_count = (self.bit_count as usize + 7) / 8;
+        if byte_count > 0 {
+            let bytes = self.bits.to_le_bytes();
+            self.bits = 0;
+            self.bit_count = 0;
+            self.push_bytes(&bytes[..byte_count])?;
         }
-
         Ok(())
     }
 
-    /// Adds bits, like `add_bits`, but the order is inverted. The deflate specification
-    /// uses both orders in one standard.
-    fn add_huffman_bits(&mut self, symbol: u32, length: u32) -> Result<(), Error> {
-        // TODO: make more efficient (add more bits at once)
-        for i in 0..length {
-            let bit = ((symbol >> (length - i - 1)) & 1) as u8;
-            self.add_bit(bit)?;
+    /// Appends complete bytes to the buffer, writing it to the sink once it
+    /// is full.
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.len += bytes.len();
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= Self::BUFFER_SIZE {
+            self.write_buffer()
+        } else {
+            Ok(())
         }
+    }
 
+    /// Writes the complete bytes collected so far to the sink. Pending bits
+    /// are kept until they make up a byte.
+    fn write_buffer(&mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            self.out.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
         Ok(())
     }
+}
 
-    fn finish_partial_bits(&mut self) -> Result<(), Error> {
-        if self.bp != 0 {
-            let bytes = &[self.bit];
-            self.add_bytes(bytes)?;
-            self.bit = 0;
-            self.bp = 0;
+/// Returns the Huffman codes for a series of code lengths, as
+/// `lengths_to_symbols` does, with their bits reversed so that they can be
+/// added with `BitwiseWriter::add_bits` like any other value.
+fn reversed_huffman_codes(lengths: &[u32], max_bits: u32) -> Vec<u32> {
+    let mut codes = lengths_to_symbols(lengths, max_bits);
+    for (code, &length) in codes.iter_mut().zip(lengths) {
+        if length > 0 {
+            *code = code.reverse_bits() >> (32 - length);
         }
-        Ok(())
     }
+    codes
 }
 
 fn set_counts_to_count(counts: &mut [usize], count: usize, i: usize, stride: usize) {
@@ -1384,10 +2024,13 @@ fn set_counts_to_count(counts: &mut [usize], count: usize, i: usize, stride: usi
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use miniz_oxide::inflate;
 
     use super::*;
+    #[cfg(feature = "std")]
+    use crate::test_util::block_splittable_data;
+    use crate::util::ZOPFLI_MASTER_BLOCK_SIZE;
 
     #[test]
     fn test_set_counts_to_count() {
@@ -1401,6 +2044,35 @@ mod test {
         assert_eq!(counts, vec![0, 1, 2, 100, 100, 100, 100, 100, 8, 9])
     }
 
+    #[test]
+    fn bitwise_writer_packs_bits_in_order() {
+        let mut bitwise_writer = BitwiseWriter::new(vec![]);
+        bitwise_writer.add_bit(1).unwrap();
+        bitwise_writer.add_bits(0b10, 2).unwrap();
+        bitwise_writer.add_bits(0xABCD_EF12, 32).unwrap();
+        bitwise_writer.add_bits(u32::MAX, 4).unwrap();
+        assert_eq!(bitwise_writer.bits_written(), 39);
+        assert_eq!(bitwise_writer.bytes_written(), 5);
+
+        bitwise_writer.finish_partial_bits().unwrap();
+        bitwise_writer.add_bytes(&[0x55]).unwrap();
+
+        let expected = (0b1111 << 35) | (0xABCD_EF12 << 3) | 0b101u64;
+        assert_eq!(
+            bitwise_writer.into_inner().unwrap(),
+            [&expected.to_le_bytes()[..5], &[0x55]].concat()
+        );
+    }
+
+    #[test]
+    fn huffman_codes_are_reversed() {
+        // Codes 0, 10, 110 and 111, as in the example of RFC 1951
+        assert_eq!(
+            reversed_huffman_codes(&[1, 2, 3, 3, 0], 3),
+            [0b0, 0b01, 0b011, 0b111, 0]
+        );
+    }
+
     #[test]
     fn weird_encoder_write_size_combinations_works() {
         let mut compressed_data = vec![];
@@ -1428,4 +2100,257 @@ mod test {
             "Decompressed data should match input data"
         );
     }
+
+    /// Decompresses a raw DEFLATE stream that was compressed with the
+    /// specified preset dictionary.
+    pub(crate) fn inflate_with_dictionary(compressed_data: &[u8], dictionary: &[u8]) -> Vec<u8> {
+        use inflate::core::{decompress, inflate_flags, DecompressorOxide};
+
+        let mut output = vec![0; dictionary.len() + 1024 * 1024];
+        output[..dictionary.len()].copy_from_slice(dictionary);
+
+        let (status, _, output_size) = decompress(
+            &mut DecompressorOxide::new(),
+            compressed_data,
+            &mut output,
+            dictionary.len(),
+            inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+        );
+        assert_eq!(status, inflate::TINFLStatus::Done);
+
+        output.drain(..dictionary.len());
+        output.truncate(output_size);
+        output
+    }
+
+    #[test]
+    fn preset_dictionary_is_used_for_backreferences() {
+        let dictionary = b"{\"name\": \"\", \"version\": \"\", \"description\": \"\"}";
+        let data = b"{\"name\": \"zopfli\", \"version\": \"0.8.0\", \"description\": \"\"}";
+
+        let compress = |dictionary: &[u8]| {
+            let mut encoder = DeflateEncoder::new_with_dictionary(
+                Options::default(),
+                BlockType::Dynamic,
+                dictionary,
+                vec![],
+            );
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let compressed_data = compress(dictionary);
+        assert!(compressed_data.len() < compress(&[]).len());
+        assert_eq!(inflate_with_dictionary(&compressed_data, dictionary), data);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn flushes_make_written_data_decodable() {
+        let data = b"Flush me, flush me, flush me again".repeat(20);
+
+        for flush_mode in [FlushMode::Sync, FlushMode::Full] {
+            let mut encoder = DeflateEncoder::new(Options::default(), BlockType::Dynamic, vec![]);
+            encoder.set_flush_mode(flush_mode);
+            encoder.write_all(&data).unwrap();
+            encoder.flush().unwrap();
+
+            let flushed_data = encoder.get_mut().clone();
+            assert!(flushed_data.ends_with(&[0, 0, 0xFF, 0xFF]));
+            // The stream is not finished, but everything written is decodable
+            let mut output = vec![0; 2 * data.len()];
+            let (status, _, output_size) = inflate::core::decompress(
+                &mut inflate::core::DecompressorOxide::new(),
+                &flushed_data,
+                &mut output,
+                0,
+                inflate::core::inflate_flags::TINFL_FLAG_HAS_MORE_INPUT
+                    | inflate::core::inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+            );
+            assert_eq!(status, inflate::TINFLStatus::NeedsMoreInput);
+            assert_eq!(output[..output_size], data);
+
+            // Flushing again without new data does not write anything
+            encoder.flush().unwrap();
+            assert_eq!(encoder.get_mut().len(), flushed_data.len());
+
+            encoder.write_all(&data).unwrap();
+            let compressed_data = encoder.finish().unwrap();
+            assert_eq!(
+                inflate::decompress_to_vec(&compressed_data).unwrap(),
+                data.repeat(2)
+            );
+            if flush_mode == FlushMode::Full {
+                assert_eq!(
+                    inflate_with_dictionary(&compressed_data[flushed_data.len()..], &[]),
+                    data
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn slice_compression_matches_encoder() {
+        // Dynamic blocks are slow to optimize, so they get smaller master blocks
+        for (btype, options) in [
+            (BlockType::Uncompressed, Options::default()),
+            (BlockType::Fixed, Options::default()),
+            (
+                BlockType::Dynamic,
+                Options {
+                    iteration_count: core::num::NonZeroU64::new(2).unwrap(),
+                    master_block_size: 40_000,
+                    ..Options::default()
+                },
+            ),
+        ] {
+            let master_block_size = options.master_block_size;
+            let data = block_splittable_data(2 * master_block_size + 50_000);
+
+            for data in [&data[..], &[]] {
+                let mut encoder = DeflateEncoder::new(options, btype, vec![]);
+                for chunk in data.chunks(master_block_size) {
+                    encoder.write_all(chunk).unwrap();
+                }
+
+                assert_eq!(
+                    deflate_slice(options, btype, data, vec![]).unwrap(),
+                    encoder.finish().unwrap()
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_sizes_do_not_affect_master_blocks() {
+        let data = block_splittable_data(150_000);
+
+        for master_block_size in [0, 40_000, ZOPFLI_MASTER_BLOCK_SIZE] {
+            let options = Options {
+                iteration_count: core::num::NonZeroU64::new(2).unwrap(),
+                master_block_size,
+                ..Options::default()
+            };
+            let slice_output = deflate_slice(options, BlockType::Dynamic, &data, vec![]).unwrap();
+
+            for write_size in [1_000, 40_000, 65_536, data.len()] {
+                let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, vec![]);
+                for chunk in data.chunks(write_size) {
+                    encoder.write_all(chunk).unwrap();
+                }
+                assert_eq!(encoder.finish().unwrap(), slice_output);
+            }
+            assert_eq!(inflate::decompress_to_vec(&slice_output).unwrap(), data);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn compress_with_threads(data: &[u8], master_block_size: usize, threads: usize) -> Vec<u8> {
+        let options = Options {
+            iteration_count: core::num::NonZeroU64::new(2).unwrap(),
+            master_block_size,
+            threads: core::num::NonZeroUsize::new(threads).unwrap(),
+            ..Options::default()
+        };
+        let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, vec![]);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_master_blocks_match_sequential() {
+        let data = block_splittable_data(300_000);
+
+        let sequential_output = compress_with_threads(&data, 40_000, 1);
+        let parallel_output = compress_with_threads(&data, 40_000, 3);
+
+        assert_eq!(
+            sequential_output, parallel_output,
+            "Parallel compression should generate the same output as sequential compression"
+        );
+        assert_eq!(
+            data,
+            inflate::decompress_to_vec(&parallel_output)
+                .expect("Could not inflate compressed stream")
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_block_split_segments_match_sequential() {
+        let data = block_splittable_data(200_000);
+
+        let (_, splitpoints, _) = blocksplit_attempt(
+            &Options::default(),
+            &data,
+            0,
+            data.len(),
+            &ProgressReporter::new(None, 0),
+        );
+        assert!(
+            splitpoints.len() > 1,
+            "Test data should be split into several blocks"
+        );
+
+        assert_eq!(
+            compress_with_threads(&data, data.len(), 1),
+            compress_with_threads(&data, data.len(), 4),
+            "Parallel compression should generate the same output as sequential compression"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn spent_time_budget_stops_iterating() {
+        let data = block_splittable_data(100_000);
+        let compress = |options| {
+            let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, vec![]);
+            encoder.write_all(&data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let budget_output = compress(Options {
+            time_budget_per_mib: Some(std::time::Duration::ZERO),
+            ..Options::default()
+        });
+        let single_iteration_output = compress(Options {
+            iteration_count: core::num::NonZeroU64::new(1).unwrap(),
+            ..Options::default()
+        });
+
+        assert_eq!(budget_output, single_iteration_output);
+        assert_eq!(
+            data,
+            inflate::decompress_to_vec(&budget_output)
+                .expect("Could not inflate compressed stream")
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn huge_time_budget_does_not_overflow() {
+        let data = block_splittable_data(10_000);
+        let options = Options {
+            iteration_count: core::num::NonZeroU64::new(3).unwrap(),
+            ..Options::default()
+        };
+        let budget_output = deflate_slice(
+            Options {
+                time_budget_per_mib: Some(std::time::Duration::MAX),
+                ..options
+            },
+            BlockType::Dynamic,
+            &data,
+            vec![],
+        )
+        .unwrap();
+
+        assert_eq!(
+            budget_output,
+            deflate_slice(options, BlockType::Dynamic, &data, vec![]).unwrap()
+        );
+    }
 }
@@ -0,0 +1,249 @@
+//! Encoders that implement the [`AsyncWrite`] trait of
+//! [`futures-io`](https://docs.rs/futures-io), which works with any async
+//! runtime.
+//!
+//! Written data is buffered until there is a master block of it, of
+//! [`Options::master_block_size`] bytes, and each master block is compressed
+//! on a thread spawned for it, as the runtime and its blocking thread pool
+//! are not known. No thread is held between master blocks. Writes are not
+//! accepted while a previous master block is being compressed, and the next
+//! one is not started until most of the compressed output was written to the
+//! sink, so memory usage stays bounded by the master block size. When it is
+//! 0, the whole input is buffered and compressed on a single thread once the
+//! encoder is closed.
+//!
+//! Closing the encoder, with [`AsyncWrite::poll_close`], is required to
+//! finish the compressed stream, and then closes the sink.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_io::AsyncWrite;
+
+use crate::{
+    async_encoder::{AsyncEncoder, MakeEncoder},
+    BlockType, Options,
+};
+
+fn spawn_blocking(job: Box<dyn FnOnce() + Send>) {
+    std::thread::Builder::new()
+        .name("zopfli-worker".into())
+        .spawn(job)
+        .expect("failed to spawn a compression thread");
+}
+
+macro_rules! async_encoder {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        pub struct $name<W: AsyncWrite + Unpin> {
+            encoder: AsyncEncoder,
+            sink: W,
+        }
+
+        impl<W: AsyncWrite + Unpin> $name<W> {
+            fn with_encoder(options: &Options, make_encoder: MakeEncoder, sink: W) -> Self {
+                Self {
+                    encoder: AsyncEncoder::new(options, make_encoder, spawn_blocking),
+                    sink,
+                }
+            }
+
+            /// Gets a reference to the underlying sink.
+            pub fn get_ref(&self) -> &W {
+                &self.sink
+            }
+
+            /// Gets a mutable reference to the underlying sink. Writing to
+            /// it directly is likely to corrupt the compressed stream.
+            pub fn get_mut(&mut self) -> &mut W {
+                &mut self.sink
+            }
+
+            /// Consumes the encoder, returning the underlying sink. The
+            /// compressed stream is only complete if the encoder was
+            /// closed successfully.
+            pub fn into_inner(self) -> W {
+                self.sink
+            }
+        }
+
+        impl<W: AsyncWrite + Unpin> AsyncWrite for $name<W> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                let this = self.get_mut();
+                let sink = &mut this.sink;
+                this.encoder.poll_write(cx, buf, &mut |cx, buf| {
+                    Pin::new(&mut *sink).poll_write(cx, buf)
+                })
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                let this = self.get_mut();
+                let sink = &mut this.sink;
+                let mut write_sink =
+                    |cx: &mut Context<'_>, buf: &[u8]| Pin::new(&mut *sink).poll_write(cx, buf);
+                match this.encoder.poll_flush(cx, &mut write_sink) {
+                    Poll::Ready(Ok(())) => Pin::new(&mut this.sink).poll_flush(cx),
+                    poll => poll,
+                }
+            }
+
+            fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                let this = self.get_mut();
+                let sink = &mut this.sink;
+                let mut write_sink =
+                    |cx: &mut Context<'_>, buf: &[u8]| Pin::new(&mut *sink).poll_write(cx, buf);
+                match this.encoder.poll_close(cx, &mut write_sink) {
+                    Poll::Ready(Ok(())) => Pin::new(&mut this.sink).poll_close(cx),
+                    poll => poll,
+                }
+            }
+        }
+    };
+}
+
+async_encoder! {
+    /// A DEFLATE encoder powered by the Zopfli algorithm that writes to a
+    /// futures-io [`AsyncWrite`] sink. See [`crate::DeflateEncoder`].
+    DeflateEncoder
+}
+
+impl<W: AsyncWrite + Unpin> DeflateEncoder<W> {
+    /// Creates a new Zopfli DEFLATE encoder that will operate according to
+    /// the specified options.
+    pub fn new(options: Options, btype: BlockType, sink: W) -> Self {
+        Self::with_encoder(
+            &options,
+            Box::new(move |output| {
+                Ok(Box::new(crate::DeflateEncoder::new(options, btype, output)))
+            }),
+            sink,
+        )
+    }
+}
+
+#[cfg(feature = "gzip")]
+async_encoder! {
+    /// A gzip encoder powered by the Zopfli algorithm that writes to a futures-io
+    /// [`AsyncWrite`] sink. See [`crate::GzipEncoder`].
+    GzipEncoder
+}
+
+#[cfg(feature = "gzip")]
+impl<W: AsyncWrite + Unpin> GzipEncoder<W> {
+    /// Creates a new gzip encoder that will operate according to the
+    /// specified options.
+    pub fn new(options: Options, btype: BlockType, sink: W) -> Self {
+        Self::new_with_header(options, btype, crate::GzipHeader::default(), sink)
+    }
+
+    /// Creates a new gzip encoder that will operate according to the
+    /// specified options, and write the specified metadata in the gzip
+    /// header.
+    pub fn new_with_header(
+        options: Options,
+        btype: BlockType,
+        header: crate::GzipHeader,
+        sink: W,
+    ) -> Self {
+        Self::with_encoder(
+            &options,
+            Box::new(move |output| {
+                Ok(Box::new(crate::GzipEncoder::new_with_header(
+                    options, btype, &header, output,
+                )?))
+            }),
+            sink,
+        )
+    }
+}
+
+#[cfg(feature = "zlib")]
+async_encoder! {
+    /// A zlib encoder powered by the Zopfli algorithm that writes to a futures-io
+    /// [`AsyncWrite`] sink. See [`crate::ZlibEncoder`].
+    ZlibEncoder
+}
+
+#[cfg(feature = "zlib")]
+impl<W: AsyncWrite + Unpin> ZlibEncoder<W> {
+    /// Creates a new zlib encoder that will operate according to the
+    /// specified options.
+    pub fn new(options: Options, btype: BlockType, sink: W) -> Self {
+        Self::with_encoder(
+            &options,
+            Box::new(move |output| Ok(Box::new(crate::ZlibEncoder::new(options, btype, output)?))),
+            sink,
+        )
+    }
+}
+
+#[cfg(all(test, feature = "zlib"))]
+mod test {
+    use std::{
+        future::{poll_fn, Future},
+        num::NonZeroU64,
+        sync::Arc,
+        task::Wake,
+        thread::{self, Thread},
+    };
+
+    use super::*;
+    use crate::Write;
+
+    /// Runs a future to completion on the current thread.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(Thread);
+
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn matches_blocking_encoder_output() {
+        let options = Options {
+            iteration_count: NonZeroU64::new(1).unwrap(),
+            ..Options::default()
+        };
+        let data = b"Async writes are compressed on another thread. ".repeat(2000);
+
+        let compressed_data = block_on(async {
+            let mut encoder = ZlibEncoder::new(options, BlockType::Dynamic, Vec::new());
+            for chunk in data.chunks(700) {
+                let mut chunk = chunk;
+                while !chunk.is_empty() {
+                    let written =
+                        poll_fn(|cx| Pin::new(&mut encoder).poll_write(cx, chunk)).await?;
+                    chunk = &chunk[written..];
+                }
+            }
+            poll_fn(|cx| Pin::new(&mut encoder).poll_close(cx)).await?;
+            io::Result::Ok(encoder.into_inner())
+        })
+        .unwrap();
+
+        let mut blocking_encoder =
+            crate::ZlibEncoder::new(options, BlockType::Dynamic, Vec::new()).unwrap();
+        blocking_encoder.write_all(&data).unwrap();
+        assert_eq!(compressed_data, blocking_encoder.finish().unwrap());
+    }
+}
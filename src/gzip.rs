@@ -1,54 +1,357 @@
-use crate::{BlockType, DeflateEncoder, Error, Options, Write};
+use alloc::vec::Vec;
+use core::num::NonZeroU64;
+
+use crate::{
+    deflate_slice, BlockType, CompressionStats, DeflateEncoder, Error, FlushMode, Options,
+    Progress, Write,
+};
+
+/// The metadata stored in the header of a gzip member, as defined in
+/// [RFC 1952](https://datatracker.ietf.org/doc/html/rfc1952#page-5).
+///
+/// The default header stores no optional fields, a zero modification
+/// time and the Unix operating system identifier, which is what the
+/// reference Zopfli implementation writes. Its fields can be configured
+/// with builder methods:
+///
+/// ```
+/// # use zopfli::GzipHeader;
+/// let header = GzipHeader::new()
+///     .filename("hello.txt")
+///     .mtime(1_700_000_000)
+///     .comment("Compressed with Zopfli");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GzipHeader {
+    filename: Option<Vec<u8>>,
+    comment: Option<Vec<u8>>,
+    extra_field: Option<Vec<u8>>,
+    mtime: u32,
+    header_crc: bool,
+    os: u8,
+}
+
+impl GzipHeader {
+    /// Operating system identifier for Unix systems, used by default.
+    pub const OS_UNIX: u8 = 3;
+    /// Operating system identifier to use when the file system the data
+    /// comes from is unknown.
+    pub const OS_UNKNOWN: u8 = 255;
+
+    /// Creates a new header with the default metadata.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the original name of the compressed file (FNAME field), which
+    /// decompressors such as `gzip -N` may use to restore it. The name should
+    /// be encoded in ISO 8859-1 (LATIN-1) and not contain any directory
+    /// components.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name contains a zero byte.
+    pub fn filename(mut self, filename: impl Into<Vec<u8>>) -> Self {
+        let filename = filename.into();
+        assert!(
+            !filename.contains(&0),
+            "gzip file names must not contain zero bytes"
+        );
+        self.filename = Some(filename);
+        self
+    }
+
+    /// Sets a human-readable comment for the file (FCOMMENT field). The
+    /// comment should be encoded in ISO 8859-1 (LATIN-1), using a single
+    /// line feed character as line separator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the comment contains a zero byte.
+    pub fn comment(mut self, comment: impl Into<Vec<u8>>) -> Self {
+        let comment = comment.into();
+        assert!(
+            !comment.contains(&0),
+            "gzip comments must not contain zero bytes"
+        );
+        self.comment = Some(comment);
+        self
+    }
+
+    /// Adds a subfield to the extra field (FEXTRA) of the header. `id` holds
+    /// the two subfield ID bytes, SI1 and SI2, and `data` its contents.
+    /// Subfields are written in the order they are added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the subfield data is longer than 65535 bytes, or if the
+    /// whole extra field would be longer than that.
+    pub fn extra_subfield(mut self, id: [u8; 2], data: &[u8]) -> Self {
+        let extra_field = self.extra_field.get_or_insert_with(Vec::new);
+        assert!(
+            data.len() <= u16::MAX as usize
+                && extra_field.len() + 4 + data.len() <= u16::MAX as usize,
+            "gzip extra fields must not be longer than 65535 bytes"
+        );
+        extra_field.extend_from_slice(&id);
+        extra_field.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        extra_field.extend_from_slice(data);
+        self
+    }
+
+    /// Sets the modification time of the original file (MTIME field), in
+    /// seconds since the Unix epoch. Zero means that no time stamp is
+    /// available, which is the default.
+    pub fn mtime(mut self, mtime: u32) -> Self {
+        self.mtime = mtime;
+        self
+    }
+
+    /// Sets whether to store a CRC-16 of the header (FHCRC field), which
+    /// allows decompressors to detect corrupted metadata. Few programs
+    /// check it, so it is not written by default.
+    pub fn header_crc(mut self, header_crc: bool) -> Self {
+        self.header_crc = header_crc;
+        self
+    }
+
+    /// Sets the identifier of the file system the data was compressed on
+    /// (OS field). See RFC 1952 for the list of defined values.
+    pub fn os(mut self, os: u8) -> Self {
+        self.os = os;
+        self
+    }
+
+    /// Returns the bytes of this header, as they are written at the start
+    /// of a gzip member.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        const FHCRC: u8 = 1 << 1;
+        const FEXTRA: u8 = 1 << 2;
+        const FNAME: u8 = 1 << 3;
+        const FCOMMENT: u8 = 1 << 4;
+
+        let mut flags = 0;
+        if self.header_crc {
+            flags |= FHCRC;
+        }
+        if self.extra_field.is_some() {
+            flags |= FEXTRA;
+        }
+        if self.filename.is_some() {
+            flags |= FNAME;
+        }
+        if self.comment.is_some() {
+            flags |= FCOMMENT;
+        }
+
+        let mut header = vec![
+            31,    // ID1
+            139,   // ID2
+            8,     // CM
+            flags, // FLG
+        ];
+        header.extend_from_slice(&self.mtime.to_le_bytes()); // MTIME
+        header.push(2); // XFL, 2 indicates best compression.
+        header.push(self.os); // OS
+
+        if let Some(extra_field) = &self.extra_field {
+            header.extend_from_slice(&(extra_field.len() as u16).to_le_bytes());
+            header.extend_from_slice(extra_field);
+        }
+        if let Some(filename) = &self.filename {
+            header.extend_from_slice(filename);
+            header.push(0);
+        }
+        if let Some(comment) = &self.comment {
+            header.extend_from_slice(comment);
+            header.push(0);
+        }
+        if self.header_crc {
+            let crc16 = crc32fast::hash(&header) as u16;
+            header.extend_from_slice(&crc16.to_le_bytes());
+        }
+
+        header
+    }
+}
+
+impl Default for GzipHeader {
+    fn default() -> Self {
+        GzipHeader {
+            filename: None,
+            comment: None,
+            extra_field: None,
+            mtime: 0,
+            header_crc: false,
+            os: Self::OS_UNIX,
+        }
+    }
+}
+
+/// The position of a member in a gzip stream, as listed by a [`GzipIndex`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GzipMember {
+    /// The offset of the first data byte of the member in the uncompressed
+    /// data.
+    pub uncompressed_offset: u64,
+    /// The offset of the header of the member in the gzip stream.
+    pub compressed_offset: u64,
+}
+
+/// A list of the members of a gzip stream written by a [`GzipEncoder`],
+/// which allows decompressing parts of the data without reading the members
+/// that come before, as each member can be decompressed independently.
+///
+/// Indexes are most useful when a [member size](GzipEncoder::set_member_size)
+/// is set, and can be stored in a sidecar file next to the gzip file with
+/// [`to_bytes`](Self::to_bytes).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GzipIndex {
+    members: Vec<GzipMember>,
+}
+
+impl GzipIndex {
+    /// The bytes that identify a serialized index.
+    const MAGIC: [u8; 4] = *b"ZGZI";
+
+    /// Returns the members of the stream, in ascending offset order.
+    pub fn members(&self) -> &[GzipMember] {
+        &self.members
+    }
+
+    /// Returns the member that holds the data byte at the specified
+    /// uncompressed offset, which is the last one when the offset is past
+    /// the end of the data. Returns `None` if the index is empty.
+    pub fn member_at(&self, uncompressed_offset: u64) -> Option<&GzipMember> {
+        let members_before = self
+            .members
+            .partition_point(|member| member.uncompressed_offset <= uncompressed_offset);
+        self.members.get(members_before.saturating_sub(1))
+    }
+
+    /// Serializes this index. The serialized form starts with the `ZGZI`
+    /// magic bytes and the number of members, followed by the uncompressed
+    /// and compressed offset of each member, all of them stored as
+    /// little-endian 64-bit integers.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + self.members.len() * 16);
+        bytes.extend_from_slice(&Self::MAGIC);
+        bytes.extend_from_slice(&(self.members.len() as u64).to_le_bytes());
+        for member in &self.members {
+            bytes.extend_from_slice(&member.uncompressed_offset.to_le_bytes());
+            bytes.extend_from_slice(&member.compressed_offset.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Deserializes an index produced by [`to_bytes`](Self::to_bytes).
+    /// Returns `None` if the bytes are not a valid index.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.strip_prefix(&Self::MAGIC)?;
+        let (member_count, bytes) = bytes.split_at(bytes.len().min(8));
+        let member_count = u64::from_le_bytes(member_count.try_into().ok()?);
+        if bytes.len() % 16 != 0 || (bytes.len() / 16) as u64 != member_count {
+            return None;
+        }
+
+        let read_u64 = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+        let members: Vec<_> = bytes
+            .chunks_exact(16)
+            .map(|member| GzipMember {
+                uncompressed_offset: read_u64(&member[..8]),
+                compressed_offset: read_u64(&member[8..]),
+            })
+            .collect();
+
+        let is_sorted = members.windows(2).all(|members| {
+            members[0].uncompressed_offset <= members[1].uncompressed_offset
+                && members[0].compressed_offset < members[1].compressed_offset
+        });
+        is_sorted.then_some(GzipIndex { members })
+    }
+}
 
 /// A Gzip encoder powered by the Zopfli algorithm, that compresses data using
 /// a [`DeflateEncoder`]. Most users will find using [`compress`](crate::compress)
 /// easier and more performant.
 ///
-/// The caveats about short writes in [`DeflateEncoder`]s carry over to `GzipEncoder`s:
-/// for best performance and compression, it is best to avoid them. One way to ensure
-/// this is to use the [`new_buffered`](GzipEncoder::new_buffered) method.
+/// Like [`DeflateEncoder`]s, `GzipEncoder`s collect written data into master
+/// blocks, so short writes do not hurt compression.
+///
+/// By default, the encoder writes a single gzip member. With
+/// [`set_member_size`](Self::set_member_size), it can instead write a series of
+/// members that can be decompressed independently, whose positions are kept in
+/// an [`index`](Self::index).
 pub struct GzipEncoder<W: Write> {
     deflate_encoder: Option<DeflateEncoder<W>>,
     crc32_hasher: crc32fast::Hasher,
-    input_size: u32,
+    input_size: u64,
+    header: Vec<u8>,
+    member_size: Option<NonZeroU64>,
+    index: GzipIndex,
+    stats: CompressionStats,
 }
 
 impl<W: Write> GzipEncoder<W> {
     /// Creates a new Gzip encoder that will operate according to the
     /// specified options.
-    pub fn new(options: Options, btype: BlockType, mut sink: W) -> Result<Self, Error> {
-        static HEADER: &[u8] = &[
-            31,  // ID1
-            139, // ID2
-            8,   // CM
-            0,   // FLG
-            0,   // MTIME
-            0, 0, 0, 2, // XFL, 2 indicates best compression.
-            3, // OS follows Unix conventions.
-        ];
+    pub fn new(options: Options, btype: BlockType, sink: W) -> Result<Self, Error> {
+        Self::new_with_header(options, btype, &GzipHeader::default(), sink)
+    }
 
-        sink.write_all(HEADER)?;
+    /// Creates a new Gzip encoder that will operate according to the
+    /// specified options, and write the specified metadata in the
+    /// gzip header.
+    pub fn new_with_header(
+        options: Options,
+        btype: BlockType,
+        header: &GzipHeader,
+        mut sink: W,
+    ) -> Result<Self, Error> {
+        let header = header.to_bytes();
+        sink.write_all(&header)?;
 
         Ok(Self {
             deflate_encoder: Some(DeflateEncoder::new(options, btype, sink)),
             crc32_hasher: crc32fast::Hasher::new(),
             input_size: 0,
+            header,
+            member_size: None,
+            index: GzipIndex {
+                members: vec![GzipMember {
+                    uncompressed_offset: 0,
+                    compressed_offset: 0,
+                }],
+            },
+            stats: CompressionStats::default(),
         })
     }
 
     /// Creates a new Gzip encoder that operates according to the specified
-    /// options and is wrapped with a buffer to guarantee that data is
-    /// compressed in large chunks, which is necessary for decent performance
-    /// and good compression ratio.
+    /// options and is wrapped with a buffer of the default master block size,
+    /// which reduces the number of calls to the encoder.
     #[cfg(feature = "std")]
     pub fn new_buffered(
         options: Options,
         btype: BlockType,
         sink: W,
+    ) -> Result<std::io::BufWriter<Self>, Error> {
+        Self::new_buffered_with_header(options, btype, &GzipHeader::default(), sink)
+    }
+
+    /// Creates a new Gzip encoder that operates according to the specified
+    /// options, writes the specified metadata in the gzip header, and is
+    /// wrapped with a buffer of the default master block size.
+    #[cfg(feature = "std")]
+    pub fn new_buffered_with_header(
+        options: Options,
+        btype: BlockType,
+        header: &GzipHeader,
+        sink: W,
     ) -> Result<std::io::BufWriter<Self>, Error> {
         Ok(std::io::BufWriter::with_capacity(
             crate::util::ZOPFLI_MASTER_BLOCK_SIZE,
-            Self::new(options, btype, sink)?,
+            Self::new_with_header(options, btype, header, sink)?,
         ))
     }
 
@@ -61,32 +364,149 @@ impl<W: Write> GzipEncoder<W> {
     /// dropped, but explicitly finishing it with this method allows
     /// handling I/O errors.
     pub fn finish(mut self) -> Result<W, Error> {
-        self._finish().map(|sink| sink.unwrap())
+        self._finish().map(|finished| finished.unwrap().0)
+    }
+
+    /// Like [`finish`](Self::finish), but also returns statistics about the
+    /// compressed stream.
+    pub fn finish_with_stats(mut self) -> Result<(W, CompressionStats), Error> {
+        self._finish().map(Option::unwrap)
+    }
+
+    /// Sets a callback that will be notified of the progress of the
+    /// compression, and may cancel it, as described in [`Progress`].
+    /// This replaces any previously set callback.
+    pub fn set_progress(&mut self, progress: impl Progress + Send + 'static) {
+        self.deflate_encoder
+            .as_mut()
+            .unwrap()
+            .set_progress(progress);
+    }
+
+    /// Sets what flushing the encoder does with the data written to it, as
+    /// described in [`FlushMode`].
+    pub fn set_flush_mode(&mut self, flush_mode: FlushMode) {
+        self.deflate_encoder
+            .as_mut()
+            .unwrap()
+            .set_flush_mode(flush_mode);
+    }
+
+    /// Sets the amount of input bytes after which the current gzip member
+    /// ends and a new one starts, or `None` to keep writing to the current
+    /// member, which is the default. Every member has the header this encoder
+    /// was created with and its own CRC-32 and size, so the output remains a
+    /// valid gzip stream that common tools decompress as a whole.
+    ///
+    /// Members do not reference the data of previous members, which makes
+    /// the compression somewhat worse, especially for small member sizes.
+    pub fn set_member_size(&mut self, member_size: Option<NonZeroU64>) {
+        self.member_size = member_size;
+    }
+
+    /// Returns the index of the members written so far, including the
+    /// current one.
+    pub fn index(&self) -> &GzipIndex {
+        &self.index
+    }
+
+    /// Ends the current member and starts a new one.
+    fn start_member(&mut self) -> Result<(), Error> {
+        let crc32 = core::mem::replace(&mut self.crc32_hasher, crc32fast::Hasher::new()).finalize();
+        let input_size = self.input_size as u32;
+        let header = &self.header;
+        let stats = self.deflate_encoder.as_mut().unwrap().restart(|sink| {
+            sink.write_all(&crc32.to_le_bytes())?;
+            sink.write_all(&input_size.to_le_bytes())?;
+            sink.write_all(header)
+        })?;
+        self.add_member_stats(stats);
+
+        self.input_size = 0;
+        self.index.members.push(GzipMember {
+            uncompressed_offset: self.stats.input_size,
+            compressed_offset: self.stats.output_size,
+        });
+
+        Ok(())
+    }
+
+    /// Adds the statistics of the DEFLATE stream of a finished member.
+    fn add_member_stats(&mut self, mut stats: CompressionStats) {
+        stats.output_size += self.header.len() as u64 + 8;
+        self.stats.append(stats);
     }
 
-    fn _finish(&mut self) -> Result<Option<W>, Error> {
+    /// Gets a mutable reference to the sink, as described in
+    /// [`DeflateEncoder::get_mut`].
+    #[cfg(feature = "std")]
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        self.deflate_encoder.as_mut().unwrap().get_mut()
+    }
+
+    /// Drops the data written so far without compressing it, as described in
+    /// [`DeflateEncoder::discard`].
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub(crate) fn discard(&mut self) {
+        if let Some(mut deflate_encoder) = self.deflate_encoder.take() {
+            deflate_encoder.discard();
+        }
+    }
+
+    fn _finish(&mut self) -> Result<Option<(W, CompressionStats)>, Error> {
         if self.deflate_encoder.is_none() {
             return Ok(None);
         }
 
-        let mut sink = self.deflate_encoder.take().unwrap().finish()?;
+        let (mut sink, stats) = self.deflate_encoder.take().unwrap().finish_with_stats()?;
 
         sink.write_all(&self.crc32_hasher.clone().finalize().to_le_bytes())?;
-        sink.write_all(&self.input_size.to_le_bytes())?;
+        sink.write_all(&(self.input_size as u32).to_le_bytes())?;
+        self.add_member_stats(stats);
 
-        Ok(Some(sink))
+        Ok(Some((sink, core::mem::take(&mut self.stats))))
     }
 }
 
+/// Writes a gzip member with a default header that stores data compressed
+/// with [`deflate_slice`].
+pub(crate) fn gzip_slice<W: Write>(
+    options: Options,
+    btype: BlockType,
+    data: &[u8],
+    mut sink: W,
+) -> Result<W, Error> {
+    sink.write_all(&GzipHeader::default().to_bytes())?;
+
+    let mut sink = deflate_slice(options, btype, data, sink)?;
+
+    sink.write_all(&crc32fast::hash(data).to_le_bytes())?;
+    sink.write_all(&(data.len() as u32).to_le_bytes())?;
+
+    Ok(sink)
+}
+
 impl<W: Write> Write for GzipEncoder<W> {
-    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    fn write(&mut self, mut buf: &[u8]) -> Result<usize, Error> {
+        if let Some(member_size) = self.member_size {
+            if self.input_size >= member_size.get() && !buf.is_empty() {
+                self.start_member()?;
+            }
+
+            // Leave the bytes of the next member for the next write
+            let member_bytes_left = member_size.get().saturating_sub(self.input_size);
+            buf = &buf[..buf
+                .len()
+                .min(member_bytes_left.try_into().unwrap_or(usize::MAX))];
+        }
+
         self.deflate_encoder
             .as_mut()
             .unwrap()
             .write(buf)
             .map(|bytes_written| {
                 self.crc32_hasher.update(&buf[..bytes_written]);
-                self.input_size = self.input_size.wrapping_add(bytes_written as u32);
+                self.input_size += bytes_written as u64;
                 bytes_written
             })
     }
@@ -113,3 +533,107 @@ impl<W: crate::io::Write> std::io::Write for GzipEncoder<W> {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_header_matches_reference_implementation() {
+        assert_eq!(
+            GzipHeader::default().to_bytes(),
+            [31, 139, 8, 0, 0, 0, 0, 0, 2, 3]
+        );
+    }
+
+    #[test]
+    fn header_with_all_fields_is_serialized_in_order() {
+        let header = GzipHeader::new()
+            .filename("a.txt")
+            .comment("hi")
+            .extra_subfield(*b"ZP", &[1, 2])
+            .mtime(0x01020304)
+            .header_crc(true)
+            .os(GzipHeader::OS_UNKNOWN)
+            .to_bytes();
+
+        let (header, crc16) = header.split_at(header.len() - 2);
+        assert_eq!(
+            header,
+            [
+                31, 139, 8, 0b11110, 4, 3, 2, 1, 2, 255, // Fixed-size fields
+                6, 0, b'Z', b'P', 2, 0, 1, 2, // FEXTRA
+                b'a', b'.', b't', b'x', b't', 0, // FNAME
+                b'h', b'i', 0 // FCOMMENT
+            ]
+        );
+        assert_eq!(crc16, &(crc32fast::hash(header) as u16).to_le_bytes());
+    }
+
+    #[test]
+    fn members_can_be_decompressed_independently() {
+        use miniz_oxide::inflate::decompress_to_vec;
+
+        let data: Vec<u8> = (0..10_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        let header = GzipHeader::new().mtime(1_700_000_000);
+        let header_size = header.to_bytes().len();
+
+        let mut encoder =
+            GzipEncoder::new_with_header(Options::default(), BlockType::Dynamic, &header, vec![])
+                .unwrap();
+        encoder.set_member_size(NonZeroU64::new(12_000));
+        encoder.write_all(&data).unwrap();
+        let index = encoder.index().clone();
+        let (compressed_data, stats) = encoder.finish_with_stats().unwrap();
+
+        assert_eq!(index.members().len(), 4);
+        assert_eq!(stats.input_size, data.len() as u64);
+        assert_eq!(stats.output_size, compressed_data.len() as u64);
+
+        let mut member_ends = index.members()[1..]
+            .iter()
+            .map(|member| member.compressed_offset as usize)
+            .chain([compressed_data.len()]);
+        for member in index.members() {
+            let member_data =
+                &compressed_data[member.compressed_offset as usize..member_ends.next().unwrap()];
+            let (member_header, member_data) = member_data.split_at(header_size);
+            let (deflate_data, trailer) = member_data.split_at(member_data.len() - 8);
+            assert_eq!(member_header, header.to_bytes());
+
+            let start = member.uncompressed_offset as usize;
+            let expected_data = &data[start..data.len().min(start + 12_000)];
+            assert_eq!(decompress_to_vec(deflate_data).unwrap(), expected_data);
+            assert_eq!(trailer[..4], crc32fast::hash(expected_data).to_le_bytes());
+            assert_eq!(trailer[4..], (expected_data.len() as u32).to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn index_roundtrips_and_finds_members() {
+        let index = GzipIndex {
+            members: vec![
+                GzipMember {
+                    uncompressed_offset: 0,
+                    compressed_offset: 0,
+                },
+                GzipMember {
+                    uncompressed_offset: 100,
+                    compressed_offset: 40,
+                },
+            ],
+        };
+
+        let bytes = index.to_bytes();
+        assert_eq!(GzipIndex::from_bytes(&bytes), Some(index.clone()));
+        assert_eq!(GzipIndex::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(GzipIndex::from_bytes(&bytes[1..]), None);
+
+        assert_eq!(index.member_at(99), Some(&index.members()[0]));
+        assert_eq!(index.member_at(100), Some(&index.members()[1]));
+        assert_eq!(index.member_at(1000), Some(&index.members()[1]));
+        assert_eq!(GzipIndex::default().member_at(0), None);
+    }
+}
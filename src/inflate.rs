@@ -0,0 +1,846 @@
+//! A small DEFLATE decoder, meant for checking that compressed data round-trips
+//! rather than for speed. It decodes raw DEFLATE streams, as defined in
+//! [RFC 1951](https://datatracker.ietf.org/doc/html/rfc1951), and, when the
+//! corresponding features are enabled, the zlib and gzip formats, validating
+//! their checksums.
+//!
+//! DEFLATE streams can decompress to over a thousand times their size, so
+//! untrusted input should be decoded with the `_limited` variants of the
+//! functions, which fail once the decompressed data exceeds a maximum size
+//! instead of exhausting memory.
+//!
+//! The Huffman decoding approach follows `puff`, the reference decoder that
+//! is distributed with zlib.
+
+use alloc::vec::Vec;
+use core::{
+    cmp, fmt,
+    fmt::{Display, Formatter},
+};
+
+use crate::util::ZOPFLI_WINDOW_SIZE;
+
+/// Maximum bit length of any Huffman code in DEFLATE.
+const MAX_CODE_BITS: usize = 15;
+/// Maximum number of literal/length codes that can be defined in a block.
+const MAX_LL_CODES: usize = 286;
+/// Maximum number of distance codes that can be defined in a block.
+const MAX_D_CODES: usize = 30;
+/// Number of code length codes.
+const NUM_CL_CODES: usize = 19;
+
+/// Base lengths for length symbols 257..285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+/// Extra bits for length symbols 257..285.
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances for distance symbols 0..29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+/// Extra bits for distance symbols 0..29.
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order in which code length code lengths are stored in dynamic blocks.
+const CL_ORDER: [usize; NUM_CL_CODES] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// The errors that may happen while decoding compressed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InflateError {
+    /// The input ended before the end of the compressed stream.
+    UnexpectedEof,
+    /// A block used the reserved block type (BTYPE=11).
+    InvalidBlockType,
+    /// The length of a non-compressed block does not match its one's
+    /// complement (NLEN).
+    InvalidStoredLength,
+    /// A dynamic block defines an incomplete or over-subscribed Huffman code.
+    InvalidCodeLengths,
+    /// The stream contains a bit sequence that is not a valid code, or a
+    /// literal/length or distance symbol that is not allowed.
+    InvalidSymbol,
+    /// A backreference points before the start of the data.
+    DistanceTooFarBack,
+    /// The zlib or gzip header is malformed or uses an unsupported
+    /// compression method.
+    InvalidHeader,
+    /// The zlib stream requires a preset dictionary, which was not provided.
+    DictionaryRequired,
+    /// The Adler-32 or CRC-32 checksum stored in the stream does not match
+    /// the decompressed data.
+    ChecksumMismatch {
+        /// The checksum stored in the stream.
+        expected: u32,
+        /// The checksum of the decompressed data.
+        actual: u32,
+    },
+    /// The uncompressed size stored in a gzip trailer (ISIZE) does not match
+    /// the size of the decompressed data, modulo 2^32.
+    SizeMismatch {
+        /// The size stored in the stream.
+        expected: u32,
+        /// The size of the decompressed data, modulo 2^32.
+        actual: u32,
+    },
+    /// There is more data after the end of the compressed stream.
+    TrailingData,
+    /// The decompressed data is bigger than the maximum output size.
+    OutputTooLarge,
+}
+
+impl Display for InflateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => f.write_str("unexpected end of compressed data"),
+            Self::InvalidBlockType => f.write_str("invalid DEFLATE block type"),
+            Self::InvalidStoredLength => f.write_str("invalid non-compressed block length"),
+            Self::InvalidCodeLengths => f.write_str("invalid Huffman code lengths"),
+            Self::InvalidSymbol => f.write_str("invalid Huffman code or symbol"),
+            Self::DistanceTooFarBack => f.write_str("backreference distance too far back"),
+            Self::InvalidHeader => f.write_str("invalid or unsupported stream header"),
+            Self::DictionaryRequired => f.write_str("a preset dictionary is required"),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            Self::SizeMismatch { expected, actual } => write!(
+                f,
+                "uncompressed size mismatch: expected {}, got {}",
+                expected, actual
+            ),
+            Self::TrailingData => f.write_str("trailing data after the compressed stream"),
+            Self::OutputTooLarge => f.write_str("decompressed data exceeds the maximum size"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InflateError {}
+
+#[cfg(all(not(feature = "std"), feature = "nightly"))]
+impl core::error::Error for InflateError {}
+
+#[cfg(feature = "std")]
+impl From<InflateError> for std::io::Error {
+    fn from(error: InflateError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Decompresses a raw DEFLATE stream that spans the whole input.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_limited(data, usize::MAX)
+}
+
+/// Decompresses a raw DEFLATE stream that spans the whole input, failing with
+/// [`InflateError::OutputTooLarge`] if it decompresses to more than
+/// `max_output` bytes.
+pub fn inflate_limited(data: &[u8], max_output: usize) -> Result<Vec<u8>, InflateError> {
+    let (decompressed_data, consumed) = inflate_prefix_with_dictionary(data, &[], max_output)?;
+    if consumed != data.len() {
+        return Err(InflateError::TrailingData);
+    }
+    Ok(decompressed_data)
+}
+
+/// Decompresses a raw DEFLATE stream that spans the whole input, and was
+/// compressed with the specified preset dictionary.
+pub fn inflate_with_dictionary(data: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, InflateError> {
+    let (decompressed_data, consumed) =
+        inflate_prefix_with_dictionary(data, dictionary, usize::MAX)?;
+    if consumed != data.len() {
+        return Err(InflateError::TrailingData);
+    }
+    Ok(decompressed_data)
+}
+
+/// Decompresses the raw DEFLATE stream at the start of the input, returning
+/// the decompressed data and the amount of input bytes the stream took. Any
+/// data after the end of the stream is ignored, which is useful for streams
+/// embedded in container formats.
+pub fn inflate_prefix(data: &[u8]) -> Result<(Vec<u8>, usize), InflateError> {
+    inflate_prefix_with_dictionary(data, &[], usize::MAX)
+}
+
+fn inflate_prefix_with_dictionary(
+    data: &[u8],
+    dictionary: &[u8],
+    max_output: usize,
+) -> Result<(Vec<u8>, usize), InflateError> {
+    let dictionary = &dictionary[dictionary.len().saturating_sub(ZOPFLI_WINDOW_SIZE)..];
+
+    let mut output =
+        Vec::with_capacity(dictionary.len() + cmp::min(data.len().saturating_mul(3), max_output));
+    output.extend_from_slice(dictionary);
+
+    let mut decoder = Decoder {
+        input: BitReader::new(data),
+        output,
+        max_output: dictionary.len().saturating_add(max_output),
+    };
+    decoder.decode_blocks()?;
+
+    let consumed = decoder.input.consumed_bytes();
+    let mut output = decoder.output;
+    output.drain(..dictionary.len());
+
+    Ok((output, consumed))
+}
+
+/// Decompresses a zlib stream that spans the whole input, checking its
+/// Adler-32 checksum.
+#[cfg(feature = "zlib")]
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, InflateError> {
+    zlib_decompress_with_dictionary(data, &[])
+}
+
+/// Decompresses a zlib stream that spans the whole input, checking its
+/// Adler-32 checksum, and failing with [`InflateError::OutputTooLarge`] if it
+/// decompresses to more than `max_output` bytes.
+#[cfg(feature = "zlib")]
+pub fn zlib_decompress_limited(data: &[u8], max_output: usize) -> Result<Vec<u8>, InflateError> {
+    zlib_decompress_impl(data, &[], max_output)
+}
+
+/// Decompresses a zlib stream that spans the whole input, checking its
+/// Adler-32 checksum. If the stream declares a preset dictionary, the
+/// specified one is used, after checking that its Adler-32 checksum matches
+/// the one stored in the header.
+#[cfg(feature = "zlib")]
+pub fn zlib_decompress_with_dictionary(
+    data: &[u8],
+    dictionary: &[u8],
+) -> Result<Vec<u8>, InflateError> {
+    zlib_decompress_impl(data, dictionary, usize::MAX)
+}
+
+#[cfg(feature = "zlib")]
+fn zlib_decompress_impl(
+    data: &[u8],
+    dictionary: &[u8],
+    max_output: usize,
+) -> Result<Vec<u8>, InflateError> {
+    let header = data.get(..2).ok_or(InflateError::UnexpectedEof)?;
+    let (cmf, flg) = (header[0], header[1]);
+    if cmf & 0x0F != 8 || cmf >> 4 > 7 || u16::from_be_bytes([cmf, flg]) % 31 != 0 {
+        return Err(InflateError::InvalidHeader);
+    }
+
+    let mut offset = 2;
+    let dictionary = if flg & 0x20 != 0 {
+        let dictid = read_u32_be(data, offset)?;
+        offset += 4;
+        if dictionary.is_empty() {
+            return Err(InflateError::DictionaryRequired);
+        }
+        let actual = adler32(dictionary);
+        if actual != dictid {
+            return Err(InflateError::ChecksumMismatch {
+                expected: dictid,
+                actual,
+            });
+        }
+        dictionary
+    } else {
+        &[]
+    };
+
+    let (decompressed_data, consumed) =
+        inflate_prefix_with_dictionary(&data[offset..], dictionary, max_output)?;
+    offset += consumed;
+
+    let expected = read_u32_be(data, offset)?;
+    let actual = adler32(&decompressed_data);
+    if expected != actual {
+        return Err(InflateError::ChecksumMismatch { expected, actual });
+    }
+    if offset + 4 != data.len() {
+        return Err(InflateError::TrailingData);
+    }
+
+    Ok(decompressed_data)
+}
+
+/// Decompresses a gzip file that spans the whole input, checking the CRC-32
+/// and uncompressed size of every member. The decompressed data of each
+/// member is concatenated, like `gzip -d` does.
+#[cfg(feature = "gzip")]
+pub fn gzip_decompress(data: &[u8]) -> Result<Vec<u8>, InflateError> {
+    gzip_decompress_limited(data, usize::MAX)
+}
+
+/// Decompresses a gzip file that spans the whole input, as
+/// [`gzip_decompress`] does, failing with [`InflateError::OutputTooLarge`] if
+/// its members decompress to more than `max_output` bytes in total.
+#[cfg(feature = "gzip")]
+pub fn gzip_decompress_limited(data: &[u8], max_output: usize) -> Result<Vec<u8>, InflateError> {
+    let mut decompressed_data = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let (member_data, member_size) =
+            gzip_decompress_member(&data[offset..], max_output - decompressed_data.len())?;
+        decompressed_data.extend_from_slice(&member_data);
+        offset += member_size;
+
+        if offset == data.len() {
+            return Ok(decompressed_data);
+        }
+    }
+}
+
+/// Decompresses the gzip member at the start of the input, returning its
+/// decompressed data and its size in the input. The decompressed data may
+/// take at most `max_output` bytes.
+#[cfg(feature = "gzip")]
+pub(crate) fn gzip_decompress_member(
+    data: &[u8],
+    max_output: usize,
+) -> Result<(Vec<u8>, usize), InflateError> {
+    let mut offset = gzip_header_size(data)?;
+
+    let (decompressed_data, consumed) =
+        inflate_prefix_with_dictionary(&data[offset..], &[], max_output)?;
+    offset += consumed;
+
+    let expected = read_u32_le(data, offset)?;
+    let actual = crc32fast::hash(&decompressed_data);
+    if expected != actual {
+        return Err(InflateError::ChecksumMismatch { expected, actual });
+    }
+
+    let expected = read_u32_le(data, offset + 4)?;
+    let actual = decompressed_data.len() as u32;
+    if expected != actual {
+        return Err(InflateError::SizeMismatch { expected, actual });
+    }
+
+    Ok((decompressed_data, offset + 8))
+}
+
+/// Returns the size of the gzip member header at the start of the input,
+/// checking that it is well-formed.
+#[cfg(feature = "gzip")]
+pub(crate) fn gzip_header_size(data: &[u8]) -> Result<usize, InflateError> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+    const RESERVED: u8 = 0b1110_0000;
+
+    let fixed_fields = data.get(..10).ok_or(InflateError::UnexpectedEof)?;
+    let flags = fixed_fields[3];
+    if fixed_fields[..3] != [31, 139, 8] || flags & RESERVED != 0 {
+        return Err(InflateError::InvalidHeader);
+    }
+
+    let mut offset = 10;
+    if flags & FEXTRA != 0 {
+        let xlen = data
+            .get(offset..offset + 2)
+            .ok_or(InflateError::UnexpectedEof)?;
+        offset += 2 + u16::from_le_bytes([xlen[0], xlen[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let terminator = data
+                .get(offset..)
+                .and_then(|field| field.iter().position(|&byte| byte == 0))
+                .ok_or(InflateError::UnexpectedEof)?;
+            offset += terminator + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        let crc16 = data
+            .get(offset..offset + 2)
+            .ok_or(InflateError::UnexpectedEof)?;
+        if u16::from_le_bytes([crc16[0], crc16[1]]) != crc32fast::hash(&data[..offset]) as u16 {
+            return Err(InflateError::InvalidHeader);
+        }
+        offset += 2;
+    }
+
+    if offset > data.len() {
+        return Err(InflateError::UnexpectedEof);
+    }
+    Ok(offset)
+}
+
+#[cfg(feature = "zlib")]
+fn adler32(data: &[u8]) -> u32 {
+    let mut hasher = simd_adler32::Adler32::new();
+    hasher.write(data);
+    hasher.finish()
+}
+
+#[cfg(feature = "zlib")]
+fn read_u32_be(data: &[u8], offset: usize) -> Result<u32, InflateError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or(InflateError::UnexpectedEof)
+}
+
+#[cfg(feature = "gzip")]
+fn read_u32_le(data: &[u8], offset: usize) -> Result<u32, InflateError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(InflateError::UnexpectedEof)
+}
+
+/// Reads bits from a byte slice, starting with the least significant bit of
+/// each byte, as DEFLATE requires.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            pos: 0,
+            bit_buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Reads the next `count` bits, which must be at most 16.
+    fn bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        while self.bit_count < count {
+            let byte = *self.data.get(self.pos).ok_or(InflateError::UnexpectedEof)?;
+            self.bit_buffer |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+            self.pos += 1;
+        }
+
+        let value = self.bit_buffer & ((1 << count) - 1);
+        self.bit_buffer >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Discards the remaining bits of the current byte and returns the
+    /// following `count` bytes.
+    fn aligned_bytes(&mut self, count: usize) -> Result<&'a [u8], InflateError> {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + count)
+            .ok_or(InflateError::UnexpectedEof)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    /// Returns how many bytes have been read, including the last partially
+    /// read one.
+    fn consumed_bytes(&self) -> usize {
+        self.pos
+    }
+}
+
+/// A canonical Huffman code, represented as the number of codes of each
+/// length and the symbols sorted by code.
+struct Huffman {
+    counts: [u16; MAX_CODE_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Builds the code for the given code lengths. Incomplete codes are only
+    /// allowed when they have a single symbol, as DEFLATE permits for
+    /// distance codes.
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut counts = [0; MAX_CODE_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+
+        // Check for an over-subscribed or incomplete code
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left <<= 1;
+            left -= count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+        }
+        let used_symbols = lengths.len() - counts[0] as usize;
+        if left > 0 && used_symbols > 1 {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+
+        let mut offsets = [0; MAX_CODE_BITS + 1];
+        for length in 1..MAX_CODE_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+
+        let mut symbols = vec![0; used_symbols];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+
+    /// Decodes the next symbol from the reader.
+    fn decode(&self, input: &mut BitReader) -> Result<u16, InflateError> {
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+
+        for &count in &self.counts[1..] {
+            code |= input.bits(1)? as i32;
+            let count = count as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+
+        Err(InflateError::InvalidSymbol)
+    }
+}
+
+struct Decoder<'a> {
+    input: BitReader<'a>,
+    output: Vec<u8>,
+    /// The maximum length of the output, including any preset dictionary.
+    max_output: usize,
+}
+
+impl Decoder<'_> {
+    fn decode_blocks(&mut self) -> Result<(), InflateError> {
+        loop {
+            let is_final = self.input.bits(1)? == 1;
+            match self.input.bits(2)? {
+                0 => self.stored_block()?,
+                1 => {
+                    let (ll_code, d_code) = fixed_codes();
+                    self.compressed_block(&ll_code, &d_code)?;
+                }
+                2 => {
+                    let (ll_code, d_code) = self.dynamic_codes()?;
+                    self.compressed_block(&ll_code, &d_code)?;
+                }
+                _ => return Err(InflateError::InvalidBlockType),
+            }
+
+            if is_final {
+                return Ok(());
+            }
+        }
+    }
+
+    fn stored_block(&mut self) -> Result<(), InflateError> {
+        let header = self.input.aligned_bytes(4)?;
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        let nlen = u16::from_le_bytes([header[2], header[3]]);
+        if len != !nlen {
+            return Err(InflateError::InvalidStoredLength);
+        }
+
+        let data = self.input.aligned_bytes(len as usize)?;
+        self.reserve_output(data.len())?;
+        self.output.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Checks that `length` more bytes fit in the output.
+    fn reserve_output(&self, length: usize) -> Result<(), InflateError> {
+        if length > self.max_output - self.output.len() {
+            return Err(InflateError::OutputTooLarge);
+        }
+        Ok(())
+    }
+
+    fn dynamic_codes(&mut self) -> Result<(Huffman, Huffman), InflateError> {
+        let hlit = self.input.bits(5)? as usize + 257;
+        let hdist = self.input.bits(5)? as usize + 1;
+        let hclen = self.input.bits(4)? as usize + 4;
+        if hlit > MAX_LL_CODES || hdist > MAX_D_CODES {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+
+        let mut cl_lengths = [0; NUM_CL_CODES];
+        for &symbol in &CL_ORDER[..hclen] {
+            cl_lengths[symbol] = self.input.bits(3)? as u8;
+        }
+        let cl_code = Huffman::new(&cl_lengths)?;
+
+        let mut lengths = Vec::with_capacity(hlit + hdist);
+        while lengths.len() < hlit + hdist {
+            let symbol = cl_code.decode(&mut self.input)?;
+            let (length, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    let previous = *lengths.last().ok_or(InflateError::InvalidCodeLengths)?;
+                    (previous, 3 + self.input.bits(2)?)
+                }
+                17 => (0, 3 + self.input.bits(3)?),
+                _ => (0, 11 + self.input.bits(7)?),
+            };
+            if lengths.len() + repeat as usize > hlit + hdist {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+            lengths.extend(core::iter::repeat(length).take(repeat as usize));
+        }
+
+        // The end of block code must be present
+        if lengths[256] == 0 {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+
+        Ok((
+            Huffman::new(&lengths[..hlit])?,
+            Huffman::new(&lengths[hlit..])?,
+        ))
+    }
+
+    fn compressed_block(
+        &mut self,
+        ll_code: &Huffman,
+        d_code: &Huffman,
+    ) -> Result<(), InflateError> {
+        loop {
+            let symbol = ll_code.decode(&mut self.input)? as usize;
+            match symbol {
+                0..=255 => {
+                    self.reserve_output(1)?;
+                    self.output.push(symbol as u8);
+                }
+                256 => return Ok(()),
+                257..=285 => {
+                    let length_symbol = symbol - 257;
+                    let length = LENGTH_BASE[length_symbol] as usize
+                        + self.input.bits(LENGTH_EXTRA[length_symbol] as u32)? as usize;
+
+                    let dist_symbol = d_code.decode(&mut self.input)? as usize;
+                    if dist_symbol >= MAX_D_CODES {
+                        return Err(InflateError::InvalidSymbol);
+                    }
+                    let dist = DIST_BASE[dist_symbol] as usize
+                        + self.input.bits(DIST_EXTRA[dist_symbol] as u32)? as usize;
+                    if dist > self.output.len() {
+                        return Err(InflateError::DistanceTooFarBack);
+                    }
+
+                    // Copy byte by byte, because the source and destination
+                    // ranges overlap when the distance is less than the length
+                    self.reserve_output(length)?;
+                    let start = self.output.len() - dist;
+                    for i in start..start + length {
+                        let byte = self.output[i];
+                        self.output.push(byte);
+                    }
+                }
+                _ => return Err(InflateError::InvalidSymbol),
+            }
+        }
+    }
+}
+
+/// Returns the literal/length and distance codes used by blocks compressed
+/// with fixed Huffman codes (BTYPE=01).
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut ll_lengths = [0; 288];
+    ll_lengths[..144].fill(8);
+    ll_lengths[144..256].fill(9);
+    ll_lengths[256..280].fill(7);
+    ll_lengths[280..].fill(8);
+
+    // Distance symbols 30 and 31 complete the code, but never occur in
+    // valid streams
+    (
+        Huffman::new(&ll_lengths).unwrap(),
+        Huffman::new(&[5; 32]).unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlockType, DeflateEncoder, Options, Write};
+
+    fn deflate(data: &[u8], btype: BlockType) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Options::default(), btype, vec![]);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn inflates_every_block_type() {
+        let data = b"Hello, Zopfli! Hello, Zopfli! Hello, inflate! Hello, inflate!".repeat(40);
+
+        for btype in [
+            BlockType::Uncompressed,
+            BlockType::Fixed,
+            BlockType::Dynamic,
+        ] {
+            assert_eq!(inflate(&deflate(&data, btype)).unwrap(), data);
+        }
+        assert_eq!(inflate(&deflate(&[], BlockType::Dynamic)).unwrap(), []);
+    }
+
+    #[test]
+    fn reports_consumed_bytes_and_trailing_data() {
+        let mut compressed_data = deflate(b"abcabcabc", BlockType::Dynamic);
+        let stream_size = compressed_data.len();
+        compressed_data.extend_from_slice(b"trailer");
+
+        assert_eq!(
+            inflate_prefix(&compressed_data).unwrap(),
+            (b"abcabcabc".to_vec(), stream_size)
+        );
+        assert_eq!(inflate(&compressed_data), Err(InflateError::TrailingData));
+        assert_eq!(
+            inflate(&compressed_data[..stream_size - 1]),
+            Err(InflateError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_streams() {
+        assert_eq!(inflate(&[0b111]), Err(InflateError::InvalidBlockType));
+        assert_eq!(
+            inflate(&[1, 5, 0, 0, 0]),
+            Err(InflateError::InvalidStoredLength)
+        );
+        // Fixed block with a distance of 1 before any data
+        assert_eq!(inflate(&[3, 2]), Err(InflateError::DistanceTooFarBack));
+    }
+
+    #[test]
+    fn limits_the_output_size() {
+        // Backreferences, literals and stored blocks each hit the limit
+        let zeros = deflate(&[0; 100_000], BlockType::Fixed);
+        let text = deflate(b"abcdefghijklmnopqrstuvwxyz", BlockType::Fixed);
+        let stored = deflate(&[7; 1000], BlockType::Uncompressed);
+
+        for (compressed_data, size) in [(zeros, 100_000), (text, 26), (stored, 1000)] {
+            assert_eq!(inflate_limited(&compressed_data, size).unwrap().len(), size);
+            assert_eq!(
+                inflate_limited(&compressed_data, size - 1),
+                Err(InflateError::OutputTooLarge)
+            );
+        }
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn checks_zlib_checksum() {
+        let mut encoder =
+            crate::ZlibEncoder::new(Options::default(), BlockType::Dynamic, vec![]).unwrap();
+        encoder.write_all(b"zlib data").unwrap();
+        let mut compressed_data = encoder.finish().unwrap();
+
+        assert_eq!(zlib_decompress(&compressed_data).unwrap(), b"zlib data");
+
+        *compressed_data.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            zlib_decompress(&compressed_data),
+            Err(InflateError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn uses_zlib_preset_dictionary() {
+        let dictionary = b"a preset dictionary";
+        let mut encoder = crate::ZlibEncoder::new_with_dictionary(
+            Options::default(),
+            BlockType::Dynamic,
+            dictionary,
+            vec![],
+        )
+        .unwrap();
+        encoder.write_all(b"a preset dictionary helps").unwrap();
+        let compressed_data = encoder.finish().unwrap();
+
+        assert_eq!(
+            zlib_decompress(&compressed_data),
+            Err(InflateError::DictionaryRequired)
+        );
+        assert_eq!(
+            zlib_decompress_with_dictionary(&compressed_data, dictionary).unwrap(),
+            b"a preset dictionary helps"
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn decompresses_concatenated_gzip_members() {
+        let mut compressed_data = vec![];
+        for (member_data, header) in [
+            (&b"first"[..], crate::GzipHeader::new()),
+            (
+                b"second",
+                crate::GzipHeader::new().filename("b").header_crc(true),
+            ),
+        ] {
+            let mut encoder = crate::GzipEncoder::new_with_header(
+                Options::default(),
+                BlockType::Dynamic,
+                &header,
+                &mut compressed_data,
+            )
+            .unwrap();
+            encoder.write_all(member_data).unwrap();
+            encoder.finish().unwrap();
+        }
+
+        assert_eq!(gzip_decompress(&compressed_data).unwrap(), b"firstsecond");
+
+        let isize_offset = compressed_data.len() - 4;
+        compressed_data[isize_offset] ^= 1;
+        assert!(matches!(
+            gzip_decompress(&compressed_data),
+            Err(InflateError::SizeMismatch { .. })
+        ));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn limits_the_total_size_of_gzip_members() {
+        let mut compressed_data = vec![];
+        for member_data in [b"first", b"other"] {
+            let mut encoder = crate::GzipEncoder::new(
+                Options::default(),
+                BlockType::Dynamic,
+                &mut compressed_data,
+            )
+            .unwrap();
+            encoder.write_all(member_data).unwrap();
+            encoder.finish().unwrap();
+        }
+
+        assert_eq!(
+            gzip_decompress_limited(&compressed_data, 10).unwrap(),
+            b"firstother"
+        );
+        assert_eq!(
+            gzip_decompress_limited(&compressed_data, 9),
+            Err(InflateError::OutputTooLarge)
+        );
+    }
+}
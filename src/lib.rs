@@ -14,16 +14,28 @@
 //!
 //! - `gzip` (enabled by default): enables support for compression in the gzip format.
 //! - `zlib` (enabled by default): enables support for compression in the Zlib format.
+//! - `png`: enables the `png` module, which optimizes the compression of PNG
+//!   images. Implies `zlib` and `inflate`.
+//! - `recompress`: enables the `recompress` module, which recompresses the
+//!   DEFLATE payloads of existing gzip, zlib and ZIP files. Implies `gzip`, `zlib`, `zip` and
+//!   `inflate`.
 //! - `std` (enabled by default): enables linking against the Rust standard library. When not enabled,
-//!                               the crate is built with the `#![no_std]` attribute and can be used
-//!                               in any environment where [`alloc`](https://doc.rust-lang.org/alloc/)
-//!                               (i.e., a memory allocator) is available. In addition, the crate
-//!                               exposes minimalist versions of the `std` I/O traits it needs to
-//!                               function, allowing users to implement them. Disabling `std` requires
-//!                               enabling `nightly` due to dependencies on unstable language features.
+//!   the crate is built with the `#![no_std]` attribute and can be used in any environment where
+//!   [`alloc`](https://doc.rust-lang.org/alloc/) (i.e., a memory allocator) is available. In
+//!   addition, the crate exposes minimalist versions of the `std` I/O traits it needs to function,
+//!   allowing users to implement them. Disabling `std` requires enabling `nightly` due to
+//!   dependencies on unstable language features.
+//! - `parallel`: enables compressing independent parts of the input on several threads, as
+//!   configured by [`Options::threads`]. Requires `std`.
+//! - `tokio` and `futures-io`: enable the namesake modules, which provide encoders that write to
+//!   the `AsyncWrite` sinks of these crates, compressing on a blocking thread. Require `std`.
+//! - `inflate`: enables the `inflate` module, a DEFLATE decoder that can check that compressed
+//!   data decompresses back to its input, and the [`Options::verify`] option that makes use of it.
+//! - `zip`: enables the `zip` module, which writes ZIP archives whose entries are compressed with
+//!   Zopfli.
 //! - `nightly`: enables performance optimizations that are specific to the nightly Rust toolchain.
-//!              Currently, this feature improves rustdoc generation and enables the namesake feature
-//!              on `crc32fast` and `simd-adler32`, but this may change in the future.
+//!   Currently, this feature improves rustdoc generation and enables the namesake feature on
+//!   `crc32fast` and `simd-adler32`, but this may change in the future.
 
 #![cfg_attr(not(feature = "std"), no_std)]
 #![cfg_attr(
@@ -36,35 +48,59 @@
 #[cfg_attr(not(feature = "std"), macro_use)]
 extern crate alloc;
 
-pub use deflate::{BlockType, DeflateEncoder};
+pub use deflate::{deflate_slice, BlockType, DeflateEncoder, FlushMode};
 #[cfg(feature = "gzip")]
-pub use gzip::GzipEncoder;
+pub use gzip::{GzipEncoder, GzipHeader, GzipIndex, GzipMember};
+pub use progress::{Progress, ProgressStage, ProgressUpdate};
 #[cfg(all(test, feature = "std"))]
 use proptest::prelude::*;
+pub use stats::CompressionStats;
 #[cfg(feature = "zlib")]
 pub use zlib::ZlibEncoder;
 
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+mod async_encoder;
 mod blocksplitter;
 mod cache;
 mod deflate;
+#[cfg(feature = "futures-io")]
+pub mod futures_io;
 #[cfg(feature = "gzip")]
 mod gzip;
 mod hash;
+#[cfg(feature = "inflate")]
+pub mod inflate;
 #[cfg(any(doc, not(feature = "std")))]
 mod io;
 mod iter;
 mod katajainen;
-mod lz77;
+pub mod lz77;
 #[cfg(not(feature = "std"))]
 mod math;
+#[cfg(feature = "png")]
+pub mod png;
+mod progress;
+#[cfg(feature = "std")]
+pub mod read;
+#[cfg(feature = "recompress")]
+pub mod recompress;
 mod squeeze;
+mod stats;
 mod symbols;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 mod tree;
 mod util;
+#[cfg(feature = "zip")]
+pub mod zip;
 #[cfg(feature = "zlib")]
 mod zlib;
 
 use core::num::NonZeroU64;
+use core::num::NonZeroUsize;
+use core::time::Duration;
 #[cfg(all(not(doc), feature = "std"))]
 use std::io::{Error, Write};
 
@@ -98,6 +134,93 @@ pub struct Options {
     ///
     /// Default value: 15.
     pub maximum_block_splits: u16,
+    /// Seed of the pseudorandom generator that perturbs the cost model once
+    /// rerunning forward and backward pass stops improving the result. The
+    /// output is deterministic for a given seed, and some inputs compress
+    /// better with seeds other than the default.
+    ///
+    /// Default value: 0, which matches the reference implementation.
+    pub seed: u32,
+    /// Amount of additional times to optimize the LZ77 representation of each
+    /// block split segment, starting over with the next seeds after `seed`.
+    /// The smallest representation is kept. As randomization only starts after
+    /// several iterations, restarts are useless with low iteration counts.
+    ///
+    /// Default value: 0.
+    #[cfg_attr(all(test, feature = "std"), proptest(strategy = "0..=1u16"))]
+    pub restarts: u16,
+    /// Maximum amount of memory, in bytes, that the caches of longest matches
+    /// may take at the same time, or 0 for no limit. When several threads are
+    /// used, each of them gets an equal share.
+    ///
+    /// Without a limit, the cache for each master block takes about 28 bytes
+    /// per input byte, which is 28 MB for a 1 MB master block. Smaller limits
+    /// store less information per position first, and then cache only the
+    /// first positions of each master block, down to not caching at all.
+    /// Smaller caches do not change the output, but make compression slower,
+    /// by up to several times without a cache. Other memory used during
+    /// compression, which is several times the master block size, is not
+    /// limited.
+    ///
+    /// Default value: 0.
+    #[cfg_attr(all(test, feature = "std"), proptest(strategy = "0..=100_000usize"))]
+    pub maximum_cache_memory: usize,
+    /// Size in bytes of the master blocks the input is divided into, or 0 to
+    /// compress the whole input as a single master block. The compression
+    /// algorithm, including block splitting, runs independently on each master
+    /// block, and encoders collect written data until they have a complete
+    /// one. Bigger master blocks give better compression for big inputs, but
+    /// memory usage grows with them, as described in
+    /// [`maximum_cache_memory`](Self::maximum_cache_memory).
+    ///
+    /// Default value: 1000000.
+    #[cfg_attr(
+        all(test, feature = "std"),
+        proptest(
+            strategy = "proptest::prop_oneof![proptest::strategy::Just(0), 1000..=100_000usize]"
+        )
+    )]
+    pub master_block_size: usize,
+    /// Maximum amount of time to spend rerunning forward and backward pass for
+    /// each MiB (2^20 bytes) of input data. Each block split segment gets its
+    /// share of the budget, proportional to its size, and stops iterating once
+    /// it is spent, keeping the best representation found so far. At least one
+    /// iteration is always run, and the rest of the compression process is not
+    /// limited, so the total compression time may exceed the budget, especially
+    /// for small inputs. Iterations are still limited by the other options.
+    /// Without the `std` feature, there is no clock to measure time with, so
+    /// this option is ignored.
+    ///
+    /// Default value: `None` (no time limit).
+    #[cfg_attr(all(test, feature = "std"), proptest(value = "None"))]
+    pub time_budget_per_mib: Option<Duration>,
+    /// Maximum amount of threads to use for compressing data in parallel. The
+    /// [master blocks](Self::master_block_size) that the input is divided into
+    /// and the segments each master block is split into are optimized
+    /// concurrently. Values greater than one make encoders wait until
+    /// they have a master block for each thread before compressing them all at
+    /// once, which multiplies memory usage by about the same factor. The output
+    /// is the same no matter how many threads are used. Without the
+    /// `parallel` feature, this option is ignored and a single thread is used.
+    ///
+    /// Default value: 1.
+    #[cfg_attr(
+        all(test, feature = "std"),
+        proptest(
+            strategy = "(1..=4usize).prop_map(|threads| NonZeroUsize::new(threads).unwrap())"
+        )
+    )]
+    pub threads: NonZeroUsize,
+    /// Whether [`compress`] should decompress its output and check that it
+    /// matches the input before writing it to the sink, returning an error
+    /// otherwise. This requires holding both the input and the output in
+    /// memory. Encoders and [`compress_to_vec`] ignore this option. Without
+    /// the `inflate` feature, there is no decoder to check the output with, so
+    /// [`compress`] returns an error when this option is set.
+    ///
+    /// Default value: false.
+    #[cfg_attr(all(test, feature = "std"), proptest(value = "false"))]
+    pub verify: bool,
 }
 
 impl Default for Options {
@@ -106,6 +229,13 @@ impl Default for Options {
             iteration_count: NonZeroU64::new(15).unwrap(),
             iterations_without_improvement: NonZeroU64::new(u64::MAX).unwrap(),
             maximum_block_splits: 15,
+            seed: 0,
+            restarts: 0,
+            maximum_cache_memory: 0,
+            master_block_size: util::ZOPFLI_MASTER_BLOCK_SIZE,
+            time_budget_per_mib: None,
+            threads: NonZeroUsize::new(1).unwrap(),
+            verify: false,
         }
     }
 }
@@ -140,35 +270,123 @@ pub enum Format {
 
 /// Compresses data from a source with the Zopfli algorithm, using the specified
 /// options, and writes the result to a sink in the defined output format.
+/// Returns statistics about the compressed data.
+///
+/// When [`Options::verify`] is set, the whole input and output are held in
+/// memory, and the output is only written once it decompresses to the input.
 #[cfg(feature = "std")]
 pub fn compress<R: std::io::Read, W: Write>(
     options: Options,
     output_format: Format,
     mut in_data: R,
     out: W,
-) -> Result<(), Error> {
+) -> Result<CompressionStats, Error> {
+    if options.verify {
+        return compress_verified(options, output_format, in_data, out);
+    }
+
     match output_format {
         #[cfg(feature = "gzip")]
         Format::Gzip => {
             let mut gzip_encoder = GzipEncoder::new_buffered(options, BlockType::Dynamic, out)?;
             std::io::copy(&mut in_data, &mut gzip_encoder)?;
-            gzip_encoder.into_inner()?.finish().map(|_| ())
+            gzip_encoder
+                .into_inner()?
+                .finish_with_stats()
+                .map(|(_, stats)| stats)
         }
         #[cfg(feature = "zlib")]
         Format::Zlib => {
             let mut zlib_encoder = ZlibEncoder::new_buffered(options, BlockType::Dynamic, out)?;
             std::io::copy(&mut in_data, &mut zlib_encoder)?;
-            zlib_encoder.into_inner()?.finish().map(|_| ())
+            zlib_encoder
+                .into_inner()?
+                .finish_with_stats()
+                .map(|(_, stats)| stats)
         }
         Format::Deflate => {
             let mut deflate_encoder =
                 DeflateEncoder::new_buffered(options, BlockType::Dynamic, out);
             std::io::copy(&mut in_data, &mut deflate_encoder)?;
-            deflate_encoder.into_inner()?.finish().map(|_| ())
+            deflate_encoder
+                .into_inner()?
+                .finish_with_stats()
+                .map(|(_, stats)| stats)
         }
     }
 }
 
+/// Compresses data that is entirely in memory with the Zopfli algorithm,
+/// using the specified options, and returns the result in the defined output
+/// format.
+///
+/// The output is the same as that of [`compress`], but the data is compressed
+/// in place by [`deflate_slice`], without going through intermediate buffers.
+#[cfg(feature = "std")]
+pub fn compress_to_vec(options: Options, output_format: Format, data: &[u8]) -> Vec<u8> {
+    let compressed_data = Vec::with_capacity(data.len() / 2);
+    match output_format {
+        #[cfg(feature = "gzip")]
+        Format::Gzip => gzip::gzip_slice(options, BlockType::Dynamic, data, compressed_data),
+        #[cfg(feature = "zlib")]
+        Format::Zlib => zlib::zlib_slice(options, BlockType::Dynamic, data, compressed_data),
+        Format::Deflate => deflate_slice(options, BlockType::Dynamic, data, compressed_data),
+    }
+    .expect("Writing to a Vec should not fail")
+}
+
+/// Implements [`compress`] when [`Options::verify`] is set, compressing the
+/// whole input in memory and decompressing the result before writing it.
+#[cfg(all(feature = "std", feature = "inflate"))]
+fn compress_verified<R: std::io::Read, W: Write>(
+    options: Options,
+    output_format: Format,
+    mut in_data: R,
+    mut out: W,
+) -> Result<CompressionStats, Error> {
+    let mut data = vec![];
+    in_data.read_to_end(&mut data)?;
+
+    let mut compressed_data = Vec::with_capacity(data.len() / 2);
+    let options = Options {
+        verify: false,
+        ..options
+    };
+    let stats = compress(options, output_format, &*data, &mut compressed_data)?;
+
+    let decompressed_data = match output_format {
+        #[cfg(feature = "gzip")]
+        Format::Gzip => inflate::gzip_decompress(&compressed_data),
+        #[cfg(feature = "zlib")]
+        Format::Zlib => inflate::zlib_decompress(&compressed_data),
+        Format::Deflate => inflate::inflate(&compressed_data),
+    }?;
+    if decompressed_data != data {
+        return Err(Error::new(
+            std::io::ErrorKind::Other,
+            "compressed data does not decompress to the input data",
+        ));
+    }
+
+    out.write_all(&compressed_data)?;
+    Ok(stats)
+}
+
+/// Implements [`compress`] when [`Options::verify`] is set but there is no
+/// decoder to check the output with.
+#[cfg(all(feature = "std", not(feature = "inflate")))]
+fn compress_verified<R: std::io::Read, W: Write>(
+    _options: Options,
+    _output_format: Format,
+    _in_data: R,
+    _out: W,
+) -> Result<CompressionStats, Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "verifying compressed data requires the inflate feature",
+    ))
+}
+
 #[cfg(all(test, feature = "std"))]
 mod test {
     use std::io;
@@ -195,4 +413,55 @@ mod test {
             prop_assert_eq!(data, decompressed_data, "Decompressed data should match input data");
         }
     }
+
+    #[cfg(all(feature = "gzip", feature = "zlib"))]
+    #[test]
+    fn compressing_to_vec_writes_the_same_output() {
+        let data = b"Compress me in one go, compress me in one go".repeat(100);
+
+        for format in [Format::Gzip, Format::Zlib, Format::Deflate] {
+            let mut compressed_data = vec![];
+            compress(Options::default(), format, &*data, &mut compressed_data).unwrap();
+
+            assert_eq!(
+                compress_to_vec(Options::default(), format, &data),
+                compressed_data
+            );
+        }
+    }
+
+    #[cfg(all(feature = "inflate", feature = "gzip", feature = "zlib"))]
+    #[test]
+    fn verified_compression_writes_the_same_output() {
+        let data = b"Verify me, verify me, verify me please".repeat(100);
+        let verify_options = Options {
+            verify: true,
+            ..Options::default()
+        };
+
+        for format in [Format::Gzip, Format::Zlib, Format::Deflate] {
+            let mut compressed_data = vec![];
+            compress(Options::default(), format, &*data, &mut compressed_data).unwrap();
+            let mut verified_data = vec![];
+            compress(verify_options, format, &*data, &mut verified_data).unwrap();
+
+            assert_eq!(compressed_data, verified_data);
+        }
+    }
+
+    #[cfg(not(feature = "inflate"))]
+    #[test]
+    fn verified_compression_requires_the_inflate_feature() {
+        let options = Options {
+            verify: true,
+            ..Options::default()
+        };
+        let mut compressed_data = vec![];
+
+        let error =
+            compress(options, Format::Deflate, &b"data"[..], &mut compressed_data).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert!(compressed_data.is_empty());
+    }
 }
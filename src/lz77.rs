@@ -1,41 +1,92 @@
+//! The LZ77 parsing and block splitting stages of the Zopfli algorithm, for
+//! reusing its parser and cost model in formats that do their own framing.
+//!
+//! Input ranges are given as `instart..inend` positions in a data slice. The
+//! bytes before `instart`, up to 32 KiB of them, are used as the window that
+//! backreferences may point into, which allows compressing data in parts. The
+//! resulting [`Lz77Store`]s can be inspected, edited or built from scratch,
+//! split into blocks and written as a DEFLATE stream:
+//!
+//! ```
+//! # use zopfli::{lz77, Options};
+//! let data = b"Hello, Zopfli! Hello, LZ77!";
+//! let store = lz77::optimal_parse(Options::default(), data, 0, data.len());
+//! let splitpoints = lz77::split_blocks(&store, 15);
+//! let compressed_data =
+//!     lz77::write_blocks(Options::default(), &store, &splitpoints, data, Vec::new())
+//!         .expect("Writing to a Vec should not fail");
+//! ```
+//!
+//! Parsing can also minimize the cost given by a custom [`CostModel`], with
+//! [`parse_with_cost_model`].
+
 use alloc::{boxed::Box, vec::Vec};
 use core::cmp;
 
+pub use crate::squeeze::{CostModel, FixedCostModel, StatisticsCostModel};
 use crate::{
-    cache::Cache,
+    blocksplitter::blocksplit_lz77,
+    cache::{Cache, NoCache, ZopfliLongestMatchCache},
+    deflate::{
+        blocksplit_attempt, calculate_block_size, calculate_block_size_auto_type, write_lz77_blocks,
+    },
     hash::{Which, ZopfliHash},
+    progress::ProgressReporter,
+    squeeze::{lz77_cost_model_run, lz77_optimal, lz77_optimal_fixed},
     symbols::{get_dist_symbol, get_length_symbol},
     util::{
         boxed_array, ZOPFLI_MAX_CHAIN_HITS, ZOPFLI_MAX_MATCH, ZOPFLI_MIN_MATCH, ZOPFLI_NUM_D,
         ZOPFLI_NUM_LL, ZOPFLI_WINDOW_MASK, ZOPFLI_WINDOW_SIZE,
     },
+    BlockType, Error, Options, Write,
 };
 
-#[derive(Clone, Copy)]
+/// An item of LZ77 data: either a literal byte or a backreference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LitLen {
+    /// A literal byte value, in the `0..=255` range.
     Literal(u16),
+    /// A backreference with a length in the `3..=258` range and a distance
+    /// in the `1..=32768` range, in that order.
     LengthDist(u16, u16),
 }
 
 impl LitLen {
+    /// Returns the amount of data bytes this item represents.
     pub fn size(&self) -> usize {
         match *self {
             LitLen::Literal(_) => 1,
             LitLen::LengthDist(len, _) => len as usize,
         }
     }
+
+    /// Returns the symbol of the literal/length alphabet that encodes this
+    /// item, in the `0..=285` range.
+    pub fn ll_symbol(&self) -> u16 {
+        match *self {
+            LitLen::Literal(byte) => byte,
+            LitLen::LengthDist(len, _) => get_length_symbol(len as usize) as u16,
+        }
+    }
+
+    /// Returns the symbol of the distance alphabet that encodes the distance
+    /// of this item, in the `0..=29` range, if it is a backreference.
+    pub fn dist_symbol(&self) -> Option<u16> {
+        match *self {
+            LitLen::Literal(_) => None,
+            LitLen::LengthDist(_, dist) => Some(get_dist_symbol(dist) as u16),
+        }
+    }
 }
 
-/// Stores lit/length and dist pairs for LZ77.
-/// Parameter litlens: Contains the literal symbols or length values.
-/// Parameter dists: Contains the distances. A value is 0 to indicate that there is
-/// no dist and the corresponding litlens value is a literal instead of a length.
-/// Parameter size: The size of both the litlens and dists arrays.
-#[derive(Clone, Default)]
+/// A sequence of LZ77 items, along with the position in the data where each
+/// of them starts, and the symbol histograms that make estimating the
+/// compressed size of ranges of it fast.
+#[derive(Debug, Clone, Default)]
 pub struct Lz77Store {
-    pub litlens: Vec<LitLen>,
+    pub(crate) litlens: Vec<LitLen>,
 
-    pub pos: Vec<usize>,
+    pub(crate) pos: Vec<usize>,
 
     ll_symbol: Vec<u16>,
     d_symbol: Vec<u16>,
@@ -45,6 +96,7 @@ pub struct Lz77Store {
 }
 
 impl Lz77Store {
+    /// Creates an empty store.
     pub fn new() -> Lz77Store {
         Lz77Store {
             litlens: vec![],
@@ -59,7 +111,7 @@ impl Lz77Store {
         }
     }
 
-    pub fn reset(&mut self) {
+    pub(crate) fn reset(&mut self) {
         self.litlens.clear();
         self.pos.clear();
         self.ll_symbol.clear();
@@ -68,11 +120,69 @@ impl Lz77Store {
         self.d_counts.clear();
     }
 
-    pub fn size(&self) -> usize {
+    pub(crate) fn size(&self) -> usize {
         self.litlens.len()
     }
 
-    pub fn append_store_item(&mut self, litlen: LitLen, pos: usize) {
+    /// Returns the amount of items in the store.
+    pub fn len(&self) -> usize {
+        self.size()
+    }
+
+    /// Returns whether the store has no items.
+    pub fn is_empty(&self) -> bool {
+        self.litlens.is_empty()
+    }
+
+    /// Returns the items in the store.
+    pub fn litlens(&self) -> &[LitLen] {
+        &self.litlens
+    }
+
+    /// Returns the position in the data where each item starts.
+    pub fn positions(&self) -> &[usize] {
+        &self.pos
+    }
+
+    /// Removes all the items from the store.
+    pub fn clear(&mut self) {
+        self.reset();
+    }
+
+    /// Appends an item that starts at the specified position in the data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the literal, length or distance of the item is out of range.
+    pub fn push(&mut self, litlen: LitLen, pos: usize) {
+        match litlen {
+            LitLen::Literal(byte) => assert!(byte <= 255, "literals must be byte values"),
+            LitLen::LengthDist(length, dist) => assert!(
+                (ZOPFLI_MIN_MATCH..=ZOPFLI_MAX_MATCH).contains(&(length as usize))
+                    && (1..=ZOPFLI_WINDOW_SIZE).contains(&(dist as usize)),
+                "backreference lengths and distances must be in range"
+            ),
+        }
+
+        self.append_store_item(litlen, pos);
+    }
+
+    /// Returns the histograms of the literal/length and distance symbols of
+    /// the items in the `lstart..lend` range, not counting the end of block
+    /// symbol.
+    pub fn histogram(&self, lstart: usize, lend: usize) -> (Box<[usize; 288]>, Box<[usize; 32]>) {
+        assert!(lstart <= lend && lend <= self.size(), "range out of bounds");
+        self.get_histogram(lstart, lend)
+    }
+
+    /// Returns the amount of data bytes that the items in the `lstart..lend`
+    /// range represent.
+    pub fn byte_range(&self, lstart: usize, lend: usize) -> usize {
+        assert!(lstart <= lend && lend <= self.size(), "range out of bounds");
+        self.get_byte_range(lstart, lend)
+    }
+
+    pub(crate) fn append_store_item(&mut self, litlen: LitLen, pos: usize) {
         let origsize = self.litlens.len();
         let llstart = ZOPFLI_NUM_LL * (origsize / ZOPFLI_NUM_LL);
         let dstart = ZOPFLI_NUM_D * (origsize / ZOPFLI_NUM_D);
@@ -119,7 +229,7 @@ impl Lz77Store {
         }
     }
 
-    pub fn lit_len_dist(&mut self, length: u16, dist: u16, pos: usize) {
+    pub(crate) fn lit_len_dist(&mut self, length: u16, dist: u16, pos: usize) {
         let litlen = if dist == 0 {
             LitLen::Literal(length)
         } else {
@@ -134,7 +244,13 @@ impl Lz77Store {
     /// The result is placed in the Lz77Store.
     /// If instart is larger than 0, it uses values before instart as starting
     /// dictionary.
-    pub fn greedy<C: Cache>(&mut self, lmc: &mut C, in_data: &[u8], instart: usize, inend: usize) {
+    pub(crate) fn greedy<C: Cache>(
+        &mut self,
+        lmc: &mut C,
+        in_data: &[u8],
+        instart: usize,
+        inend: usize,
+    ) {
         if instart == inend {
             return;
         }
@@ -225,7 +341,7 @@ impl Lz77Store {
         }
     }
 
-    pub fn follow_path<C: Cache>(
+    pub(crate) fn follow_path<C: Cache>(
         &mut self,
         in_data: &[u8],
         instart: usize,
@@ -324,7 +440,7 @@ impl Lz77Store {
     /// Gets the histogram of lit/len and dist symbols in the given range, using the
     /// cumulative histograms, so faster than adding one by one for large range. Does
     /// not add the one end symbol of value 256.
-    pub fn get_histogram(
+    pub(crate) fn get_histogram(
         &self,
         lstart: usize,
         lend: usize,
@@ -367,7 +483,7 @@ impl Lz77Store {
         }
     }
 
-    pub fn get_byte_range(&self, lstart: usize, lend: usize) -> usize {
+    pub(crate) fn get_byte_range(&self, lstart: usize, lend: usize) -> usize {
         if lstart == lend {
             return 0;
         }
@@ -377,7 +493,7 @@ impl Lz77Store {
     }
 }
 
-pub struct LongestMatch {
+pub(crate) struct LongestMatch {
     pub distance: u16,
     pub length: u16,
     pub from_cache: bool,
@@ -385,7 +501,7 @@ pub struct LongestMatch {
 }
 
 impl LongestMatch {
-    pub fn new(limit: usize) -> Self {
+    pub(crate) fn new(limit: usize) -> Self {
         LongestMatch {
             distance: 0,
             length: 0,
@@ -395,6 +511,221 @@ impl LongestMatch {
     }
 }
 
+/// Computes an optimal LZ77 representation of the bytes of `data` in the
+/// `instart..inend` range, iterating with the cost model of dynamic Huffman
+/// trees as configured by `options`.
+///
+/// # Panics
+///
+/// Panics if `instart..inend` is not a range within `data`.
+pub fn optimal_parse(options: Options, data: &[u8], instart: usize, inend: usize) -> Lz77Store {
+    assert!(
+        instart <= inend && inend <= data.len(),
+        "range out of bounds"
+    );
+    lz77_optimal(
+        &mut ZopfliLongestMatchCache::for_options(inend - instart, &options),
+        data,
+        instart,
+        inend,
+        &options,
+        &ProgressReporter::new(None, 0),
+    )
+    .0
+}
+
+/// Computes the best LZ77 representation of the bytes of `data` in the
+/// `instart..inend` range for the fixed Huffman tree of DEFLATE.
+///
+/// # Panics
+///
+/// Panics if `instart..inend` is not a range within `data`.
+pub fn optimal_fixed_parse(data: &[u8], instart: usize, inend: usize) -> Lz77Store {
+    assert!(
+        instart <= inend && inend <= data.len(),
+        "range out of bounds"
+    );
+    let mut store = Lz77Store::new();
+    lz77_optimal_fixed(
+        &mut ZopfliLongestMatchCache::new(inend - instart),
+        data,
+        instart,
+        inend,
+        &mut store,
+    );
+    store
+}
+
+/// Computes the LZ77 representation of the bytes of `data` in the
+/// `instart..inend` range that is the cheapest according to `cost_model`.
+///
+/// This is a single pass of optimal parsing. [`optimal_parse`] iterates such
+/// passes with a [`StatisticsCostModel`] of the result of the previous one,
+/// and custom models can be refined in the same way. For example, a model fed
+/// by statistics from a training corpus:
+///
+/// ```
+/// # use zopfli::{lz77::{self, StatisticsCostModel}, Options};
+/// let corpus = b"{\"id\": 1, \"name\": \"zopfli\"}, {\"id\": 2, \"name\": \"lz77\"}";
+/// let corpus_store = lz77::optimal_parse(Options::default(), corpus, 0, corpus.len());
+/// let cost_model = StatisticsCostModel::from_store(&corpus_store);
+///
+/// let data = b"{\"id\": 3, \"name\": \"deflate\"}";
+/// let store = lz77::parse_with_cost_model(&cost_model, data, 0, data.len());
+/// ```
+///
+/// # Panics
+///
+/// Panics if `instart..inend` is not a range within `data`.
+pub fn parse_with_cost_model<M: CostModel + ?Sized>(
+    cost_model: &M,
+    data: &[u8],
+    instart: usize,
+    inend: usize,
+) -> Lz77Store {
+    assert!(
+        instart <= inend && inend <= data.len(),
+        "range out of bounds"
+    );
+    let mut store = Lz77Store::new();
+    lz77_cost_model_run(
+        &mut ZopfliLongestMatchCache::new(inend - instart),
+        data,
+        instart,
+        inend,
+        cost_model,
+        &mut store,
+    );
+    store
+}
+
+/// Computes a fast LZ77 representation of the bytes of `data` in the
+/// `instart..inend` range with lazy matching, like gzip does.
+///
+/// # Panics
+///
+/// Panics if `instart..inend` is not a range within `data`.
+pub fn greedy_parse(data: &[u8], instart: usize, inend: usize) -> Lz77Store {
+    assert!(
+        instart <= inend && inend <= data.len(),
+        "range out of bounds"
+    );
+    let mut store = Lz77Store::new();
+    store.greedy(&mut NoCache, data, instart, inend);
+    store
+}
+
+/// Runs the whole pipeline that compresses a master block into dynamic
+/// blocks: the bytes of `data` in the `instart..inend` range are split into
+/// blocks, the LZ77 representation of each block is optimized, and the split
+/// is refined on the result. Returns the LZ77 data of the whole range and the
+/// split points, as [`split_blocks`] does.
+///
+/// # Panics
+///
+/// Panics if `instart..inend` is not a range within `data`.
+pub fn optimal_blocks(
+    options: Options,
+    data: &[u8],
+    instart: usize,
+    inend: usize,
+) -> (Lz77Store, Vec<usize>) {
+    assert!(
+        instart <= inend && inend <= data.len(),
+        "range out of bounds"
+    );
+    let (store, splitpoints, _) = blocksplit_attempt(
+        &options,
+        data,
+        instart,
+        inend,
+        &ProgressReporter::new(None, 0),
+    );
+    (store, splitpoints)
+}
+
+/// Splits the items of a store into at most `max_blocks` blocks (0 for no
+/// limit) where doing so reduces their estimated compressed size. Returns
+/// the indices of the items that start each block but the first.
+pub fn split_blocks(store: &Lz77Store, max_blocks: u16) -> Vec<usize> {
+    let mut splitpoints = Vec::new();
+    blocksplit_lz77(store, max_blocks, &mut splitpoints);
+    splitpoints
+}
+
+/// Returns the size in bits of a block of the specified type holding the
+/// items of a store in the `lstart..lend` range, including its header and
+/// Huffman trees.
+///
+/// # Panics
+///
+/// Panics if `lstart..lend` is not a range within the items of the store.
+pub fn block_size(store: &Lz77Store, lstart: usize, lend: usize, btype: BlockType) -> f64 {
+    assert!(
+        lstart <= lend && lend <= store.size(),
+        "range out of bounds"
+    );
+    calculate_block_size(store, lstart, lend, btype)
+}
+
+/// Like [`block_size`], but returns the size of the block type that is
+/// estimated to be the smallest.
+///
+/// # Panics
+///
+/// Panics if `lstart..lend` is not a range within the items of the store.
+pub fn block_size_auto_type(store: &Lz77Store, lstart: usize, lend: usize) -> f64 {
+    assert!(
+        lstart <= lend && lend <= store.size(),
+        "range out of bounds"
+    );
+    calculate_block_size_auto_type(store, lstart, lend)
+}
+
+/// Writes a complete DEFLATE stream with the items of a store to the
+/// specified sink, returning it. The store is split into blocks at the
+/// specified item indices, and each block uses the type that makes it the
+/// smallest, as configured by `options`.
+///
+/// The store must represent `data`: every item must start at its position in
+/// `data`, right after the previous one, and backreferences must point to
+/// bytes that match. Stored and fixed blocks are built from `data` directly.
+///
+/// # Panics
+///
+/// Panics if the split points are not in ascending order or out of bounds,
+/// or if the positions of the items are not consecutive, do not fit in
+/// `data`, or have backreferences that point before its start.
+pub fn write_blocks<W: Write>(
+    options: Options,
+    store: &Lz77Store,
+    splitpoints: &[usize],
+    data: &[u8],
+    sink: W,
+) -> Result<W, Error> {
+    assert!(
+        splitpoints.windows(2).all(|pair| pair[0] <= pair[1])
+            && splitpoints
+                .last()
+                .map_or(true, |&last| last <= store.size()),
+        "split points must be ascending indices in the store"
+    );
+    let mut next_pos = store.pos.first().copied().unwrap_or(0);
+    assert!(
+        store.litlens.iter().zip(&store.pos).all(|(&litlen, &pos)| {
+            let (length, dist) = match litlen {
+                LitLen::Literal(_) => (1, 0),
+                LitLen::LengthDist(length, dist) => (length as usize, dist as usize),
+            };
+            let in_bounds = pos == next_pos && dist <= pos && pos + length <= data.len();
+            next_pos = pos + length;
+            in_bounds
+        }),
+        "item positions must be consecutive and within the data"
+    );
+    write_lz77_blocks(&options, store, splitpoints, data, sink)
+}
+
 /// Finds how long the match of `scan` and `match` is. Can be used to find how many
 /// bytes starting from `scan`, and from `match`, are equal. Returns the last byte
 /// after `scan`, which is still equal to the corresponding byte after `match`.
@@ -421,7 +752,7 @@ fn get_match(array: &[u8], scan_offset: usize, match_offset: usize, end: usize)
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn find_longest_match<C: Cache>(
+pub(crate) fn find_longest_match<C: Cache>(
     lmc: &mut C,
     h: &ZopfliHash,
     array: &[u8],
@@ -611,3 +942,119 @@ fn verify_len_dist(data: &[u8], pos: usize, dist: u16, length: u16) {
 
 #[cfg(not(debug_assertions))]
 fn verify_len_dist(_data: &[u8], _pos: usize, _dist: u16, _length: u16) {}
+
+#[cfg(test)]
+mod test {
+    use miniz_oxide::inflate;
+
+    use super::*;
+    use crate::deflate_slice;
+
+    #[test]
+    fn pipeline_matches_deflate_slice() {
+        let data = b"The quick brown fox jumps over the lazy dog. ".repeat(50);
+
+        let (store, splitpoints) = optimal_blocks(Options::default(), &data, 0, data.len());
+        assert_eq!(store.byte_range(0, store.len()), data.len());
+
+        assert_eq!(
+            write_blocks(Options::default(), &store, &splitpoints, &data, vec![]).unwrap(),
+            deflate_slice(Options::default(), BlockType::Dynamic, &data, vec![]).unwrap()
+        );
+    }
+
+    #[test]
+    fn hand_built_stores_can_be_written() {
+        let data = b"abcabcabcabcX";
+
+        let mut store = Lz77Store::new();
+        store.push(LitLen::Literal(b'a'.into()), 0);
+        store.push(LitLen::Literal(b'b'.into()), 1);
+        store.push(LitLen::Literal(b'c'.into()), 2);
+        store.push(LitLen::LengthDist(9, 3), 3);
+        store.push(LitLen::Literal(b'X'.into()), 12);
+
+        assert_eq!(store.litlens()[3].ll_symbol(), 263);
+        assert_eq!(store.litlens()[3].dist_symbol(), Some(2));
+        let (ll_counts, d_counts) = store.histogram(0, store.len());
+        assert_eq!(ll_counts[263], 1);
+        assert_eq!(d_counts.iter().sum::<usize>(), 1);
+
+        let compressed_data = write_blocks(Options::default(), &store, &[], data, vec![]).unwrap();
+        assert_eq!(inflate::decompress_to_vec(&compressed_data).unwrap(), data);
+    }
+
+    #[test]
+    fn custom_cost_models_are_minimized() {
+        let mut data = b"0123456789abcdefghij".repeat(10);
+        data.extend((0..2000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8));
+        data.extend(b"0123456789abcdefghij".repeat(10));
+
+        assert_eq!(
+            parse_with_cost_model(&FixedCostModel, &data, 0, data.len()).litlens(),
+            optimal_fixed_parse(&data, 0, data.len()).litlens()
+        );
+
+        // Charge backreferences beyond 1 KiB so much that they are never used,
+        // as a target decoder with a small window would need
+        let near_only = |litlen, dist| match dist {
+            0..=1024 => FixedCostModel.cost(litlen, dist),
+            _ => 1000.0,
+        };
+        let store = parse_with_cost_model(&near_only, &data, 0, data.len());
+        assert!(store
+            .litlens()
+            .iter()
+            .any(|litlen| litlen.dist_symbol().is_some()));
+        assert!(store
+            .litlens()
+            .iter()
+            .all(|litlen| !matches!(litlen, LitLen::LengthDist(_, dist) if *dist > 1024)));
+        let compressed_data = write_blocks(Options::default(), &store, &[], &data, vec![]).unwrap();
+        assert_eq!(inflate::decompress_to_vec(&compressed_data).unwrap(), data);
+    }
+
+    #[test]
+    fn statistics_cost_models_match_store_histograms() {
+        let data = b"The quick brown fox jumps over the lazy dog. ".repeat(20);
+        let store = optimal_parse(Options::default(), &data, 0, data.len());
+        let (ll_counts, d_counts) = store.histogram(0, store.len());
+
+        let from_store = StatisticsCostModel::from_store(&store);
+        let from_histograms = StatisticsCostModel::from_histograms(&ll_counts, &d_counts);
+        for (litlen, dist) in [(b'T'.into(), 0), (b'z'.into(), 0), (45, 45), (258, 900)] {
+            assert_eq!(
+                from_store.cost(litlen, dist),
+                from_histograms.cost(litlen, dist)
+            );
+        }
+        assert!(from_store.cost(b'o'.into(), 0) < from_store.cost(b'#'.into(), 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "backreference lengths and distances must be in range")]
+    fn out_of_range_backreferences_are_rejected() {
+        Lz77Store::new().push(LitLen::LengthDist(2, 1), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "range out of bounds")]
+    fn out_of_bounds_parse_ranges_are_rejected() {
+        greedy_parse(b"abc", 0, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "range out of bounds")]
+    fn out_of_bounds_block_ranges_are_rejected() {
+        optimal_blocks(Options::default(), b"abc", 2, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "item positions must be consecutive and within the data")]
+    fn stores_that_do_not_fit_the_data_are_rejected() {
+        let mut store = Lz77Store::new();
+        store.push(LitLen::Literal(b'a'.into()), 0);
+        store.push(LitLen::LengthDist(3, 1), 1);
+        write_blocks(Options::default(), &store, &[], b"aaa", vec![]).unwrap();
+    }
+}
@@ -1,71 +1,1117 @@
 use std::{
     env,
-    fs::File,
+    ffi::{OsStr, OsString},
+    fs::{self, File, Metadata},
     io::{self, prelude::*},
+    num::NonZeroU64,
+    path::Path,
+    process,
+    time::UNIX_EPOCH,
 };
 
-use log::info;
+use log::{info, LevelFilter, Log, Record};
+
+const USAGE: &str = "\
+Usage: zopfli [OPTION]... [FILE]...
+  or:  zopfli png [OPTION]... INFILE OUTFILE
+  or:  zopfli recompress [OPTION]... FILE...
+  or:  zopfli precompress [OPTION]... DIR...
+Compress each FILE with the Zopfli algorithm. With no FILE, or when FILE is -,
+read standard input and write the result to standard output. The png command
+optimizes PNG images, the recompress command recompresses gzip, zlib and ZIP
+files in place, and the precompress command creates gzip versions of the files
+of a website, as described by their --help. The png and recompress commands,
+and the --verify option, need zopfli to be built with the png, recompress and
+inflate features. A FILE named like a command is compressed when it follows
+--, as in zopfli -- png.
+
+  -c            write the result on standard output, instead of to disk
+  -k, --keep    keep input files, which are never removed, so this has no
+                effect and is accepted for gzip compatibility
+  -n, --no-name do not save the original file name and time stamp in gzip
+                output
+  -N, --name    save the original file name and time stamp in gzip output
+                (default)
+  -v            verbose mode
+  -h, --help    give this help
+  --i#          perform # iterations (default 15). More gives more compression
+                but is slower. Examples: --i10, --i50, --i1000
+  --mb#         maximum amount of blocks to split into (default 15, 0 for
+                unlimited)
+  --splitlast   accepted for compatibility with the C zopfli tool, where it
+                chose the block splitting order; the output does not depend on
+                it here, so use --mb# to control block splitting
+  --seed#       seed for the randomization of iterations (default 0)
+  --restarts#   restart the iterations # times with the next seeds, keeping
+                the best result (default 0)
+  --gzip        output to gzip format (default)
+  --zlib        output to zlib format instead of gzip
+  --deflate     output to deflate format instead of gzip
+  --member-size=N
+                start a new gzip member every N input bytes, so that parts of
+                the output can be decompressed independently
+  --index       also write the offsets of the gzip members to FILE.gz.idx
+  --verify      decompress the output and check that it matches the input
+                before writing it, which holds both in memory
+";
+
+#[cfg(feature = "png")]
+const PNG_USAGE: &str = "\
+Usage: zopfli png [OPTION]... INFILE OUTFILE
+Optimize the compression of the PNG image INFILE with the Zopfli algorithm,
+without changing its pixels, and save it as OUTFILE, which may be INFILE. If
+the result is not smaller, INFILE is saved as is.
+
+  --strip             remove ancillary chunks, such as text and color space
+                      information, except transparency (tRNS)
+  --keepchunks=NAMES  keep the ancillary chunks of these comma-separated types
+                      when stripping, e.g. --keepchunks=iCCP,pHYs
+  --filters=TYPES     filter strategies to try, as a sequence of: 0 to 4 for a
+                      single filter type, m (minimum sum), e (entropy),
+                      p (predefined, as in INFILE) and b (brute force).
+                      Default: 0mepb
+  -v                  verbose mode
+  -h, --help          give this help
+  --i#                perform # iterations (default 15)
+  --mb#               maximum amount of blocks to split into (default 15, 0 for
+                      unlimited)
+  --seed#             seed for the randomization of iterations (default 0)
+  --restarts#         restart the iterations # times with the next seeds,
+                      keeping the best result (default 0)
+";
+
+#[cfg(feature = "recompress")]
+const RECOMPRESS_USAGE: &str = "\
+Usage: zopfli recompress [OPTION]... FILE...
+Recompress the DEFLATE data of each gzip, zlib or ZIP (including JAR and EPUB,
+but not signed APK) FILE with the Zopfli algorithm, in place. Headers, file
+names, time stamps and the order of ZIP entries are kept. Data that does not get
+smaller is kept as is.
+
+  -v            verbose mode
+  -h, --help    give this help
+  --i#          perform # iterations (default 15)
+  --mb#         maximum amount of blocks to split into (default 15, 0 for
+                unlimited)
+  --seed#       seed for the randomization of iterations (default 0)
+  --restarts#   restart the iterations # times with the next seeds, keeping
+                the best result (default 0)
+  --maxsize#    refuse files with data that decompresses to more than # MiB
+                (default 1024)
+";
+
+const PRECOMPRESS_USAGE: &str = "\
+Usage: zopfli precompress [OPTION]... DIR...
+Compress every file in each DIR and its subdirectories that has one of the
+given extensions into a .gz file next to it, with the time stamp of the source
+file, so that web servers can send it to clients that accept gzip. Files whose
+.gz file is already newer are skipped, and .gz files that do not save enough
+space are not written, or removed if they are outdated.
+
+  --ext=EXTS        comma-separated extensions of the files to compress.
+                    Default: html,htm,css,js,mjs,json,xml,svg,txt,map,wasm
+  --min-saved=N     only keep .gz files that are at least N bytes smaller than
+                    the source file, or N percent smaller when N ends with %
+                    (default 1)
+  -v                verbose mode
+  -h, --help        give this help
+  --i#              perform # iterations (default 15)
+  --mb#             maximum amount of blocks to split into (default 15, 0 for
+                    unlimited)
+  --seed#           seed for the randomization of iterations (default 0)
+  --restarts#       restart the iterations # times with the next seeds,
+                    keeping the best result (default 0)
+";
+
+/// The extensions of the files the precompress command compresses by default,
+/// which are the usual text-based formats of websites.
+const DEFAULT_PRECOMPRESS_EXTENSIONS: &[&str] = &[
+    "html", "htm", "css", "js", "mjs", "json", "xml", "svg", "txt", "map", "wasm",
+];
+
+/// Settings parsed from the command line.
+struct Args {
+    options: zopfli::Options,
+    output_type: zopfli::Format,
+    to_stdout: bool,
+    save_name: bool,
+    member_size: Option<NonZeroU64>,
+    write_index: bool,
+    verify: bool,
+    verbose: bool,
+    filenames: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args {
+            options: zopfli::Options::default(),
+            output_type: zopfli::Format::Gzip,
+            to_stdout: false,
+            save_name: true,
+            member_size: None,
+            write_index: false,
+            verify: false,
+            verbose: false,
+            filenames: vec![],
+        };
+        let mut options_ended = false;
+
+        for arg in args {
+            if options_ended || arg == "-" || !arg.starts_with('-') {
+                parsed.filenames.push(arg);
+                continue;
+            }
+
+            match arg.as_str() {
+                "--" => options_ended = true,
+                "-c" => parsed.to_stdout = true,
+                "-k" | "--keep" => {}
+                "-n" | "--no-name" => parsed.save_name = false,
+                "-N" | "--name" => parsed.save_name = true,
+                "-v" => parsed.verbose = true,
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    process::exit(0);
+                }
+                "--gzip" => parsed.output_type = zopfli::Format::Gzip,
+                "--zlib" => parsed.output_type = zopfli::Format::Zlib,
+                "--deflate" => parsed.output_type = zopfli::Format::Deflate,
+                "--splitlast" => {}
+                "--index" => parsed.write_index = true,
+                #[cfg(feature = "inflate")]
+                "--verify" => parsed.verify = true,
+                #[cfg(not(feature = "inflate"))]
+                "--verify" => return Err("--verify requires the inflate feature".into()),
+                _ => {
+                    if let Some(member_size) = arg.strip_prefix("--member-size=") {
+                        parsed.member_size = Some(
+                            member_size
+                                .parse()
+                                .map_err(|_| format!("invalid member size: {}", member_size))?,
+                        );
+                    } else {
+                        parse_options_arg(&mut parsed.options, &arg)?;
+                    }
+                }
+            }
+        }
+
+        if parsed.filenames.is_empty() {
+            parsed.filenames.push("-".into());
+        }
+        if (parsed.member_size.is_some() || parsed.write_index)
+            && !matches!(parsed.output_type, zopfli::Format::Gzip)
+        {
+            return Err("gzip members are only supported for gzip output".into());
+        }
+        if parsed.write_index
+            && (parsed.to_stdout || parsed.filenames.iter().any(|filename| filename == "-"))
+        {
+            return Err("the index can't be written when writing to standard output".into());
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Parses the command line arguments that set Zopfli options, which are
+/// shared by every command.
+fn parse_options_arg(options: &mut zopfli::Options, arg: &str) -> Result<(), String> {
+    if let Some(iterations) = arg.strip_prefix("--i") {
+        options.iteration_count = iterations
+            .parse()
+            .ok()
+            .and_then(NonZeroU64::new)
+            .ok_or_else(|| format!("invalid iteration count: {}", iterations))?;
+    } else if let Some(max_blocks) = arg.strip_prefix("--mb") {
+        options.maximum_block_splits = max_blocks
+            .parse()
+            .map_err(|_| format!("invalid maximum block splits: {}", max_blocks))?;
+    } else if let Some(seed) = arg.strip_prefix("--seed") {
+        options.seed = seed
+            .parse()
+            .map_err(|_| format!("invalid seed: {}", seed))?;
+    } else if let Some(restarts) = arg.strip_prefix("--restarts") {
+        options.restarts = restarts
+            .parse()
+            .map_err(|_| format!("invalid restart count: {}", restarts))?;
+    } else {
+        return Err(format!("unrecognized option: {}", arg));
+    }
+
+    Ok(())
+}
+
+/// Settings for the png command parsed from the command line.
+#[cfg(feature = "png")]
+struct PngArgs {
+    options: zopfli::Options,
+    png_options: zopfli::png::PngOptions,
+    verbose: bool,
+    in_filename: String,
+    out_filename: String,
+}
+
+#[cfg(feature = "png")]
+impl PngArgs {
+    fn parse(args: impl Iterator<Item = String>) -> Result<PngArgs, String> {
+        let mut options = zopfli::Options::default();
+        let mut png_options = zopfli::png::PngOptions::default();
+        let mut verbose = false;
+        let mut filenames = vec![];
+
+        for arg in args {
+            if !arg.starts_with('-') {
+                filenames.push(arg);
+                continue;
+            }
+
+            match arg.as_str() {
+                "--strip" => png_options.strip_ancillary_chunks = true,
+                "-v" => verbose = true,
+                "-h" | "--help" => {
+                    print!("{}", PNG_USAGE);
+                    process::exit(0);
+                }
+                _ => {
+                    if let Some(chunk_types) = arg.strip_prefix("--keepchunks=") {
+                        for chunk_type in chunk_types.split(',') {
+                            png_options.keep_chunks.push(
+                                chunk_type
+                                    .as_bytes()
+                                    .try_into()
+                                    .map_err(|_| format!("invalid chunk type: {}", chunk_type))?,
+                            );
+                        }
+                    } else if let Some(strategies) = arg.strip_prefix("--filters=") {
+                        png_options.filter_strategies = strategies
+                            .chars()
+                            .map(parse_filter_strategy)
+                            .collect::<Result<_, _>>()?;
+                    } else {
+                        parse_options_arg(&mut options, &arg)?;
+                    }
+                }
+            }
+        }
+
+        match <[String; 2]>::try_from(filenames) {
+            Ok([in_filename, out_filename]) => Ok(PngArgs {
+                options,
+                png_options,
+                verbose,
+                in_filename,
+                out_filename,
+            }),
+            Err(_) => Err("expected an input and an output file name".into()),
+        }
+    }
+}
+
+/// Settings for the recompress command parsed from the command line.
+#[cfg(feature = "recompress")]
+struct RecompressArgs {
+    options: zopfli::Options,
+    verbose: bool,
+    /// The maximum decompressed size of each DEFLATE payload, in bytes.
+    max_payload_size: usize,
+    filenames: Vec<String>,
+}
+
+#[cfg(feature = "recompress")]
+impl RecompressArgs {
+    fn parse(args: impl Iterator<Item = String>) -> Result<RecompressArgs, String> {
+        let mut parsed = RecompressArgs {
+            options: zopfli::Options::default(),
+            verbose: false,
+            max_payload_size: 1024 << 20,
+            filenames: vec![],
+        };
+
+        for arg in args {
+            if !arg.starts_with('-') {
+                parsed.filenames.push(arg);
+                continue;
+            }
+
+            if let Some(max_size) = arg.strip_prefix("--maxsize") {
+                parsed.max_payload_size = max_size
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|mib| mib.checked_mul(1 << 20))
+                    .ok_or_else(|| format!("invalid maximum size: {}", max_size))?;
+                continue;
+            }
+
+            match arg.as_str() {
+                "-v" => parsed.verbose = true,
+                "-h" | "--help" => {
+                    print!("{}", RECOMPRESS_USAGE);
+                    process::exit(0);
+                }
+                _ => parse_options_arg(&mut parsed.options, &arg)?,
+            }
+        }
+
+        if parsed.filenames.is_empty() {
+            return Err("expected at least one file name".into());
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Settings for the precompress command parsed from the command line.
+struct PrecompressArgs {
+    options: zopfli::Options,
+    extensions: Vec<String>,
+    min_savings: MinSavings,
+    verbose: bool,
+    directories: Vec<String>,
+}
+
+/// How much smaller than its source a precompressed file must be to be kept.
+#[derive(Clone, Copy)]
+enum MinSavings {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl MinSavings {
+    fn parse(min_savings: &str) -> Result<MinSavings, String> {
+        let invalid = || format!("invalid minimum savings: {}", min_savings);
+        match min_savings.strip_suffix('%') {
+            Some(percent) => percent
+                .parse()
+                .ok()
+                .filter(|percent: &f64| (0.0..=100.0).contains(percent))
+                .map(MinSavings::Percent)
+                .ok_or_else(invalid),
+            None => min_savings
+                .parse()
+                .map(MinSavings::Bytes)
+                .map_err(|_| invalid()),
+        }
+    }
+
+    fn are_met(self, original_size: u64, compressed_size: u64) -> bool {
+        let saved = original_size.saturating_sub(compressed_size);
+        match self {
+            MinSavings::Bytes(bytes) => saved >= bytes,
+            MinSavings::Percent(percent) => saved as f64 >= original_size as f64 * percent / 100.0,
+        }
+    }
+}
+
+impl PrecompressArgs {
+    fn parse(args: impl Iterator<Item = String>) -> Result<PrecompressArgs, String> {
+        let mut parsed = PrecompressArgs {
+            options: zopfli::Options::default(),
+            extensions: DEFAULT_PRECOMPRESS_EXTENSIONS
+                .iter()
+                .map(|&extension| extension.into())
+                .collect(),
+            min_savings: MinSavings::Bytes(1),
+            verbose: false,
+            directories: vec![],
+        };
+
+        for arg in args {
+            if !arg.starts_with('-') {
+                parsed.directories.push(arg);
+                continue;
+            }
+
+            match arg.as_str() {
+                "-v" => parsed.verbose = true,
+                "-h" | "--help" => {
+                    print!("{}", PRECOMPRESS_USAGE);
+                    process::exit(0);
+                }
+                _ => {
+                    if let Some(extensions) = arg.strip_prefix("--ext=") {
+                        parsed.extensions = extensions
+                            .split(',')
+                            .filter(|extension| !extension.is_empty())
+                            .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                            .collect();
+                    } else if let Some(min_savings) = arg.strip_prefix("--min-saved=") {
+                        parsed.min_savings = MinSavings::parse(min_savings)?;
+                    } else {
+                        parse_options_arg(&mut parsed.options, &arg)?;
+                    }
+                }
+            }
+        }
+
+        if parsed.directories.is_empty() {
+            return Err("expected at least one directory".into());
+        }
+
+        Ok(parsed)
+    }
+}
+
+#[cfg(feature = "png")]
+fn parse_filter_strategy(strategy: char) -> Result<zopfli::png::FilterStrategy, String> {
+    use zopfli::png::{FilterStrategy, FilterType};
+
+    Ok(match strategy {
+        '0' => FilterStrategy::Fixed(FilterType::None),
+        '1' => FilterStrategy::Fixed(FilterType::Sub),
+        '2' => FilterStrategy::Fixed(FilterType::Up),
+        '3' => FilterStrategy::Fixed(FilterType::Average),
+        '4' => FilterStrategy::Fixed(FilterType::Paeth),
+        'm' => FilterStrategy::MinSum,
+        'e' => FilterStrategy::Entropy,
+        'p' => FilterStrategy::Predefined,
+        'b' => FilterStrategy::BruteForce,
+        _ => return Err(format!("unknown filter strategy: {}", strategy)),
+    })
+}
 
 fn main() {
-    let options = zopfli::Options::default();
-    let output_type = zopfli::Format::Gzip;
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        #[cfg(feature = "png")]
+        Some("png") => {
+            args.next();
+            png_main(args);
+            return;
+        }
+        #[cfg(feature = "recompress")]
+        Some("recompress") => {
+            args.next();
+            recompress_main(args);
+            return;
+        }
+        #[cfg(not(feature = "png"))]
+        Some("png") => unavailable_command("png"),
+        #[cfg(not(feature = "recompress"))]
+        Some("recompress") => unavailable_command("recompress"),
+        Some("precompress") => {
+            args.next();
+            precompress_main(args);
+            return;
+        }
+        _ => {}
+    }
+
+    let args = Args::parse(args).unwrap_or_else(|why| {
+        eprintln!("zopfli: {}", why);
+        eprint!("{}", USAGE);
+        process::exit(1);
+    });
+
+    if args.verbose {
+        init_logger();
+    }
+
+    let mut failed_files = 0;
+    for filename in &args.filenames {
+        if let Err(why) = compress_file(&args, filename) {
+            eprintln!("zopfli: {}", why);
+            failed_files += 1;
+        }
+    }
+
+    if failed_files > 0 {
+        eprintln!(
+            "zopfli: {} of {} files could not be compressed",
+            failed_files,
+            args.filenames.len()
+        );
+        process::exit(1);
+    }
+}
+
+/// Exits with an error for a command that this build of Zopfli does not
+/// include, because the namesake feature was not enabled.
+#[cfg(not(all(feature = "png", feature = "recompress")))]
+fn unavailable_command(command: &str) -> ! {
+    eprintln!(
+        "zopfli: the {} command is not available, as zopfli was built without the {} feature",
+        command, command
+    );
+    process::exit(1);
+}
+
+#[cfg(feature = "png")]
+fn png_main(args: impl Iterator<Item = String>) {
+    let args = PngArgs::parse(args).unwrap_or_else(|why| {
+        eprintln!("zopfli: {}", why);
+        eprint!("{}", PNG_USAGE);
+        process::exit(1);
+    });
+
+    if args.verbose {
+        init_logger();
+    }
+
+    if let Err(why) = optimize_png(&args) {
+        eprintln!("zopfli: {}", why);
+        process::exit(1);
+    }
+}
+
+/// Optimizes a PNG file according to the command line settings. Returns a
+/// human-readable description of the error on failure.
+#[cfg(feature = "png")]
+fn optimize_png(args: &PngArgs) -> Result<(), String> {
+    let png = fs::read(&args.in_filename)
+        .map_err(|why| format!("couldn't read {}: {}", args.in_filename, why))?;
+    let optimized_png = zopfli::png::optimize(args.options, &args.png_options, &png)
+        .map_err(|why| format!("couldn't optimize {}: {}", args.in_filename, why))?;
+
+    info!(
+        "Original Size: {}, Optimized: {}",
+        png.len(),
+        optimized_png.len()
+    );
+    let result = if optimized_png.len() < png.len() {
+        optimized_png
+    } else {
+        info!("Result is not smaller, keeping the original image");
+        png
+    };
+
+    replace_file(Path::new(&args.out_filename), &result)
+        .map_err(|why| format!("couldn't write {}: {}", args.out_filename, why))
+}
+
+#[cfg(feature = "recompress")]
+fn recompress_main(args: impl Iterator<Item = String>) {
+    let args = RecompressArgs::parse(args).unwrap_or_else(|why| {
+        eprintln!("zopfli: {}", why);
+        eprint!("{}", RECOMPRESS_USAGE);
+        process::exit(1);
+    });
+
+    if args.verbose {
+        init_logger();
+    }
+
+    let mut failed_files = 0;
+    for filename in &args.filenames {
+        if let Err(why) = recompress_file(&args, filename) {
+            eprintln!("zopfli: {}", why);
+            failed_files += 1;
+        }
+    }
+
+    if failed_files > 0 {
+        eprintln!(
+            "zopfli: {} of {} files could not be recompressed",
+            failed_files,
+            args.filenames.len()
+        );
+        process::exit(1);
+    }
+}
+
+/// Recompresses the file at the given path in place, according to the
+/// command line settings. Returns a human-readable description of the error
+/// on failure.
+#[cfg(feature = "recompress")]
+fn recompress_file(args: &RecompressArgs, filename: &str) -> Result<(), String> {
+    let data = fs::read(filename).map_err(|why| format!("couldn't read {}: {}", filename, why))?;
+    let recompressed_data =
+        zopfli::recompress::recompress(args.options, &data, args.max_payload_size)
+            .map_err(|why| format!("couldn't recompress {}: {}", filename, why))?;
+
+    info!(
+        "{}: Original Size: {}, Recompressed: {}",
+        filename,
+        data.len(),
+        recompressed_data.len()
+    );
+    if recompressed_data.len() >= data.len() {
+        info!("Result is not smaller, keeping the original file");
+        return Ok(());
+    }
+
+    replace_file(Path::new(filename), &recompressed_data)
+        .map_err(|why| format!("couldn't write {}: {}", filename, why))
+}
+
+/// Writes data to a file, replacing it if it exists, in a way that never
+/// leaves a partially written file behind: the data is written and synced to
+/// a temporary file in the same directory, which is then renamed over the
+/// file. Replaced files keep their permissions.
+fn replace_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.zopfli-tmp", process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path).and_then(|mut temp_file| {
+        temp_file.write_all(data)?;
+        if let Ok(metadata) = fs::metadata(path) {
+            temp_file.set_permissions(metadata.permissions())?;
+        }
+        temp_file.sync_all()?;
+        fs::rename(&temp_path, path)
+    });
+    if result.is_err() {
+        fs::remove_file(&temp_path).ok();
+    }
+    result
+}
+
+fn precompress_main(args: impl Iterator<Item = String>) {
+    let args = PrecompressArgs::parse(args).unwrap_or_else(|why| {
+        eprintln!("zopfli: {}", why);
+        eprint!("{}", PRECOMPRESS_USAGE);
+        process::exit(1);
+    });
+
+    if args.verbose {
+        init_logger();
+    }
+
+    let mut summary = PrecompressSummary::default();
+    for directory in &args.directories {
+        precompress_directory(&args, Path::new(directory), &mut summary);
+    }
+
+    info!(
+        "Compressed: {}, Up to date: {}, Not worth it: {}",
+        summary.compressed, summary.up_to_date, summary.not_worth_it
+    );
+    if summary.failed > 0 {
+        eprintln!(
+            "zopfli: {} files could not be precompressed",
+            summary.failed
+        );
+        process::exit(1);
+    }
+}
+
+/// Counts the outcomes of precompressing the files of a directory tree.
+#[derive(Default)]
+struct PrecompressSummary {
+    compressed: usize,
+    up_to_date: usize,
+    not_worth_it: usize,
+    failed: usize,
+}
+
+/// What happened to a file the precompress command looked at.
+enum PrecompressOutcome {
+    Compressed,
+    UpToDate,
+    NotWorthIt,
+}
+
+/// Precompresses the matching files of a directory and its subdirectories,
+/// reporting errors as they happen. Symbolic links to directories are not
+/// followed, so that link cycles cannot make the walk endless.
+fn precompress_directory(
+    args: &PrecompressArgs,
+    directory: &Path,
+    summary: &mut PrecompressSummary,
+) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(why) => {
+            eprintln!(
+                "zopfli: couldn't read directory {}: {}",
+                directory.display(),
+                why
+            );
+            summary.failed += 1;
+            return;
+        }
+    };
+
+    let mut paths = vec![];
+    for entry in entries {
+        match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+            Ok((path, file_type)) if file_type.is_dir() => paths.push((path, true)),
+            Ok((path, _)) if has_extension(&path, &args.extensions) => paths.push((path, false)),
+            Ok(_) => {}
+            Err(why) => {
+                eprintln!(
+                    "zopfli: couldn't read directory {}: {}",
+                    directory.display(),
+                    why
+                );
+                summary.failed += 1;
+            }
+        }
+    }
+    // Process files in a predictable order, which makes the output easier to follow
+    paths.sort();
+
+    for (path, is_dir) in paths {
+        if is_dir {
+            precompress_directory(args, &path, summary);
+            continue;
+        }
+
+        match precompress_file(args, &path) {
+            Ok(PrecompressOutcome::Compressed) => summary.compressed += 1,
+            Ok(PrecompressOutcome::UpToDate) => summary.up_to_date += 1,
+            Ok(PrecompressOutcome::NotWorthIt) => summary.not_worth_it += 1,
+            Err(why) => {
+                eprintln!("zopfli: {}", why);
+                summary.failed += 1;
+            }
+        }
+    }
+}
+
+/// Checks whether the extension of the file at the given path is one of the
+/// given lowercase extensions, ignoring case.
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| {
+            extensions
+                .iter()
+                .any(|candidate| candidate.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Compresses the file at the given path into a `.gz` file next to it, unless
+/// that file is up to date or compression does not save enough space. Returns
+/// a human-readable description of the error on failure.
+fn precompress_file(args: &PrecompressArgs, path: &Path) -> Result<PrecompressOutcome, String> {
+    let mut out_path = path.as_os_str().to_owned();
+    out_path.push(".gz");
+    let out_path = Path::new(&out_path);
+
+    let metadata = fs::metadata(path)
+        .map_err(|why| format!("couldn't read metadata of {}: {}", path.display(), why))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a regular file", path.display()));
+    }
+    let modified = metadata.modified().ok();
+
+    if let (Some(modified), Ok(out_modified)) = (
+        modified,
+        fs::metadata(out_path).and_then(|metadata| metadata.modified()),
+    ) {
+        if out_modified > modified {
+            info!("{}: up to date", out_path.display());
+            return Ok(PrecompressOutcome::UpToDate);
+        }
+    }
+
+    let data =
+        fs::read(path).map_err(|why| format!("couldn't read {}: {}", path.display(), why))?;
+
+    // Time stamps that do not fit in the MTIME field are not stored
+    let mtime = modified
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .and_then(|mtime| u32::try_from(mtime.as_secs()).ok())
+        .unwrap_or(0);
+    let header = zopfli::GzipHeader::new().mtime(mtime);
+
+    let (compressed_data, stats) = zopfli::GzipEncoder::new_with_header(
+        args.options,
+        zopfli::BlockType::Dynamic,
+        &header,
+        vec![],
+    )
+    .and_then(|mut encoder| {
+        encoder.write_all(&data)?;
+        encoder.finish_with_stats()
+    })
+    .map_err(|why| format!("couldn't compress {}: {}", path.display(), why))?;
 
-    // TODO: CLI arguments
-    // TODO: Allow specifying output to STDOUT
+    info!(
+        "{}: Original Size: {}, Compressed: {}",
+        path.display(),
+        stats.input_size,
+        stats.output_size
+    );
+    if !args
+        .min_savings
+        .are_met(stats.input_size, stats.output_size)
+    {
+        info!(
+            "{}: not enough space saved, not keeping it",
+            out_path.display()
+        );
+        // An outdated compressed file would be served instead of the new contents
+        return match fs::remove_file(out_path) {
+            Err(why) if why.kind() != io::ErrorKind::NotFound => Err(format!(
+                "couldn't remove outdated {}: {}",
+                out_path.display(),
+                why
+            )),
+            _ => Ok(PrecompressOutcome::NotWorthIt),
+        };
+    }
 
-    let extension = match output_type {
+    replace_file(out_path, &compressed_data)
+        .map_err(|why| format!("couldn't write {}: {}", out_path.display(), why))?;
+    Ok(PrecompressOutcome::Compressed)
+}
+
+/// Compresses the file at the given path, or the standard input when it is `-`,
+/// according to the command line settings. Returns a human-readable description
+/// of the error on failure.
+fn compress_file(args: &Args, filename: &str) -> Result<(), String> {
+    let extension = match args.output_type {
         zopfli::Format::Gzip => ".gz",
         zopfli::Format::Zlib => ".zlib",
         zopfli::Format::Deflate => ".deflate",
     };
 
-    for filename in env::args().skip(1) {
-        let file = File::open(&filename)
-            .unwrap_or_else(|why| panic!("couldn't open {}: {}", filename, why));
-        let filesize = file.metadata().map(|x| x.len()).unwrap() as usize;
+    let (mut file, metadata): (Box<dyn Read>, Option<Metadata>) = if filename == "-" {
+        (Box::new(io::stdin().lock()), None)
+    } else {
+        let file =
+            File::open(filename).map_err(|why| format!("couldn't open {}: {}", filename, why))?;
+        let metadata = file
+            .metadata()
+            .map_err(|why| format!("couldn't read metadata of {}: {}", filename, why))?;
+        if metadata.is_dir() {
+            return Err(format!("{} is a directory", filename));
+        }
+        (Box::new(file), Some(metadata))
+    };
 
-        let out_filename = format!("{}{}", filename, extension);
+    let out_filename = if args.to_stdout || filename == "-" {
+        None
+    } else {
+        Some(format!("{}{}", filename, extension))
+    };
 
-        // Attempt to create the output file, panic if the output file could not be opened
-        let out_file = File::create(&out_filename)
-            .unwrap_or_else(|why| panic!("couldn't create output file {}: {}", out_filename, why));
-        let mut out_file = WriteStatistics::new(out_file);
+    let mut out_file: Box<dyn Write> =
+        match &out_filename {
+            None => Box::new(io::stdout().lock()),
+            Some(out_filename) => {
+                info!("Saving to: {}", out_filename);
+                Box::new(File::create(out_filename).map_err(|why| {
+                    format!("couldn't create output file {}: {}", out_filename, why)
+                })?)
+            }
+        };
 
-        zopfli::compress(options, output_type, &file, &mut out_file).unwrap_or_else(|why| {
-            panic!("couldn't write to output file {}: {}", out_filename, why)
-        });
+    // Verified output is compressed to memory first, from an input that is
+    // kept in memory to compare it with
+    let mut input = vec![];
+    let mut source: Box<dyn Read + '_> = if args.verify {
+        file.read_to_end(&mut input)
+            .map_err(|why| format!("couldn't read {}: {}", filename, why))?;
+        Box::new(&input[..])
+    } else {
+        file
+    };
+    let mut compressed_data = vec![];
+    let sink: &mut dyn Write = if args.verify {
+        &mut compressed_data
+    } else {
+        &mut out_file
+    };
+
+    let mut index = None;
+    let result = match args.output_type {
+        zopfli::Format::Gzip => {
+            let header = gzip_header(args, filename, metadata.as_ref());
+            zopfli::GzipEncoder::new_buffered_with_header(
+                args.options,
+                zopfli::BlockType::Dynamic,
+                &header,
+                sink,
+            )
+            .and_then(|mut encoder| {
+                encoder.get_mut().set_member_size(args.member_size);
+                io::copy(&mut source, &mut encoder)?;
+                let encoder = encoder.into_inner()?;
+                index = Some(encoder.index().clone());
+                encoder.finish_with_stats().map(|(_, stats)| stats)
+            })
+        }
+        output_type => zopfli::compress(args.options, output_type, source, sink),
+    }
+    .and_then(|stats| {
+        if args.verify {
+            #[cfg(feature = "inflate")]
+            verify_output(args.output_type, &compressed_data, &input)?;
+            out_file.write_all(&compressed_data)?;
+        }
+        out_file.flush().map(|()| stats)
+    });
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(why) => {
+            // Do not leave truncated output files behind
+            if let Some(out_filename) = &out_filename {
+                drop(out_file);
+                fs::remove_file(out_filename).ok();
+            }
+            return Err(format!("couldn't compress {}: {}", filename, why));
+        }
+    };
+
+    if let (true, Some(out_filename), Some(index)) = (args.write_index, &out_filename, index) {
+        let index_filename = format!("{}.idx", out_filename);
+        info!("Saving index to: {}", index_filename);
+        fs::write(&index_filename, index.to_bytes())
+            .map_err(|why| format!("couldn't write {}: {}", index_filename, why))?;
+    }
 
-        let out_size = out_file.count;
+    if let Some(metadata) = metadata {
+        let filesize = metadata.len();
+        let out_size = stats.output_size;
+        let removed_percentage = if filesize == 0 {
+            0.0
+        } else {
+            100.0 * (filesize as f64 - out_size as f64) / filesize as f64
+        };
         info!(
             "Original Size: {}, Compressed: {}, Compression: {}% Removed",
-            filesize,
-            out_size,
-            100.0 * (filesize - out_size) as f64 / filesize as f64
+            filesize, out_size, removed_percentage
         );
     }
+
+    Ok(())
+}
+
+/// Checks that compressed data in the given format decompresses to the input.
+#[cfg(feature = "inflate")]
+fn verify_output(format: zopfli::Format, compressed_data: &[u8], input: &[u8]) -> io::Result<()> {
+    let decompressed_data = match format {
+        zopfli::Format::Gzip => zopfli::inflate::gzip_decompress(compressed_data),
+        zopfli::Format::Zlib => zopfli::inflate::zlib_decompress(compressed_data),
+        zopfli::Format::Deflate => zopfli::inflate::inflate(compressed_data),
+    }?;
+    if decompressed_data != input {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "compressed data does not decompress to the input data",
+        ));
+    }
+    Ok(())
 }
 
-struct WriteStatistics<W> {
-    inner: W,
-    count: usize,
+/// Builds the gzip header for the output of the given input file. Like `gzip`,
+/// it stores the name and modification time of the file, unless disabled. The
+/// standard input has no name.
+fn gzip_header(args: &Args, filename: &str, metadata: Option<&Metadata>) -> zopfli::GzipHeader {
+    let header = zopfli::GzipHeader::new();
+    if !args.save_name {
+        return header;
+    }
+
+    let name = Path::new(filename)
+        .file_name()
+        .filter(|_| filename != "-")
+        .and_then(gzip_file_name);
+    let header = match name {
+        Some(name) => header.filename(name),
+        None => header,
+    };
+
+    // Time stamps that do not fit in the MTIME field are not stored
+    let mtime = metadata
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .and_then(|mtime| u32::try_from(mtime.as_secs()).ok())
+        .unwrap_or(0);
+
+    header.mtime(mtime)
 }
 
-impl<W> WriteStatistics<W> {
-    fn new(inner: W) -> Self {
-        WriteStatistics { inner, count: 0 }
+/// Returns the bytes to store as the FNAME of a file name. Like `gzip`, names
+/// are stored as they are on Unix. Elsewhere, names that cannot be encoded in
+/// ISO 8859-1 are not stored.
+fn gzip_file_name(name: &OsStr) -> Option<Vec<u8>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(name.as_bytes().to_vec())
+    }
+    #[cfg(not(unix))]
+    {
+        name.to_str()?
+            .chars()
+            .map(|c| u8::try_from(c).ok())
+            .collect()
     }
 }
 
-impl<W: Write> Write for WriteStatistics<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let res = self.inner.write(buf);
-        if let Ok(size) = res {
-            self.count += size;
+fn init_logger() {
+    log::set_logger(&StderrLogger).ok();
+    log::set_max_level(LevelFilter::Info);
+}
+
+/// A minimal logger that prints the messages logged by Zopfli to the standard
+/// error stream, which is used to implement the verbose mode.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
         }
-        res
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accepts_compatibility_options_without_effect() {
+        let args = Args::parse(
+            ["-k", "--keep", "--splitlast", "file"]
+                .into_iter()
+                .map(String::from),
+        )
+        .unwrap();
+
+        assert_eq!(args.filenames, ["file"]);
+        assert_eq!(args.options, zopfli::Options::default());
+    }
+
+    #[cfg(feature = "inflate")]
+    #[test]
+    fn verify_compresses_files_that_decompress_to_the_input() {
+        let dir = env::temp_dir().join(format!("zopfli-verify-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("input.txt");
+        let data = b"Verify the output of the command line tool. ".repeat(100);
+        fs::write(&path, &data).unwrap();
+        let filename = path.to_str().unwrap();
+
+        let args = Args::parse(["--verify", filename].into_iter().map(String::from)).unwrap();
+        assert!(args.verify);
+        let result = compress_file(&args, filename);
+        let compressed_data = fs::read(format!("{}.gz", filename));
+        fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(
+            zopfli::inflate::gzip_decompress(&compressed_data.unwrap()).unwrap(),
+            data
+        );
+    }
+
+    #[cfg(feature = "inflate")]
+    #[test]
+    fn verify_rejects_output_that_does_not_decompress_to_the_input() {
+        let compressed_data = zopfli::compress_to_vec(
+            zopfli::Options::default(),
+            zopfli::Format::Deflate,
+            b"input",
+        );
+
+        assert!(verify_output(zopfli::Format::Deflate, &compressed_data, b"input").is_ok());
+        assert!(verify_output(zopfli::Format::Deflate, &compressed_data, b"other").is_err());
     }
 }
@@ -0,0 +1,866 @@
+//! Lossless optimization of PNG images, in the spirit of the `zopflipng` tool
+//! that is distributed with the reference Zopfli implementation.
+//!
+//! [`optimize`] decodes the image data of a PNG file, filters its scanlines
+//! with several strategies, compresses each result with Zopfli and keeps the
+//! smallest one. The pixels, the image header and the palette are kept as-is,
+//! so the optimized image looks exactly like the original one.
+
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    fmt::{Display, Formatter},
+};
+
+#[cfg(not(feature = "std"))]
+use crate::math::F64MathExt;
+use crate::{
+    cache::NoCache,
+    deflate::calculate_block_size_auto_type,
+    inflate::{zlib_decompress_limited, InflateError},
+    lz77::Lz77Store,
+    BlockType, Options, Write, ZlibEncoder,
+};
+
+/// The bytes every PNG file starts with.
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+/// Maximum length of the data of a chunk.
+const MAX_CHUNK_LENGTH: usize = (1 << 31) - 1;
+
+const IHDR: [u8; 4] = *b"IHDR";
+const IDAT: [u8; 4] = *b"IDAT";
+const IEND: [u8; 4] = *b"IEND";
+const TRNS: [u8; 4] = *b"tRNS";
+
+/// The starting column, starting row, column increment and row increment of
+/// each pass of the Adam7 interlace method.
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// A filter type that transforms the bytes of a scanline before compression,
+/// as defined in the PNG specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FilterType {
+    /// The scanline is not transformed.
+    None,
+    /// Each byte is stored as the difference from the corresponding byte of
+    /// the pixel to its left.
+    Sub,
+    /// Each byte is stored as the difference from the byte above it.
+    Up,
+    /// Each byte is stored as the difference from the average of the
+    /// corresponding bytes of the pixels to its left and above it.
+    Average,
+    /// Each byte is stored as the difference from the corresponding byte of
+    /// the pixel to its left, above it or above and to its left that is
+    /// closest to a linear prediction.
+    Paeth,
+}
+
+impl FilterType {
+    const ALL: [FilterType; 5] = [
+        FilterType::None,
+        FilterType::Sub,
+        FilterType::Up,
+        FilterType::Average,
+        FilterType::Paeth,
+    ];
+
+    fn from_byte(byte: u8) -> Option<FilterType> {
+        Self::ALL.get(byte as usize).copied()
+    }
+}
+
+/// A way to choose the filter type of each scanline of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FilterStrategy {
+    /// Use the same filter type for every scanline.
+    Fixed(FilterType),
+    /// Use the filter type that minimizes the sum of the absolute values of
+    /// the filtered bytes, interpreted as signed, for each scanline. This is
+    /// the heuristic recommended by the PNG specification.
+    MinSum,
+    /// Use the filter type that minimizes the Shannon entropy of the
+    /// filtered bytes for each scanline.
+    Entropy,
+    /// Use the same filter types as the input image.
+    Predefined,
+    /// Use the filter type that compresses each scanline best, as estimated
+    /// by a quick LZ77 pass. This is the slowest strategy to choose filters
+    /// with, but its cost is still small compared to Zopfli compression.
+    BruteForce,
+}
+
+/// Settings for optimizing PNG images with [`optimize`], in addition to the
+/// Zopfli [`Options`](crate::Options).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PngOptions {
+    /// The filter strategies to try. Every strategy requires compressing the
+    /// image with Zopfli, so each of them multiplies the time spent.
+    ///
+    /// Default value: [`Fixed(FilterType::None)`](FilterStrategy::Fixed),
+    /// [`MinSum`](FilterStrategy::MinSum), [`Entropy`](FilterStrategy::Entropy),
+    /// [`Predefined`](FilterStrategy::Predefined) and
+    /// [`BruteForce`](FilterStrategy::BruteForce), which are the strategies
+    /// `zopflipng` tries by default.
+    pub filter_strategies: Vec<FilterStrategy>,
+    /// Whether to remove ancillary chunks, which store metadata that
+    /// decoders may ignore, such as text or color space information. The
+    /// `tRNS` chunk, which defines transparency, is always kept.
+    ///
+    /// Default value: false.
+    pub strip_ancillary_chunks: bool,
+    /// The types of ancillary chunks to keep when stripping them, such as
+    /// `*b"iCCP"`.
+    ///
+    /// Default value: empty.
+    pub keep_chunks: Vec<[u8; 4]>,
+}
+
+impl Default for PngOptions {
+    fn default() -> PngOptions {
+        PngOptions {
+            filter_strategies: vec![
+                FilterStrategy::Fixed(FilterType::None),
+                FilterStrategy::MinSum,
+                FilterStrategy::Entropy,
+                FilterStrategy::Predefined,
+                FilterStrategy::BruteForce,
+            ],
+            strip_ancillary_chunks: false,
+            keep_chunks: Vec::new(),
+        }
+    }
+}
+
+/// The errors that may happen while optimizing a PNG image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PngError {
+    /// The data does not start with the PNG signature.
+    InvalidSignature,
+    /// The data ended before the `IEND` chunk.
+    UnexpectedEof,
+    /// The CRC of a chunk does not match its contents.
+    ChecksumMismatch {
+        /// The type of the corrupt chunk.
+        chunk_type: [u8; 4],
+    },
+    /// The `IHDR` chunk is missing, is not the first chunk, or describes an
+    /// image that is not valid or uses unknown methods.
+    InvalidHeader,
+    /// There are no `IDAT` chunks.
+    MissingImageData,
+    /// A scanline uses an unknown filter type.
+    InvalidFilterType(u8),
+    /// The size of the decompressed image data does not match the image
+    /// dimensions.
+    ImageDataSizeMismatch,
+    /// The image data could not be decompressed.
+    Inflate(InflateError),
+}
+
+impl Display for PngError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSignature => f.write_str("not a PNG file"),
+            Self::UnexpectedEof => f.write_str("unexpected end of PNG file"),
+            Self::ChecksumMismatch { chunk_type } => write!(
+                f,
+                "CRC mismatch in {} chunk",
+                core::str::from_utf8(chunk_type).unwrap_or("an invalid")
+            ),
+            Self::InvalidHeader => f.write_str("invalid or unsupported PNG header"),
+            Self::MissingImageData => f.write_str("no image data"),
+            Self::InvalidFilterType(filter_type) => {
+                write!(f, "invalid scanline filter type: {}", filter_type)
+            }
+            Self::ImageDataSizeMismatch => {
+                f.write_str("image data size does not match the image dimensions")
+            }
+            Self::Inflate(error) => write!(f, "invalid image data: {}", error),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PngError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Inflate(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(not(feature = "std"), feature = "nightly"))]
+impl core::error::Error for PngError {}
+
+impl From<InflateError> for PngError {
+    fn from(error: InflateError) -> Self {
+        Self::Inflate(error)
+    }
+}
+
+/// Optimizes the compression of a PNG image, returning the new PNG file.
+///
+/// The image data is recompressed with every filter strategy in `png_options`,
+/// keeping the smallest result, which may still be bigger than the input. The
+/// other chunks are copied in the same order, unless stripped.
+pub fn optimize(
+    options: Options,
+    png_options: &PngOptions,
+    png: &[u8],
+) -> Result<Vec<u8>, PngError> {
+    let chunks = parse_chunks(png)?;
+    let header = match chunks.first() {
+        Some(chunk) if chunk.chunk_type == IHDR => ImageHeader::parse(chunk.data)?,
+        _ => return Err(PngError::InvalidHeader),
+    };
+
+    let image_data: Vec<u8> = chunks
+        .iter()
+        .filter(|chunk| chunk.chunk_type == IDAT)
+        .flat_map(|chunk| chunk.data)
+        .copied()
+        .collect();
+    if image_data.is_empty() {
+        return Err(PngError::MissingImageData);
+    }
+    // The image data may not decompress to more than its dimensions allow,
+    // so that a small image can not exhaust memory
+    let filter_data = match zlib_decompress_limited(&image_data, header.filtered_size()) {
+        Err(InflateError::OutputTooLarge) => return Err(PngError::ImageDataSizeMismatch),
+        result => result?,
+    };
+    let image = Image::unfilter(&header, &filter_data)?;
+
+    let mut tried_data: Vec<Vec<u8>> = Vec::new();
+    let mut best_idat: Option<Vec<u8>> = None;
+    for &strategy in &png_options.filter_strategies {
+        let filtered_data = image.filter(strategy);
+        // Different strategies often choose the same filters
+        if tried_data.contains(&filtered_data) {
+            continue;
+        }
+
+        let idat = compress(options, &filtered_data);
+        if best_idat
+            .as_ref()
+            .map_or(true, |best| idat.len() < best.len())
+        {
+            best_idat = Some(idat);
+        }
+        tried_data.push(filtered_data);
+    }
+    // Without strategies, the image is recompressed as it is
+    let best_idat = best_idat.unwrap_or_else(|| compress(options, &image.filter_data));
+
+    let mut optimized_png = SIGNATURE.to_vec();
+    let mut wrote_idat = false;
+    for chunk in &chunks {
+        if chunk.chunk_type == IDAT {
+            if !wrote_idat {
+                for idat_part in best_idat.chunks(MAX_CHUNK_LENGTH) {
+                    write_chunk(&mut optimized_png, IDAT, idat_part);
+                }
+                wrote_idat = true;
+            }
+        } else if chunk.is_critical()
+            || !png_options.strip_ancillary_chunks
+            || chunk.chunk_type == TRNS
+            || png_options.keep_chunks.contains(&chunk.chunk_type)
+        {
+            write_chunk(&mut optimized_png, chunk.chunk_type, chunk.data);
+        }
+    }
+
+    Ok(optimized_png)
+}
+
+/// Compresses filtered image data to the zlib stream stored in `IDAT` chunks.
+fn compress(options: Options, filtered_data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(options, BlockType::Dynamic, Vec::new())
+        .expect("Writing to a Vec should not fail");
+    encoder
+        .write_all(filtered_data)
+        .expect("Writing to a Vec should not fail");
+    encoder.finish().expect("Writing to a Vec should not fail")
+}
+
+struct Chunk<'a> {
+    chunk_type: [u8; 4],
+    data: &'a [u8],
+}
+
+impl Chunk<'_> {
+    /// Critical chunks are needed to display the image. Their type starts
+    /// with an uppercase letter.
+    fn is_critical(&self) -> bool {
+        self.chunk_type[0] & 0x20 == 0
+    }
+}
+
+/// Splits a PNG file into its chunks, up to and including `IEND`, checking
+/// their CRC.
+fn parse_chunks(png: &[u8]) -> Result<Vec<Chunk<'_>>, PngError> {
+    let mut rest = png
+        .strip_prefix(&SIGNATURE)
+        .ok_or(PngError::InvalidSignature)?;
+    let mut chunks = Vec::new();
+
+    loop {
+        let length = rest.get(..4).ok_or(PngError::UnexpectedEof)?;
+        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+        if length > MAX_CHUNK_LENGTH || rest.len() < length + 12 {
+            return Err(PngError::UnexpectedEof);
+        }
+
+        let chunk_type: [u8; 4] = rest[4..8].try_into().unwrap();
+        let data = &rest[8..8 + length];
+        let crc = u32::from_be_bytes(rest[8 + length..12 + length].try_into().unwrap());
+        if crc != crc32fast::hash(&rest[4..8 + length]) {
+            return Err(PngError::ChecksumMismatch { chunk_type });
+        }
+
+        chunks.push(Chunk { chunk_type, data });
+        rest = &rest[12 + length..];
+
+        if chunk_type == IEND {
+            return Ok(chunks);
+        }
+    }
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: [u8; 4], data: &[u8]) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&chunk_type);
+    hasher.update(data);
+
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(&chunk_type);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+/// The fields of the `IHDR` chunk that determine the layout of the image data.
+struct ImageHeader {
+    width: usize,
+    height: usize,
+    bits_per_pixel: usize,
+    interlaced: bool,
+}
+
+impl ImageHeader {
+    fn parse(data: &[u8]) -> Result<ImageHeader, PngError> {
+        let data: &[u8; 13] = data.try_into().map_err(|_| PngError::InvalidHeader)?;
+        let width = u32::from_be_bytes(data[..4].try_into().unwrap());
+        let height = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        let [bit_depth, color_type, compression_method, filter_method, interlace_method] =
+            [data[8], data[9], data[10], data[11], data[12]];
+
+        let channels = match (color_type, bit_depth) {
+            (0, 1 | 2 | 4 | 8 | 16) => 1,
+            (2, 8 | 16) => 3,
+            (3, 1 | 2 | 4 | 8) => 1,
+            (4, 8 | 16) => 2,
+            (6, 8 | 16) => 4,
+            _ => return Err(PngError::InvalidHeader),
+        };
+        if width == 0
+            || height == 0
+            || width as usize > MAX_CHUNK_LENGTH
+            || height as usize > MAX_CHUNK_LENGTH
+            || compression_method != 0
+            || filter_method != 0
+            || interlace_method > 1
+        {
+            return Err(PngError::InvalidHeader);
+        }
+
+        // The scanlines of reduced images are not wider than the image, so
+        // their sizes fit in a usize if this one does, even on 32-bit targets
+        let bits_per_pixel = channels * bit_depth as usize;
+        (width as usize)
+            .checked_mul(bits_per_pixel)
+            .and_then(|bits| bits.checked_add(7))
+            .ok_or(PngError::InvalidHeader)?;
+
+        Ok(ImageHeader {
+            width: width as usize,
+            height: height as usize,
+            bits_per_pixel,
+            interlaced: interlace_method == 1,
+        })
+    }
+
+    /// Returns the size in bytes of the scanlines and the amount of
+    /// scanlines of each reduced image that makes up the image data, omitting
+    /// empty ones. Non-interlaced images have a single reduced image.
+    fn reduced_images(&self) -> Vec<(usize, usize)> {
+        let passes: &[_] = if self.interlaced {
+            &ADAM7_PASSES
+        } else {
+            &[(0, 0, 1, 1)]
+        };
+
+        passes
+            .iter()
+            .filter(|&&(x0, y0, _, _)| x0 < self.width && y0 < self.height)
+            .map(|&(x0, y0, dx, dy)| {
+                let width = (self.width - x0 + dx - 1) / dx;
+                let height = (self.height - y0 + dy - 1) / dy;
+                ((width * self.bits_per_pixel + 7) / 8, height)
+            })
+            .collect()
+    }
+
+    /// Returns the size in bytes of the filtered image data, with the filter
+    /// type byte of each scanline. Saturates if it does not fit in a `usize`.
+    fn filtered_size(&self) -> usize {
+        self.reduced_images()
+            .iter()
+            .map(|&(line_size, lines)| (line_size + 1).saturating_mul(lines))
+            .fold(0, usize::saturating_add)
+    }
+}
+
+/// The unfiltered scanlines of an image.
+struct Image {
+    /// The size and amount of scanlines of each reduced image.
+    reduced_images: Vec<(usize, usize)>,
+    /// The amount of bytes per complete pixel, rounded up to one. Filters
+    /// compare each byte with the corresponding byte of the pixel to its left.
+    filter_distance: usize,
+    /// The scanlines, without filter type bytes.
+    scanlines: Vec<u8>,
+    /// The scanlines as they were filtered in the input image.
+    filter_data: Vec<u8>,
+}
+
+impl Image {
+    fn unfilter(header: &ImageHeader, filter_data: &[u8]) -> Result<Image, PngError> {
+        let reduced_images = header.reduced_images();
+        let filter_distance = (header.bits_per_pixel + 7) / 8;
+
+        if filter_data.len() != header.filtered_size() {
+            return Err(PngError::ImageDataSizeMismatch);
+        }
+
+        let mut scanlines = Vec::with_capacity(filter_data.len());
+        let mut filtered_lines = filter_data;
+        for &(line_size, lines) in &reduced_images {
+            let mut previous_line_start = None;
+            for _ in 0..lines {
+                let filter_type = FilterType::from_byte(filtered_lines[0])
+                    .ok_or(PngError::InvalidFilterType(filtered_lines[0]))?;
+                let line_start = scanlines.len();
+                scanlines.extend_from_slice(&filtered_lines[1..line_size + 1]);
+                filtered_lines = &filtered_lines[line_size + 1..];
+
+                let (previous_lines, line) = scanlines.split_at_mut(line_start);
+                let previous_line = previous_line_start.map(|start| &previous_lines[start..]);
+                unfilter_line(filter_type, filter_distance, line, previous_line);
+                previous_line_start = Some(line_start);
+            }
+        }
+
+        Ok(Image {
+            reduced_images,
+            filter_distance,
+            scanlines,
+            filter_data: filter_data.to_vec(),
+        })
+    }
+
+    /// Returns the image data filtered with the specified strategy, ready to
+    /// be compressed.
+    fn filter(&self, strategy: FilterStrategy) -> Vec<u8> {
+        let mut filter_data = Vec::with_capacity(self.filter_data.len());
+        let mut candidates: [Vec<u8>; 5] = Default::default();
+
+        let mut scanlines = &self.scanlines[..];
+        let mut input_filter_types = self.filter_data.iter();
+        for &(line_size, lines) in &self.reduced_images {
+            let mut previous_line: Option<&[u8]> = None;
+            let mut previous_filtered_line_start = None;
+
+            for _ in 0..lines {
+                let line = &scanlines[..line_size];
+                scanlines = &scanlines[line_size..];
+                let input_filter_type = *input_filter_types.next().unwrap();
+                input_filter_types.nth(line_size - 1);
+
+                let mut filter_with = |filter_type: FilterType| {
+                    let filtered_line = &mut candidates[filter_type as usize];
+                    filtered_line.clear();
+                    filter_line(
+                        filter_type,
+                        self.filter_distance,
+                        line,
+                        previous_line,
+                        filtered_line,
+                    );
+                };
+
+                let filter_type = match strategy {
+                    FilterStrategy::Fixed(filter_type) => filter_type,
+                    FilterStrategy::Predefined => FilterType::from_byte(input_filter_type).unwrap(),
+                    FilterStrategy::MinSum => {
+                        FilterType::ALL.iter().for_each(|&t| filter_with(t));
+                        best_filter_type(&candidates, |line| {
+                            line.iter()
+                                .map(|&b| (b as i8).unsigned_abs() as u64)
+                                .sum::<u64>() as f64
+                        })
+                    }
+                    FilterStrategy::Entropy => {
+                        FilterType::ALL.iter().for_each(|&t| filter_with(t));
+                        best_filter_type(&candidates, entropy)
+                    }
+                    FilterStrategy::BruteForce => {
+                        FilterType::ALL.iter().for_each(|&t| filter_with(t));
+                        let previous_filtered_line = previous_filtered_line_start
+                            .map_or(&[][..], |start| &filter_data[start..]);
+                        best_filter_type(&candidates, |line| {
+                            estimated_compressed_size(previous_filtered_line, line)
+                        })
+                    }
+                };
+
+                let filtered_line_start = filter_data.len() + 1;
+                filter_data.push(filter_type as u8);
+                filter_line(
+                    filter_type,
+                    self.filter_distance,
+                    line,
+                    previous_line,
+                    &mut filter_data,
+                );
+
+                previous_line = Some(line);
+                previous_filtered_line_start = Some(filtered_line_start);
+            }
+        }
+
+        filter_data
+    }
+}
+
+/// Returns the filter type whose filtered scanline has the lowest score.
+fn best_filter_type(candidates: &[Vec<u8>; 5], score: impl Fn(&[u8]) -> f64) -> FilterType {
+    let mut best = (FilterType::None, f64::INFINITY);
+    for (&filter_type, filtered_line) in FilterType::ALL.iter().zip(candidates) {
+        let line_score = score(filtered_line);
+        if line_score < best.1 {
+            best = (filter_type, line_score);
+        }
+    }
+    best.0
+}
+
+/// Returns the Shannon entropy of the bytes, in nats, multiplied by their
+/// count.
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in bytes {
+        counts[byte as usize] += 1;
+    }
+
+    let total = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let count = count as f64;
+            -count * (count / total).ln()
+        })
+        .sum()
+}
+
+/// Estimates the compressed size of a filtered scanline, in bits, using the
+/// previous one as dictionary.
+fn estimated_compressed_size(previous_filtered_line: &[u8], filtered_line: &[u8]) -> f64 {
+    let mut data = Vec::with_capacity(previous_filtered_line.len() + filtered_line.len());
+    data.extend_from_slice(previous_filtered_line);
+    data.extend_from_slice(filtered_line);
+
+    let mut store = Lz77Store::new();
+    store.greedy(
+        &mut NoCache,
+        &data,
+        previous_filtered_line.len(),
+        data.len(),
+    );
+    calculate_block_size_auto_type(&store, 0, store.size())
+}
+
+/// Returns the predictor of the Paeth filter for a byte, given the
+/// corresponding bytes to its left (`a`), above (`b`) and above left (`c`).
+fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Returns the value the byte at index `i` of a scanline is predicted from,
+/// given the unfiltered scanline and the one above it, if any.
+#[inline]
+fn predictor(
+    filter_type: FilterType,
+    filter_distance: usize,
+    line: &[u8],
+    previous_line: Option<&[u8]>,
+    i: usize,
+) -> u8 {
+    let left = |line: &[u8]| {
+        i.checked_sub(filter_distance)
+            .map_or(0, |left_index| line[left_index])
+    };
+    let a = left(line);
+    let b = previous_line.map_or(0, |previous_line| previous_line[i]);
+    let c = previous_line.map_or(0, left);
+
+    match filter_type {
+        FilterType::None => 0,
+        FilterType::Sub => a,
+        FilterType::Up => b,
+        FilterType::Average => ((a as u16 + b as u16) / 2) as u8,
+        FilterType::Paeth => paeth_predictor(a, b, c),
+    }
+}
+
+/// Filters a scanline, appending the result to `filtered_line`.
+fn filter_line(
+    filter_type: FilterType,
+    filter_distance: usize,
+    line: &[u8],
+    previous_line: Option<&[u8]>,
+    filtered_line: &mut Vec<u8>,
+) {
+    filtered_line.extend(line.iter().enumerate().map(|(i, &byte)| {
+        byte.wrapping_sub(predictor(
+            filter_type,
+            filter_distance,
+            line,
+            previous_line,
+            i,
+        ))
+    }));
+}
+
+/// Reverses the filter applied to a scanline in place.
+fn unfilter_line(
+    filter_type: FilterType,
+    filter_distance: usize,
+    line: &mut [u8],
+    previous_line: Option<&[u8]>,
+) {
+    for i in 0..line.len() {
+        line[i] = line[i].wrapping_add(predictor(
+            filter_type,
+            filter_distance,
+            line,
+            previous_line,
+            i,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inflate::zlib_decompress;
+
+    /// Builds a PNG file with the given header fields, extra chunks before the
+    /// image data and pseudorandom, smooth image data that uses every filter
+    /// type.
+    fn test_png(
+        size: (u32, u32),
+        color_type: u8,
+        bit_depth: u8,
+        interlaced: bool,
+        extra_chunks: &[([u8; 4], &[u8])],
+    ) -> Vec<u8> {
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&size.0.to_be_bytes());
+        ihdr.extend_from_slice(&size.1.to_be_bytes());
+        ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, interlaced as u8]);
+        let header = ImageHeader::parse(&ihdr).unwrap();
+
+        let mut filter_data = Vec::new();
+        let mut states = crate::test_util::lcg(7);
+        for (line_size, lines) in header.reduced_images() {
+            for line in 0..lines {
+                filter_data.push((line % 5) as u8);
+                filter_data.extend(
+                    states
+                        .by_ref()
+                        .take(line_size)
+                        .map(|state| (state >> 29) as u8),
+                );
+            }
+        }
+
+        let mut png = SIGNATURE.to_vec();
+        write_chunk(&mut png, IHDR, &ihdr);
+        for &(chunk_type, data) in extra_chunks {
+            write_chunk(&mut png, chunk_type, data);
+        }
+        write_chunk(&mut png, IDAT, &compress(Options::default(), &filter_data));
+        write_chunk(&mut png, IEND, &[]);
+        png
+    }
+
+    fn decode_scanlines(png: &[u8]) -> Vec<u8> {
+        let chunks = parse_chunks(png).unwrap();
+        let header = ImageHeader::parse(chunks[0].data).unwrap();
+        let image_data: Vec<u8> = chunks
+            .iter()
+            .filter(|chunk| chunk.chunk_type == IDAT)
+            .flat_map(|chunk| chunk.data)
+            .copied()
+            .collect();
+
+        Image::unfilter(&header, &zlib_decompress(&image_data).unwrap())
+            .unwrap()
+            .scanlines
+    }
+
+    #[test]
+    fn optimizing_preserves_pixels() {
+        for (color_type, bit_depth, interlaced) in [
+            (0, 1, true),
+            (0, 16, false),
+            (2, 8, true),
+            (3, 4, false),
+            (4, 8, true),
+            (6, 16, false),
+        ] {
+            let png = test_png((13, 11), color_type, bit_depth, interlaced, &[]);
+            let optimized_png = optimize(Options::default(), &PngOptions::default(), &png).unwrap();
+
+            assert_eq!(decode_scanlines(&png), decode_scanlines(&optimized_png));
+        }
+    }
+
+    #[test]
+    fn every_filter_type_round_trips() {
+        let png = test_png((9, 7), 2, 8, false, &[]);
+        let header = ImageHeader::parse(parse_chunks(&png).unwrap()[0].data).unwrap();
+        let scanlines = decode_scanlines(&png);
+
+        for filter_type in FilterType::ALL {
+            let strategies = PngOptions {
+                filter_strategies: vec![FilterStrategy::Fixed(filter_type)],
+                ..PngOptions::default()
+            };
+            let optimized_png = optimize(Options::default(), &strategies, &png).unwrap();
+            let chunks = parse_chunks(&optimized_png).unwrap();
+            let filter_data = zlib_decompress(chunks[1].data).unwrap();
+
+            assert!(filter_data
+                .chunks(header.reduced_images()[0].0 + 1)
+                .all(|line| line[0] == filter_type as u8));
+            assert_eq!(scanlines, decode_scanlines(&optimized_png));
+        }
+    }
+
+    #[test]
+    fn strips_ancillary_chunks_except_kept_ones() {
+        let png = test_png(
+            (4, 4),
+            0,
+            8,
+            false,
+            &[
+                (*b"tEXt", b"Comment\0Hello"),
+                (*b"gAMA", &[0, 0, 177, 143]),
+                (TRNS, &[0, 0]),
+            ],
+        );
+        let png_options = PngOptions {
+            strip_ancillary_chunks: true,
+            keep_chunks: vec![*b"gAMA"],
+            ..PngOptions::default()
+        };
+
+        let optimized_png = optimize(Options::default(), &png_options, &png).unwrap();
+        let chunk_types: Vec<_> = parse_chunks(&optimized_png)
+            .unwrap()
+            .iter()
+            .map(|chunk| chunk.chunk_type)
+            .collect();
+
+        assert_eq!(chunk_types, [IHDR, *b"gAMA", TRNS, IDAT, IEND]);
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let mut png = test_png((4, 4), 0, 8, false, &[]);
+        assert_eq!(
+            optimize(Options::default(), &PngOptions::default(), &png[1..]),
+            Err(PngError::InvalidSignature)
+        );
+        assert_eq!(
+            optimize(
+                Options::default(),
+                &PngOptions::default(),
+                &png[..png.len() - 1]
+            ),
+            Err(PngError::UnexpectedEof)
+        );
+
+        png[20] ^= 1;
+        assert_eq!(
+            optimize(Options::default(), &PngOptions::default(), &png),
+            Err(PngError::ChecksumMismatch { chunk_type: IHDR })
+        );
+    }
+
+    #[test]
+    fn rejects_scanlines_too_big_for_usize() {
+        // The widest image with the most bits per pixel, 2^31 - 1 pixels of 64
+        // bits, has scanlines of about 2^34 bytes
+        let ihdr = [0x7f, 0xff, 0xff, 0xff, 0, 0, 0, 1, 16, 6, 0, 0, 0];
+        let header = ImageHeader::parse(&ihdr);
+
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(header.err(), Some(PngError::InvalidHeader));
+        #[cfg(not(target_pointer_width = "32"))]
+        assert_eq!(
+            header.ok().map(|header| header.reduced_images()),
+            Some(vec![(0x7fff_ffff * 8, 1)])
+        );
+    }
+
+    #[test]
+    fn rejects_image_data_bigger_than_the_image() {
+        let mut png = SIGNATURE.to_vec();
+        write_chunk(&mut png, IHDR, &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]);
+        write_chunk(&mut png, IDAT, &compress(Options::default(), &[0; 1000]));
+        write_chunk(&mut png, IEND, &[]);
+
+        assert_eq!(
+            optimize(Options::default(), &PngOptions::default(), &png),
+            Err(PngError::ImageDataSizeMismatch)
+        );
+    }
+}
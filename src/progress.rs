@@ -0,0 +1,255 @@
+use core::ops::ControlFlow;
+
+use crate::Error;
+
+/// A callback that is notified of the progress of a compression operation,
+/// and may cancel it. Set it on an encoder with
+/// [`DeflateEncoder::set_progress`](crate::DeflateEncoder::set_progress) or the
+/// namesake methods of the other encoders.
+///
+/// Returning [`ControlFlow::Break`] stops the compression as soon as possible,
+/// making the pending write or finish operation fail with an error of the
+/// [`ErrorKind::Other`](crate::ErrorKind::Other) kind. The encoder can't be
+/// used afterwards, as the data written to its sink is incomplete. The
+/// [`ErrorKind::Interrupted`](crate::ErrorKind::Interrupted) kind is not used
+/// because it asks callers to retry the operation.
+///
+/// This trait is implemented for closures that take a [`ProgressUpdate`] and
+/// return a [`ControlFlow`].
+pub trait Progress {
+    /// Called when a step of the compression process is finished.
+    ///
+    /// When compressing on several threads, this method may be called from
+    /// any of them, but never concurrently.
+    fn update(&mut self, update: ProgressUpdate) -> ControlFlow<()>;
+}
+
+impl<F: FnMut(ProgressUpdate) -> ControlFlow<()>> Progress for F {
+    fn update(&mut self, update: ProgressUpdate) -> ControlFlow<()> {
+        self(update)
+    }
+}
+
+/// The step of the compression process that a [`ProgressUpdate`] refers to,
+/// from the most to the least frequent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ProgressStage {
+    /// An iteration of the LZ77 optimization of a block split segment,
+    /// which is repeated up to [`Options::iteration_count`](crate::Options::iteration_count)
+    /// times per segment, finished.
+    SqueezeIteration,
+    /// The LZ77 optimization of a block split segment finished.
+    BlockSplitSegment,
+    /// All the blocks for a master block, which is a chunk of
+    /// [`Options::master_block_size`](crate::Options::master_block_size)
+    /// written bytes, were written to the sink.
+    MasterBlock,
+}
+
+/// A report of the progress of a compression operation, passed to
+/// [`Progress::update`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct ProgressUpdate {
+    /// The step of the compression process that just finished.
+    pub stage: ProgressStage,
+    /// The amount of input bytes that have been completely optimized so
+    /// far. Segments may be optimized out of order when compressing on
+    /// several threads, but this value never decreases.
+    pub bytes_processed: u64,
+    /// The best compressed size found for the data the step worked on, in
+    /// bits: the block split segment for the
+    /// [`SqueezeIteration`](ProgressStage::SqueezeIteration) and
+    /// [`BlockSplitSegment`](ProgressStage::BlockSplitSegment) stages, and
+    /// the master block for the [`MasterBlock`](ProgressStage::MasterBlock)
+    /// stage.
+    pub best_cost: f64,
+}
+
+#[cfg(feature = "parallel")]
+type Lock<T> = std::sync::Mutex<T>;
+#[cfg(not(feature = "parallel"))]
+type Lock<T> = core::cell::RefCell<T>;
+
+struct ProgressState<'a> {
+    progress: &'a mut (dyn Progress + Send),
+    bytes_processed: u64,
+    cancelled: bool,
+}
+
+/// Forwards progress updates to an optional [`Progress`] callback, keeping
+/// track of the processed bytes and whether it asked to cancel. This is
+/// shared by every function that reports progress, so it can be used from
+/// several threads when the `parallel` feature is enabled.
+pub(crate) struct ProgressReporter<'a> {
+    state: Option<Lock<ProgressState<'a>>>,
+}
+
+impl<'a> ProgressReporter<'a> {
+    /// Creates a reporter that forwards updates to `progress`, if any, and
+    /// counts the processed bytes starting at `bytes_processed`.
+    pub(crate) fn new(
+        progress: Option<&'a mut (dyn Progress + Send)>,
+        bytes_processed: u64,
+    ) -> Self {
+        ProgressReporter {
+            state: progress.map(|progress| {
+                Lock::new(ProgressState {
+                    progress,
+                    bytes_processed,
+                    cancelled: false,
+                })
+            }),
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut ProgressState<'a>) -> T) -> Option<T> {
+        self.state.as_ref().map(|state| {
+            #[cfg(feature = "parallel")]
+            let mut state = state
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            #[cfg(not(feature = "parallel"))]
+            let mut state = state.borrow_mut();
+
+            f(&mut state)
+        })
+    }
+
+    /// Adds `bytes` to the processed bytes and notifies the callback about
+    /// the end of a step, unless the compression was already cancelled.
+    /// Breaks if the compression is cancelled.
+    pub(crate) fn report(
+        &self,
+        stage: ProgressStage,
+        bytes: u64,
+        best_cost: f64,
+    ) -> ControlFlow<()> {
+        self.with_state(|state| {
+            if state.cancelled {
+                return ControlFlow::Break(());
+            }
+
+            state.bytes_processed += bytes;
+            let flow = state.progress.update(ProgressUpdate {
+                stage,
+                bytes_processed: state.bytes_processed,
+                best_cost,
+            });
+            state.cancelled = flow.is_break();
+            flow
+        })
+        .unwrap_or(ControlFlow::Continue(()))
+    }
+
+    /// Returns whether the callback asked to cancel the compression.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.with_state(|state| state.cancelled).unwrap_or(false)
+    }
+
+    /// Consumes the reporter, returning the amount of processed bytes and
+    /// whether the compression was cancelled, or `None` if there is no
+    /// callback.
+    pub(crate) fn into_totals(self) -> Option<(u64, bool)> {
+        self.state.map(|state| {
+            #[cfg(feature = "parallel")]
+            let state = state
+                .into_inner()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            #[cfg(not(feature = "parallel"))]
+            let state = state.into_inner();
+
+            (state.bytes_processed, state.cancelled)
+        })
+    }
+}
+
+/// Returns the error that operations cancelled by a [`Progress`] callback
+/// fail with.
+pub(crate) fn cancelled_error() -> Error {
+    #[cfg(all(not(doc), feature = "std"))]
+    {
+        Error::new(std::io::ErrorKind::Other, "compression cancelled")
+    }
+    #[cfg(any(doc, not(feature = "std")))]
+    {
+        crate::io::ErrorKind::Other.into()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use std::{
+        io::ErrorKind,
+        num::NonZeroU64,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{BlockType, DeflateEncoder, Options, Write};
+
+    fn compress_with_progress(
+        data: &[u8],
+        progress: impl Progress + Send + 'static,
+    ) -> Result<Vec<u8>, Error> {
+        let options = Options {
+            iteration_count: NonZeroU64::new(3).unwrap(),
+            master_block_size: 50_000,
+            ..Options::default()
+        };
+
+        let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, Vec::new());
+        encoder.set_progress(progress);
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    #[test]
+    fn reports_every_stage_in_order() {
+        let data = crate::test_util::block_splittable_data(120_000);
+        let updates = Arc::new(Mutex::new(Vec::new()));
+
+        let progress_updates = Arc::clone(&updates);
+        compress_with_progress(&data, move |update| {
+            progress_updates.lock().unwrap().push(update);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+
+        let updates = updates.lock().unwrap();
+        let count = |stage| {
+            updates
+                .iter()
+                .filter(|update| update.stage == stage)
+                .count()
+        };
+        assert_eq!(count(ProgressStage::MasterBlock), 3);
+        assert!(count(ProgressStage::BlockSplitSegment) >= 3);
+        assert!(count(ProgressStage::SqueezeIteration) > count(ProgressStage::BlockSplitSegment));
+
+        assert!(updates
+            .windows(2)
+            .all(|pair| pair[0].bytes_processed <= pair[1].bytes_processed));
+        assert_eq!(updates.last().unwrap().bytes_processed, data.len() as u64);
+        assert!(updates.iter().all(|update| update.best_cost > 0.0));
+    }
+
+    #[test]
+    fn cancellation_fails_with_other_error() {
+        let data = crate::test_util::block_splittable_data(120_000);
+        let mut remaining_updates = 5;
+
+        let error = compress_with_progress(&data, move |_| {
+            remaining_updates -= 1;
+            if remaining_updates == 0 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::Other);
+    }
+}
@@ -0,0 +1,417 @@
+//! Encoders that wrap a [`Read`] source and compress its data on demand, so
+//! that the compressed data can itself be read, e.g., with
+//! [`std::io::copy`].
+//!
+//! The source is read in chunks of a [master block](Options::master_block_size),
+//! as the blocking encoders created with
+//! [`DeflateEncoder::new_buffered`](crate::DeflateEncoder::new_buffered) do, so
+//! reading the first compressed bytes may take a while.
+//!
+//! ```
+//! # use std::io::Read;
+//! # use zopfli::{read::GzipEncoder, BlockType, Options};
+//! # fn main() -> std::io::Result<()> {
+//! let mut encoder = GzipEncoder::new(Options::default(), BlockType::Dynamic, &b"Hello, Zopfli!"[..]);
+//! let mut compressed_data = Vec::new();
+//! encoder.read_to_end(&mut compressed_data)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{self, Read};
+
+use crate::{BlockType, Options, Write};
+
+/// A blocking encoder whose sink is a `Vec`, which is drained as the
+/// compressed data is read.
+trait VecEncoder: Write {
+    fn output(&mut self) -> &mut Vec<u8>;
+
+    fn finish_output(self) -> io::Result<Vec<u8>>;
+}
+
+impl VecEncoder for crate::DeflateEncoder<Vec<u8>> {
+    fn output(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish_output(self) -> io::Result<Vec<u8>> {
+        self.finish()
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl VecEncoder for crate::GzipEncoder<Vec<u8>> {
+    fn output(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish_output(self) -> io::Result<Vec<u8>> {
+        self.finish()
+    }
+}
+
+#[cfg(feature = "zlib")]
+impl VecEncoder for crate::ZlibEncoder<Vec<u8>> {
+    fn output(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish_output(self) -> io::Result<Vec<u8>> {
+        self.finish()
+    }
+}
+
+/// The state shared by the encoders of this module.
+struct EncoderReader<R, E> {
+    source: R,
+    /// The encoder, until the source is exhausted and the stream finished.
+    encoder: Option<E>,
+    /// Data read from the source that was not compressed yet.
+    input: Vec<u8>,
+    /// The amount of data read from the source at once, which is a master
+    /// block of it.
+    chunk_size: usize,
+    /// Compressed data that was not read yet, from `output_position` on.
+    output: Vec<u8>,
+    output_position: usize,
+}
+
+impl<R: Read, E: VecEncoder> EncoderReader<R, E> {
+    fn new(options: &Options, source: R, encoder: E) -> Self {
+        Self {
+            source,
+            encoder: Some(encoder),
+            input: Vec::new(),
+            chunk_size: match options.master_block_size {
+                0 => usize::MAX,
+                size => size,
+            },
+            output: Vec::new(),
+            output_position: 0,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_position == self.output.len() {
+            if self.encoder.is_none() {
+                return Ok(0);
+            }
+
+            // Any data read before an error stays in the input buffer, so
+            // that reading can be retried
+            let remaining = self.chunk_size - self.input.len();
+            (&mut self.source)
+                .take(remaining as u64)
+                .read_to_end(&mut self.input)?;
+            let source_exhausted = self.input.len() < self.chunk_size;
+
+            let encoder = self.encoder.as_mut().unwrap();
+            encoder.write_all(&self.input)?;
+            self.input.clear();
+
+            self.output.clear();
+            self.output_position = 0;
+            if source_exhausted {
+                self.output = self.encoder.take().unwrap().finish_output()?;
+            } else {
+                core::mem::swap(&mut self.output, encoder.output());
+            }
+        }
+
+        let available = &self.output[self.output_position..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.output_position += read;
+
+        Ok(read)
+    }
+}
+
+/// A DEFLATE encoder powered by the Zopfli algorithm that compresses the
+/// data read from a source. See [`crate::DeflateEncoder`].
+pub struct DeflateEncoder<R: Read> {
+    inner: EncoderReader<R, crate::DeflateEncoder<Vec<u8>>>,
+}
+
+impl<R: Read> DeflateEncoder<R> {
+    /// Creates a new Zopfli DEFLATE encoder that will operate according to
+    /// the specified options.
+    pub fn new(options: Options, btype: BlockType, source: R) -> Self {
+        Self {
+            inner: EncoderReader::new(
+                &options,
+                source,
+                crate::DeflateEncoder::new(options, btype, Vec::new()),
+            ),
+        }
+    }
+
+    /// Creates a new Zopfli DEFLATE encoder that will operate according to
+    /// the specified options, using the specified preset dictionary, as
+    /// described in [`crate::DeflateEncoder::new_with_dictionary`].
+    pub fn new_with_dictionary(
+        options: Options,
+        btype: BlockType,
+        dictionary: &[u8],
+        source: R,
+    ) -> Self {
+        Self {
+            inner: EncoderReader::new(
+                &options,
+                source,
+                crate::DeflateEncoder::new_with_dictionary(options, btype, dictionary, Vec::new()),
+            ),
+        }
+    }
+
+    /// Gets a reference to the underlying source.
+    pub fn get_ref(&self) -> &R {
+        &self.inner.source
+    }
+
+    /// Gets a mutable reference to the underlying source. Reading from it
+    /// directly means that data will not be compressed.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner.source
+    }
+
+    /// Consumes the encoder, returning the underlying source.
+    pub fn into_inner(self) -> R {
+        self.inner.source
+    }
+}
+
+impl<R: Read> Read for DeflateEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// A gzip encoder powered by the Zopfli algorithm that compresses the data
+/// read from a source. See [`crate::GzipEncoder`].
+#[cfg(feature = "gzip")]
+pub struct GzipEncoder<R: Read> {
+    inner: EncoderReader<R, crate::GzipEncoder<Vec<u8>>>,
+}
+
+#[cfg(feature = "gzip")]
+impl<R: Read> GzipEncoder<R> {
+    /// Creates a new gzip encoder that will operate according to the
+    /// specified options.
+    pub fn new(options: Options, btype: BlockType, source: R) -> Self {
+        Self::new_with_header(options, btype, &crate::GzipHeader::default(), source)
+    }
+
+    /// Creates a new gzip encoder that will operate according to the
+    /// specified options, and write the specified metadata in the gzip
+    /// header.
+    pub fn new_with_header(
+        options: Options,
+        btype: BlockType,
+        header: &crate::GzipHeader,
+        source: R,
+    ) -> Self {
+        Self {
+            inner: EncoderReader::new(
+                &options,
+                source,
+                crate::GzipEncoder::new_with_header(options, btype, header, Vec::new())
+                    .expect("Writing to a Vec should not fail"),
+            ),
+        }
+    }
+
+    /// Gets a reference to the underlying source.
+    pub fn get_ref(&self) -> &R {
+        &self.inner.source
+    }
+
+    /// Gets a mutable reference to the underlying source. Reading from it
+    /// directly means that data will not be compressed.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner.source
+    }
+
+    /// Consumes the encoder, returning the underlying source.
+    pub fn into_inner(self) -> R {
+        self.inner.source
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl<R: Read> Read for GzipEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// A zlib encoder powered by the Zopfli algorithm that compresses the data
+/// read from a source. See [`crate::ZlibEncoder`].
+#[cfg(feature = "zlib")]
+pub struct ZlibEncoder<R: Read> {
+    inner: EncoderReader<R, crate::ZlibEncoder<Vec<u8>>>,
+}
+
+#[cfg(feature = "zlib")]
+impl<R: Read> ZlibEncoder<R> {
+    /// Creates a new zlib encoder that will operate according to the
+    /// specified options.
+    pub fn new(options: Options, btype: BlockType, source: R) -> Self {
+        Self {
+            inner: EncoderReader::new(
+                &options,
+                source,
+                crate::ZlibEncoder::new(options, btype, Vec::new())
+                    .expect("Writing to a Vec should not fail"),
+            ),
+        }
+    }
+
+    /// Creates a new zlib encoder that will operate according to the
+    /// specified options, using the specified preset dictionary, as
+    /// described in [`crate::ZlibEncoder::new_with_dictionary`].
+    pub fn new_with_dictionary(
+        options: Options,
+        btype: BlockType,
+        dictionary: &[u8],
+        source: R,
+    ) -> Self {
+        Self {
+            inner: EncoderReader::new(
+                &options,
+                source,
+                crate::ZlibEncoder::new_with_dictionary(options, btype, dictionary, Vec::new())
+                    .expect("Writing to a Vec should not fail"),
+            ),
+        }
+    }
+
+    /// Gets a reference to the underlying source.
+    pub fn get_ref(&self) -> &R {
+        &self.inner.source
+    }
+
+    /// Gets a mutable reference to the underlying source. Reading from it
+    /// directly means that data will not be compressed.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner.source
+    }
+
+    /// Consumes the encoder, returning the underlying source.
+    pub fn into_inner(self) -> R {
+        self.inner.source
+    }
+}
+
+#[cfg(feature = "zlib")]
+impl<R: Read> Read for ZlibEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZeroU64;
+
+    use super::*;
+
+    /// A source that returns few bytes per read, and fails once.
+    struct TrickleSource<'a> {
+        data: &'a [u8],
+        failed: bool,
+    }
+
+    impl Read for TrickleSource<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.failed && self.data.len() < 500 {
+                self.failed = true;
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+
+            let read = self.data.len().min(buf.len()).min(7);
+            buf[..read].copy_from_slice(&self.data[..read]);
+            self.data = &self.data[read..];
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn matches_write_encoder_output() {
+        let options = Options {
+            iteration_count: NonZeroU64::new(1).unwrap(),
+            ..Options::default()
+        };
+        let data = b"Readers pull compressed data from a source. ".repeat(200);
+
+        let mut encoder = DeflateEncoder::new(
+            options,
+            BlockType::Dynamic,
+            TrickleSource {
+                data: &data,
+                failed: false,
+            },
+        );
+        let mut compressed_data = Vec::new();
+        let error = encoder.read_to_end(&mut compressed_data).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        let mut buf = [0; 3];
+        loop {
+            match encoder.read(&mut buf).unwrap() {
+                0 => break,
+                read => compressed_data.extend_from_slice(&buf[..read]),
+            }
+        }
+
+        let mut write_encoder = crate::DeflateEncoder::new(options, BlockType::Dynamic, Vec::new());
+        write_encoder.write_all(&data).unwrap();
+        assert_eq!(compressed_data, write_encoder.finish().unwrap());
+        assert!(encoder.get_ref().data.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn compresses_several_master_blocks() {
+        use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+        use crate::util::ZOPFLI_MASTER_BLOCK_SIZE;
+
+        let options = Options {
+            iteration_count: NonZeroU64::new(1).unwrap(),
+            ..Options::default()
+        };
+        let data = b"0123456789".repeat(ZOPFLI_MASTER_BLOCK_SIZE / 10 + 1);
+
+        let mut compressed_data = Vec::new();
+        io::copy(
+            &mut ZlibEncoder::new(options, BlockType::Dynamic, &data[..]),
+            &mut compressed_data,
+        )
+        .unwrap();
+
+        assert_eq!(decompress_to_vec_zlib(&compressed_data).unwrap(), data);
+    }
+
+    #[test]
+    fn reads_the_source_a_master_block_at_a_time() {
+        let options = Options {
+            master_block_size: 1000,
+            ..Options::default()
+        };
+        let data = b"Master blocks are read as needed. ".repeat(150);
+
+        let mut source = &data[..];
+        let mut encoder = DeflateEncoder::new(options, BlockType::Fixed, &mut source);
+        let mut compressed_data = vec![0; 1];
+        encoder.read_exact(&mut compressed_data).unwrap();
+        // A master block is compressed once the next one is read
+        assert_eq!(encoder.get_ref().len(), data.len() - 2000);
+
+        encoder.read_to_end(&mut compressed_data).unwrap();
+        assert_eq!(
+            compressed_data,
+            crate::deflate_slice(options, BlockType::Fixed, &data, Vec::new()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,883 @@
+//! Recompression of data that was already compressed with DEFLATE by another
+//! compressor, such as zlib at its highest level.
+//!
+//! Every DEFLATE payload of the input is decompressed and compressed again
+//! with Zopfli, keeping everything else byte for byte: gzip member headers
+//! (with their file names and timestamps), zlib headers, and ZIP local and
+//! central headers, in their original order, except for the sizes and offsets
+//! that change. A payload whose new DEFLATE stream is not smaller than the
+//! original is left untouched.
+//!
+//! As the input may come from anywhere, every payload must decompress to at
+//! most a maximum size, so that a small input can not exhaust memory.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let gzip_file = std::fs::read("index.html.gz")?;
+//! let max_payload_size = 1 << 30;
+//! let recompressed_file =
+//!     zopfli::recompress::recompress(zopfli::Options::default(), &gzip_file, max_payload_size)?;
+//! std::fs::write("index.html.gz", recompressed_file)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    fmt::{Display, Formatter},
+    ops::Range,
+};
+
+use log::debug;
+
+use crate::{
+    inflate::{self, InflateError},
+    zip::{
+        CENTRAL_DIRECTORY_HEADER_SIGNATURE, DATA_DESCRIPTOR_SIGNATURE,
+        END_OF_CENTRAL_DIRECTORY_SIGNATURE, FLAG_DATA_DESCRIPTOR, LOCAL_FILE_HEADER_SIGNATURE,
+        METHOD_DEFLATED, ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE,
+        ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE, ZIP64_EXTRA_FIELD_ID,
+    },
+    BlockType, DeflateEncoder, Options, Write,
+};
+
+/// General purpose flag that signals that a ZIP entry is encrypted.
+const FLAG_ENCRYPTED: u16 = 1 << 0;
+
+/// The magic value that ends the APK Signing Block, which is stored right
+/// before the central directory of APKs signed with the v2 or later schemes.
+const APK_SIGNING_BLOCK_MAGIC: &[u8] = b"APK Sig Block 42";
+
+/// The container formats of DEFLATE payloads that can be recompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Container {
+    /// A gzip file, with one or more members.
+    Gzip,
+    /// A zlib stream.
+    Zlib,
+    /// A ZIP archive, or a format based on it, such as JAR or EPUB. Signed
+    /// APKs are not supported.
+    Zip,
+}
+
+/// The errors that may happen while recompressing data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RecompressError {
+    /// The input is not in any of the supported container formats.
+    UnknownContainer,
+    /// The ZIP archive structure is malformed or unsupported, such as when
+    /// it spans several disks.
+    InvalidZip,
+    /// The ZIP archive is an APK with an APK Signing Block, whose signatures
+    /// cover the exact bytes of the entries, so recompressing them would make
+    /// the APK fail to install.
+    SignedApk,
+    /// A payload could not be decompressed, its checksum does not match, or
+    /// it decompresses to more than the maximum payload size.
+    Inflate(InflateError),
+}
+
+impl Display for RecompressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownContainer => f.write_str("not a gzip, zlib or ZIP file"),
+            Self::InvalidZip => f.write_str("malformed or unsupported ZIP archive"),
+            Self::SignedApk => {
+                f.write_str("signed APK, whose signature would be invalidated by recompressing it")
+            }
+            Self::Inflate(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RecompressError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Inflate(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(not(feature = "std"), feature = "nightly"))]
+impl core::error::Error for RecompressError {}
+
+impl From<InflateError> for RecompressError {
+    fn from(error: InflateError) -> Self {
+        Self::Inflate(error)
+    }
+}
+
+/// Detects the container format of the input from its first bytes.
+pub fn detect_container(data: &[u8]) -> Option<Container> {
+    match data {
+        [31, 139, 8, ..] => Some(Container::Gzip),
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => Some(Container::Zip),
+        [cmf, flg, ..]
+            if cmf & 0x0F == 8 && cmf >> 4 <= 7 && u16::from_be_bytes([*cmf, *flg]) % 31 == 0 =>
+        {
+            Some(Container::Zlib)
+        }
+        _ => None,
+    }
+}
+
+/// Detects the container format of the input and recompresses its DEFLATE
+/// payloads with Zopfli, according to the specified options. The result is
+/// never bigger than the input.
+///
+/// Recompression fails with [`InflateError::OutputTooLarge`] if a payload
+/// decompresses to more than `max_payload_size` bytes. The payloads of ZIP
+/// entries are also bounded by their recorded uncompressed size.
+pub fn recompress(
+    options: Options,
+    data: &[u8],
+    max_payload_size: usize,
+) -> Result<Vec<u8>, RecompressError> {
+    let container = detect_container(data).ok_or(RecompressError::UnknownContainer)?;
+    recompress_container(options, container, data, max_payload_size)
+}
+
+/// Recompresses the DEFLATE payloads of the input, which is in the specified
+/// container format, with Zopfli, according to the specified options. The
+/// result is never bigger than the input.
+///
+/// Payloads are limited to `max_payload_size` bytes, as in [`recompress`].
+pub fn recompress_container(
+    options: Options,
+    container: Container,
+    data: &[u8],
+    max_payload_size: usize,
+) -> Result<Vec<u8>, RecompressError> {
+    match container {
+        Container::Gzip => recompress_gzip(options, data, max_payload_size),
+        Container::Zlib => recompress_zlib(options, data, max_payload_size),
+        Container::Zip => recompress_zip(options, data, max_payload_size),
+    }
+}
+
+/// Compresses the decompressed data of a payload to a raw DEFLATE stream,
+/// returning it only if it is smaller than the original stream.
+fn deflate_if_smaller(
+    options: Options,
+    decompressed_data: &[u8],
+    original_stream: &[u8],
+) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, Vec::new());
+    encoder
+        .write_all(decompressed_data)
+        .expect("Writing to a Vec should not fail");
+    let stream = encoder.finish().expect("Writing to a Vec should not fail");
+
+    debug!(
+        "Payload recompressed from {} to {} bytes",
+        original_stream.len(),
+        stream.len()
+    );
+    Some(stream).filter(|stream| stream.len() < original_stream.len())
+}
+
+fn recompress_gzip(
+    options: Options,
+    data: &[u8],
+    max_payload_size: usize,
+) -> Result<Vec<u8>, RecompressError> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let member = &data[offset..];
+        let header_size = inflate::gzip_header_size(member)?;
+        let (decompressed_data, member_size) =
+            inflate::gzip_decompress_member(member, max_payload_size)?;
+
+        // The CRC-32 and size trailer stays valid, as the data is the same
+        let original_stream = &member[header_size..member_size - 8];
+        output.extend_from_slice(&member[..header_size]);
+        output.extend_from_slice(
+            deflate_if_smaller(options, &decompressed_data, original_stream)
+                .as_deref()
+                .unwrap_or(original_stream),
+        );
+        output.extend_from_slice(&member[member_size - 8..member_size]);
+
+        offset += member_size;
+    }
+
+    Ok(output)
+}
+
+fn recompress_zlib(
+    options: Options,
+    data: &[u8],
+    max_payload_size: usize,
+) -> Result<Vec<u8>, RecompressError> {
+    // Streams with a preset dictionary are rejected, so the header is two
+    // bytes long, and the Adler-32 checksum spans the last four
+    let decompressed_data = inflate::zlib_decompress_limited(data, max_payload_size)?;
+    let original_stream = &data[2..data.len() - 4];
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&data[..2]);
+    output.extend_from_slice(
+        deflate_if_smaller(options, &decompressed_data, original_stream)
+            .as_deref()
+            .unwrap_or(original_stream),
+    );
+    output.extend_from_slice(&data[data.len() - 4..]);
+
+    Ok(output)
+}
+
+fn recompress_zip(
+    options: Options,
+    data: &[u8],
+    max_payload_size: usize,
+) -> Result<Vec<u8>, RecompressError> {
+    let directory = CentralDirectory::find(data)?;
+    if data
+        .get(..directory.offset)
+        .ok_or(RecompressError::InvalidZip)?
+        .ends_with(APK_SIGNING_BLOCK_MAGIC)
+    {
+        return Err(RecompressError::SignedApk);
+    }
+    let entries = directory.entries(data)?;
+
+    // Entries are rewritten in the order they appear in the archive, which
+    // may differ from the central directory order
+    let mut entry_order: Vec<usize> = (0..entries.len()).collect();
+    entry_order.sort_by_key(|&index| entries[index].local_header);
+
+    let mut output = Vec::with_capacity(data.len());
+    let mut new_compressed_sizes = vec![0; entries.len()];
+    let mut new_local_header_offsets = vec![0; entries.len()];
+    let mut offset = 0;
+
+    for index in entry_order {
+        let entry = &entries[index];
+        if entry.local_header < offset {
+            return Err(RecompressError::InvalidZip);
+        }
+        output.extend_from_slice(&data[offset..entry.local_header]);
+        new_local_header_offsets[index] = output.len() as u64;
+
+        let original_stream = &data[entry.data_start..entry.data_end];
+        let new_stream = if entry.is_recompressible() {
+            let max_output = usize::try_from(entry.uncompressed_size)
+                .map_or(max_payload_size, |size| size.min(max_payload_size));
+            let decompressed_data = inflate::inflate_limited(original_stream, max_output)?;
+            let actual = crc32fast::hash(&decompressed_data);
+            if actual != entry.crc32 {
+                return Err(InflateError::ChecksumMismatch {
+                    expected: entry.crc32,
+                    actual,
+                }
+                .into());
+            }
+            deflate_if_smaller(options, &decompressed_data, original_stream)
+        } else {
+            None
+        };
+        let stream = new_stream.as_deref().unwrap_or(original_stream);
+        let old_compressed_size = original_stream.len() as u64;
+        let new_compressed_size = stream.len() as u64;
+        new_compressed_sizes[index] = new_compressed_size;
+
+        let mut local_header = data[entry.local_header..entry.data_start].to_vec();
+        let local_fields = FieldLocations::local_header(&local_header)?;
+        let local_header_has_zip64_sizes = local_fields.has_zip64;
+        local_fields.replace(
+            &mut local_header,
+            1,
+            old_compressed_size,
+            new_compressed_size,
+        );
+        output.extend_from_slice(&local_header);
+        output.extend_from_slice(stream);
+        offset = entry.data_end;
+
+        if entry.flags & FLAG_DATA_DESCRIPTOR != 0 {
+            // The data descriptor signature is optional, and its sizes are
+            // 64-bit if the local header has ZIP64 fields
+            let signature_size = if read_u32(data, offset)? == DATA_DESCRIPTOR_SIGNATURE {
+                4
+            } else {
+                0
+            };
+            let size_width = if local_header_has_zip64_sizes { 8 } else { 4 };
+            let descriptor_end = offset + signature_size + 4 + 2 * size_width;
+            let mut descriptor = data
+                .get(offset..descriptor_end)
+                .ok_or(RecompressError::InvalidZip)?
+                .to_vec();
+            let compressed_size = Field {
+                position: signature_size + 4,
+                width: size_width,
+            };
+            compressed_size.replace(&mut descriptor, old_compressed_size, new_compressed_size);
+            output.extend_from_slice(&descriptor);
+            offset = descriptor_end;
+        }
+    }
+
+    if directory.offset < offset {
+        return Err(RecompressError::InvalidZip);
+    }
+    output.extend_from_slice(&data[offset..directory.offset]);
+    let new_directory_offset = output.len() as u64;
+    for (index, entry) in entries.iter().enumerate() {
+        let mut header = data[entry.central_header.clone()].to_vec();
+        let fields = FieldLocations::central_header(&header)?;
+        fields.replace(
+            &mut header,
+            1,
+            (entry.data_end - entry.data_start) as u64,
+            new_compressed_sizes[index],
+        );
+        fields.replace(
+            &mut header,
+            2,
+            entry.local_header as u64,
+            new_local_header_offsets[index],
+        );
+        output.extend_from_slice(&header);
+    }
+
+    let trailer_start = output.len();
+    output.extend_from_slice(&data[directory.end..]);
+    let end_record = trailer_start + (directory.end_record - directory.end);
+    if let Some(zip64_record) = directory.zip64_record {
+        let locator = end_record - 20;
+        let zip64_record = trailer_start + (zip64_record - directory.end);
+        Field::u64_at(zip64_record + 48).write(&mut output, new_directory_offset);
+        Field::u64_at(locator + 8).write(&mut output, zip64_record as u64);
+    }
+    Field::u32_at(end_record + 16).replace(
+        &mut output,
+        directory.offset as u64,
+        new_directory_offset,
+    );
+
+    Ok(output)
+}
+
+/// The location of the central directory of a ZIP archive.
+struct CentralDirectory {
+    offset: usize,
+    end: usize,
+    entry_count: u64,
+    end_record: usize,
+    zip64_record: Option<usize>,
+}
+
+/// The central directory fields of a ZIP entry that are needed to rewrite it.
+struct ZipEntry {
+    central_header: Range<usize>,
+    flags: u16,
+    method: u16,
+    crc32: u32,
+    uncompressed_size: u64,
+    local_header: usize,
+    data_start: usize,
+    data_end: usize,
+}
+
+impl ZipEntry {
+    fn is_recompressible(&self) -> bool {
+        self.method == METHOD_DEFLATED && self.flags & FLAG_ENCRYPTED == 0
+    }
+}
+
+impl CentralDirectory {
+    fn find(data: &[u8]) -> Result<Self, RecompressError> {
+        // The end of central directory record is followed by a comment of
+        // up to 64 KiB, so look for a signature whose comment ends the file
+        let end_record = (data.len().saturating_sub(22 + u16::MAX as usize)
+            ..=data.len().saturating_sub(22))
+            .rev()
+            .find(|&offset| {
+                read_u32(data, offset) == Ok(END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+                    && read_u16(data, offset + 20).map(|length| offset + 22 + length as usize)
+                        == Ok(data.len())
+            })
+            .ok_or(RecompressError::InvalidZip)?;
+
+        if read_u16(data, end_record + 4)? != 0 || read_u16(data, end_record + 6)? != 0 {
+            return Err(RecompressError::InvalidZip);
+        }
+        let mut entry_count = read_u16(data, end_record + 10)? as u64;
+        let mut size = read_u32(data, end_record + 12)? as u64;
+        let mut offset = read_u32(data, end_record + 16)? as u64;
+
+        let mut zip64_record = None;
+        let locator = end_record.checked_sub(20);
+        if let Some(locator) = locator.filter(|&locator| {
+            read_u32(data, locator) == Ok(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE)
+        }) {
+            let record = usize::try_from(read_u64(data, locator + 8)?)
+                .map_err(|_| RecompressError::InvalidZip)?;
+            if read_u32(data, record)? != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE
+                || read_u32(data, record + 16)? != 0
+                || read_u32(data, record + 20)? != 0
+            {
+                return Err(RecompressError::InvalidZip);
+            }
+            entry_count = read_u64(data, record + 32)?;
+            size = read_u64(data, record + 40)?;
+            offset = read_u64(data, record + 48)?;
+            zip64_record = Some(record);
+        }
+
+        let offset = usize::try_from(offset).map_err(|_| RecompressError::InvalidZip)?;
+        let end = usize::try_from(size)
+            .ok()
+            .and_then(|size| offset.checked_add(size))
+            .filter(|&end| end <= zip64_record.unwrap_or(end_record))
+            .ok_or(RecompressError::InvalidZip)?;
+
+        Ok(CentralDirectory {
+            offset,
+            end,
+            entry_count,
+            end_record,
+            zip64_record,
+        })
+    }
+
+    fn entries(&self, data: &[u8]) -> Result<Vec<ZipEntry>, RecompressError> {
+        let mut entries = Vec::new();
+        let mut offset = self.offset;
+
+        for _ in 0..self.entry_count {
+            if read_u32(data, offset)? != CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+                return Err(RecompressError::InvalidZip);
+            }
+            let header_end = offset
+                + 46
+                + read_u16(data, offset + 28)? as usize
+                + read_u16(data, offset + 30)? as usize
+                + read_u16(data, offset + 32)? as usize;
+            let header = data
+                .get(offset..header_end)
+                .filter(|_| header_end <= self.end)
+                .ok_or(RecompressError::InvalidZip)?;
+
+            let fields = FieldLocations::central_header(header)?;
+            let compressed_size = fields.read(header, 1);
+            let local_header =
+                usize::try_from(fields.read(header, 2)).map_err(|_| RecompressError::InvalidZip)?;
+
+            if read_u32(data, local_header)? != LOCAL_FILE_HEADER_SIGNATURE {
+                return Err(RecompressError::InvalidZip);
+            }
+            let data_start = local_header
+                + 30
+                + read_u16(data, local_header + 26)? as usize
+                + read_u16(data, local_header + 28)? as usize;
+            let data_end = usize::try_from(compressed_size)
+                .ok()
+                .and_then(|size| data_start.checked_add(size))
+                .filter(|&data_end| data_end <= self.offset)
+                .ok_or(RecompressError::InvalidZip)?;
+
+            entries.push(ZipEntry {
+                central_header: offset..header_end,
+                flags: read_u16(header, 8)?,
+                method: read_u16(header, 10)?,
+                crc32: read_u32(header, 16)?,
+                uncompressed_size: fields.read(header, 0),
+                local_header,
+                data_start,
+                data_end,
+            });
+            offset = header_end;
+        }
+
+        if offset != self.end {
+            return Err(RecompressError::InvalidZip);
+        }
+        Ok(entries)
+    }
+}
+
+/// A little-endian integer field at some position of a buffer.
+#[derive(Clone, Copy)]
+struct Field {
+    position: usize,
+    width: usize,
+}
+
+impl Field {
+    fn u32_at(position: usize) -> Self {
+        Field { position, width: 4 }
+    }
+
+    fn u64_at(position: usize) -> Self {
+        Field { position, width: 8 }
+    }
+
+    fn read(self, buf: &[u8]) -> u64 {
+        let mut bytes = [0; 8];
+        bytes[..self.width].copy_from_slice(&buf[self.position..self.position + self.width]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write(self, buf: &mut [u8], value: u64) {
+        buf[self.position..self.position + self.width]
+            .copy_from_slice(&value.to_le_bytes()[..self.width]);
+    }
+
+    /// Replaces the value of the field if it is the old one. Fields that are
+    /// not filled in, such as local header sizes that are in a data
+    /// descriptor instead, are left alone.
+    fn replace(self, buf: &mut [u8], old_value: u64, new_value: u64) {
+        if self.read(buf) == old_value {
+            self.write(buf, new_value);
+        }
+    }
+}
+
+/// The locations of the size and offset fields of a ZIP header, which are in
+/// its ZIP64 extra field if their 32-bit fields are saturated.
+struct FieldLocations {
+    fields: Vec<Field>,
+    has_zip64: bool,
+}
+
+impl FieldLocations {
+    /// Resolves the locations of the compressed and uncompressed sizes of a
+    /// local file header.
+    fn local_header(header: &[u8]) -> Result<Self, RecompressError> {
+        let extra_start = 30 + read_u16(header, 26)? as usize;
+        let extra_end = extra_start + read_u16(header, 28)? as usize;
+        Self::resolve(header, &[22, 18], extra_start..extra_end)
+    }
+
+    /// Resolves the locations of the compressed and uncompressed sizes, and
+    /// the local header offset, of a central directory header.
+    fn central_header(header: &[u8]) -> Result<Self, RecompressError> {
+        let extra_start = 46 + read_u16(header, 28)? as usize;
+        let extra_end = extra_start + read_u16(header, 30)? as usize;
+        Self::resolve(header, &[24, 20, 42], extra_start..extra_end)
+    }
+
+    /// Resolves the locations of the 32-bit fields at the specified positions
+    /// of the header, which must be in the order their ZIP64 counterparts
+    /// are stored.
+    fn resolve(
+        header: &[u8],
+        positions: &[usize],
+        extra_fields: Range<usize>,
+    ) -> Result<Self, RecompressError> {
+        if extra_fields.end > header.len() {
+            return Err(RecompressError::InvalidZip);
+        }
+
+        let mut zip64_field = None;
+        let mut offset = extra_fields.start;
+        while offset + 4 <= extra_fields.end {
+            let id = read_u16(header, offset)?;
+            let size = read_u16(header, offset + 2)? as usize;
+            if id == ZIP64_EXTRA_FIELD_ID {
+                zip64_field = Some(offset + 4..(offset + 4 + size).min(extra_fields.end));
+                break;
+            }
+            offset += 4 + size;
+        }
+
+        let mut zip64_offset = zip64_field.as_ref().map_or(0, |field| field.start);
+        let mut fields = Vec::with_capacity(positions.len());
+        for &position in positions {
+            if read_u32(header, position)? == u32::MAX {
+                match &zip64_field {
+                    Some(field) if zip64_offset + 8 <= field.end => {
+                        fields.push(Field::u64_at(zip64_offset));
+                        zip64_offset += 8;
+                    }
+                    _ => return Err(RecompressError::InvalidZip),
+                }
+            } else {
+                fields.push(Field::u32_at(position));
+            }
+        }
+
+        Ok(FieldLocations {
+            fields,
+            has_zip64: zip64_field.is_some(),
+        })
+    }
+
+    fn read(&self, header: &[u8], index: usize) -> u64 {
+        self.fields[index].read(header)
+    }
+
+    fn replace(&self, header: &mut [u8], index: usize, old_value: u64, new_value: u64) {
+        self.fields[index].replace(header, old_value, new_value);
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, RecompressError> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or(RecompressError::InvalidZip)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, RecompressError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or(RecompressError::InvalidZip)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, RecompressError> {
+    let low = read_u32(data, offset)? as u64;
+    let high = read_u32(data, offset + 4)? as u64;
+    Ok(high << 32 | low)
+}
+
+#[cfg(test)]
+mod test {
+    use miniz_oxide::deflate::{compress_to_vec, compress_to_vec_zlib};
+
+    use super::*;
+    use crate::zip::{test::read_entries, METHOD_STORED};
+
+    fn text(len: usize) -> Vec<u8> {
+        (0u32..)
+            .flat_map(|i| format!("Line {}: {}\n", i, i * i % 1009).into_bytes())
+            .take(len)
+            .collect()
+    }
+
+    fn gzip_member(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut member = vec![31, 139, 8, 1 << 3, 0x78, 0x56, 0x34, 0x12, 2, 3];
+        member.extend_from_slice(name);
+        member.push(0);
+        member.extend_from_slice(&compress_to_vec(data, 9));
+        member.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        member.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        member
+    }
+
+    #[test]
+    fn detects_containers() {
+        assert_eq!(
+            detect_container(&gzip_member(b"", b"")),
+            Some(Container::Gzip)
+        );
+        assert_eq!(detect_container(&[0x78, 0xDA, 3, 0]), Some(Container::Zlib));
+        assert_eq!(detect_container(b"PK\x03\x04"), Some(Container::Zip));
+        assert_eq!(detect_container(b"PK\x05\x06"), Some(Container::Zip));
+        assert_eq!(detect_container(b"\x89PNG"), None);
+        assert_eq!(
+            recompress(Options::default(), b"plain text", usize::MAX),
+            Err(RecompressError::UnknownContainer)
+        );
+    }
+
+    #[test]
+    fn recompresses_every_gzip_member_keeping_headers() {
+        let first_member = gzip_member(b"first.txt", &text(5000));
+        let second_member = gzip_member(b"second.txt", &text(3000));
+        let gzip_file = [first_member.as_slice(), &second_member].concat();
+
+        let recompressed_file = recompress(Options::default(), &gzip_file, usize::MAX).unwrap();
+
+        assert!(recompressed_file.len() < gzip_file.len());
+        assert_eq!(
+            inflate::gzip_decompress(&recompressed_file).unwrap(),
+            [text(5000), text(3000)].concat()
+        );
+        let header_size = inflate::gzip_header_size(&first_member).unwrap();
+        assert_eq!(
+            recompressed_file[..header_size],
+            first_member[..header_size]
+        );
+        let second_member_start = inflate::gzip_decompress_member(&recompressed_file, usize::MAX)
+            .unwrap()
+            .1;
+        assert_eq!(
+            recompressed_file[second_member_start..][..header_size + 1],
+            second_member[..header_size + 1]
+        );
+    }
+
+    #[test]
+    fn recompresses_zlib_streams_keeping_headers() {
+        let zlib_stream = compress_to_vec_zlib(&text(5000), 9);
+
+        let recompressed_stream = recompress(Options::default(), &zlib_stream, usize::MAX).unwrap();
+
+        assert!(recompressed_stream.len() < zlib_stream.len());
+        assert_eq!(recompressed_stream[..2], zlib_stream[..2]);
+        assert_eq!(
+            inflate::zlib_decompress(&recompressed_stream).unwrap(),
+            text(5000)
+        );
+    }
+
+    #[test]
+    fn keeps_streams_that_do_not_shrink() {
+        let zlib_stream = compress_to_vec_zlib(b"tiny", 9);
+
+        let recompressed_stream = recompress(Options::default(), &zlib_stream, usize::MAX).unwrap();
+
+        assert_eq!(recompressed_stream, zlib_stream);
+    }
+
+    /// Builds a ZIP archive whose entries are compressed with miniz_oxide,
+    /// with an archive comment. The entries flagged as streamed use a data
+    /// descriptor.
+    fn zip_archive(entries: &[(&str, &[u8], u16, bool)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut central_directory = Vec::new();
+
+        for &(name, data, method, streamed) in entries {
+            let stream = if method == METHOD_DEFLATED {
+                compress_to_vec(data, 9)
+            } else {
+                data.to_vec()
+            };
+            let flags = if streamed { FLAG_DATA_DESCRIPTOR } else { 0 };
+            let crc32 = crc32fast::hash(data);
+            let fields = |header: &mut Vec<u8>, hide_sizes: bool| {
+                header.extend_from_slice(&flags.to_le_bytes());
+                header.extend_from_slice(&method.to_le_bytes());
+                header.extend_from_slice(&[0x21, 0x60, 0x58, 0x21]);
+                for value in [crc32, stream.len() as u32, data.len() as u32] {
+                    let value = if hide_sizes { 0 } else { value };
+                    header.extend_from_slice(&value.to_le_bytes());
+                }
+                header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                header.extend_from_slice(&0u16.to_le_bytes());
+            };
+
+            central_directory.extend_from_slice(&CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes());
+            central_directory.extend_from_slice(&[20, 3, 20, 0]);
+            fields(&mut central_directory, false);
+            central_directory.extend_from_slice(&[0; 8]);
+            central_directory.extend_from_slice(&0o100_644u32.to_le_bytes()[..2]);
+            central_directory.extend_from_slice(&(archive.len() as u32).to_le_bytes());
+            central_directory.extend_from_slice(name.as_bytes());
+
+            archive.extend_from_slice(&LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes());
+            archive.extend_from_slice(&[20, 0]);
+            fields(&mut archive, streamed);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&stream);
+            if streamed {
+                archive.extend_from_slice(&DATA_DESCRIPTOR_SIGNATURE.to_le_bytes());
+                archive.extend_from_slice(&crc32.to_le_bytes());
+                archive.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+                archive.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            }
+        }
+
+        let central_directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&central_directory);
+        archive.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&central_directory_offset.to_le_bytes());
+        archive.extend_from_slice(&7u16.to_le_bytes());
+        archive.extend_from_slice(b"comment");
+        archive
+    }
+
+    #[test]
+    fn recompresses_zip_entries_keeping_their_order() {
+        let zip_file = zip_archive(&[
+            ("b.txt", &text(4000), METHOD_DEFLATED, false),
+            ("stored.txt", &text(100), METHOD_STORED, false),
+            ("a.txt", &text(6000), METHOD_DEFLATED, true),
+        ]);
+
+        let recompressed_file = recompress(Options::default(), &zip_file, usize::MAX).unwrap();
+
+        assert!(recompressed_file.len() < zip_file.len());
+        assert!(recompressed_file.ends_with(b"comment"));
+        let entries: Vec<_> = read_entries(&recompressed_file)
+            .into_iter()
+            .map(|entry| (entry.name, entry.method, entry.data))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("b.txt".into(), METHOD_DEFLATED, text(4000)),
+                ("stored.txt".into(), METHOD_STORED, text(100)),
+                ("a.txt".into(), METHOD_DEFLATED, text(6000)),
+            ]
+        );
+        // Only the compressed size of the first local header changes
+        assert_eq!(recompressed_file[..18], zip_file[..18]);
+        assert_eq!(recompressed_file[22..35], zip_file[22..35]);
+    }
+
+    #[test]
+    fn rejects_signed_apks() {
+        let mut zip_file = zip_archive(&[("classes.dex", &text(1000), METHOD_DEFLATED, false)]);
+        let end_record = zip_file
+            .windows(4)
+            .rposition(|bytes| bytes == END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes())
+            .unwrap();
+        let directory_offset = read_u32(&zip_file, end_record + 16).unwrap() as usize;
+
+        // An empty APK Signing Block: its size, which is repeated at the end,
+        // and the magic value
+        let mut signing_block = Vec::new();
+        signing_block.extend_from_slice(&24u64.to_le_bytes());
+        signing_block.extend_from_slice(&24u64.to_le_bytes());
+        signing_block.extend_from_slice(APK_SIGNING_BLOCK_MAGIC);
+        zip_file.splice(directory_offset..directory_offset, signing_block);
+        let directory_offset = (directory_offset as u32 + 32).to_le_bytes();
+        zip_file[end_record + 32 + 16..end_record + 32 + 20].copy_from_slice(&directory_offset);
+
+        assert_eq!(
+            recompress(Options::default(), &zip_file, usize::MAX),
+            Err(RecompressError::SignedApk)
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_zip_entries() {
+        let mut zip_file = zip_archive(&[("a.txt", &text(1000), METHOD_DEFLATED, false)]);
+        let central_header = zip_file
+            .windows(4)
+            .position(|bytes| bytes == CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes())
+            .unwrap();
+        zip_file[central_header + 16] ^= 1;
+
+        assert!(matches!(
+            recompress(Options::default(), &zip_file, usize::MAX),
+            Err(RecompressError::Inflate(
+                InflateError::ChecksumMismatch { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn limits_the_size_of_payloads() {
+        let zlib_stream = compress_to_vec_zlib(&text(5000), 9);
+        assert_eq!(
+            recompress(Options::default(), &zlib_stream, 4999),
+            Err(RecompressError::Inflate(InflateError::OutputTooLarge))
+        );
+
+        // A ZIP entry may not decompress to more than its recorded size
+        let mut zip_file = zip_archive(&[("a.txt", &text(1000), METHOD_DEFLATED, false)]);
+        let central_header = zip_file
+            .windows(4)
+            .position(|bytes| bytes == CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes())
+            .unwrap();
+        zip_file[central_header + 24..central_header + 28].copy_from_slice(&999u32.to_le_bytes());
+        assert_eq!(
+            recompress(Options::default(), &zip_file, usize::MAX),
+            Err(RecompressError::Inflate(InflateError::OutputTooLarge))
+        );
+    }
+}
@@ -17,8 +17,10 @@ use crate::{
     deflate::{calculate_block_size, BlockType},
     hash::ZopfliHash,
     lz77::{find_longest_match, LitLen, Lz77Store},
+    progress::{ProgressReporter, ProgressStage},
     symbols::{get_dist_extra_bits, get_dist_symbol, get_length_extra_bits, get_length_symbol},
     util::{ZOPFLI_MAX_MATCH, ZOPFLI_NUM_D, ZOPFLI_NUM_LL, ZOPFLI_WINDOW_MASK, ZOPFLI_WINDOW_SIZE},
+    Options,
 };
 
 const K_INV_LOG2: f64 = core::f64::consts::LOG2_E; // 1.0 / log(2.0)
@@ -27,6 +29,88 @@ const K_INV_LOG2: f64 = core::f64::consts::LOG2_E; // 1.0 / log(2.0)
 #[allow(unused_imports)] // False-positive
 use crate::math::F64MathExt;
 
+/// A model of the cost in bits of LZ77 items, which optimal parsing finds the
+/// cheapest representation of the data for. Closures that take the same
+/// arguments as [`cost`](CostModel::cost) are cost models too.
+///
+/// To save time, the parser skips backreferences that cannot be cheaper than
+/// the cheapest length with distance 1 plus the cheapest distance with length
+/// 3, among the first distances of each distance symbol. Models whose costs
+/// only depend on the symbols and their extra bits meet this assumption, and
+/// other models may get slightly worse results.
+pub trait CostModel {
+    /// Returns the cost of a literal byte `litlen` when `dist` is 0, or of a
+    /// backreference of length `litlen`, from 3 to 258, and distance `dist`,
+    /// from 1 to 32768, otherwise. Costs must be finite and not negative.
+    fn cost(&self, litlen: usize, dist: u16) -> f64;
+}
+
+impl<F: Fn(usize, u16) -> f64> CostModel for F {
+    fn cost(&self, litlen: usize, dist: u16) -> f64 {
+        self(litlen, dist)
+    }
+}
+
+/// The cost model of the fixed Huffman tree of DEFLATE, which gives the
+/// exact size of each item in a fixed block.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixedCostModel;
+
+impl CostModel for FixedCostModel {
+    fn cost(&self, litlen: usize, dist: u16) -> f64 {
+        get_cost_fixed(litlen, dist)
+    }
+}
+
+/// A cost model that charges each symbol its entropy according to symbol
+/// counts, plus its extra bits. This is the model that the iterations of
+/// optimal parsing use, with the counts of the previous iteration, and it
+/// can also be fed by statistics from a training corpus.
+#[derive(Debug, Clone)]
+pub struct StatisticsCostModel {
+    stats: SymbolStats,
+}
+
+impl StatisticsCostModel {
+    /// Creates a cost model from histograms of literal/length and distance
+    /// symbols, such as those returned by [`Lz77Store::histogram`]. Symbols
+    /// that were not counted cost as much as if they were counted once, and
+    /// the end of block symbol is always counted once.
+    pub fn from_histograms(
+        litlens: &[usize; ZOPFLI_NUM_LL],
+        dists: &[usize; ZOPFLI_NUM_D],
+    ) -> Self {
+        let mut stats = SymbolStats {
+            litlens: *litlens,
+            dists: *dists,
+            ..SymbolStats::default()
+        };
+        stats.litlens[256] = 1;
+        stats.calculate_entropy();
+        StatisticsCostModel { stats }
+    }
+
+    /// Creates a cost model from the symbol counts of all the items of a
+    /// store.
+    pub fn from_store(store: &Lz77Store) -> Self {
+        let mut stats = SymbolStats::default();
+        stats.get_statistics(store);
+        StatisticsCostModel { stats }
+    }
+}
+
+impl CostModel for StatisticsCostModel {
+    fn cost(&self, litlen: usize, dist: u16) -> f64 {
+        get_cost_stat(litlen, dist, &self.stats)
+    }
+}
+
+impl CostModel for SymbolStats {
+    fn cost(&self, litlen: usize, dist: u16) -> f64 {
+        get_cost_stat(litlen, dist, self)
+    }
+}
+
 /// Cost model which should exactly match fixed tree.
 fn get_cost_fixed(litlen: usize, dist: u16) -> f64 {
     let result = if dist == 0 {
@@ -65,8 +149,14 @@ struct RanState {
 }
 
 impl RanState {
-    fn new() -> RanState {
-        RanState { m_w: 1, m_z: 2 }
+    /// Creates a generator whose sequence is determined by `seed`. Seed 0 gives
+    /// the state the reference implementation always starts with, and every
+    /// seed keeps both halves of the state nonzero.
+    fn new(seed: u32) -> RanState {
+        RanState {
+            m_w: 1 + (seed & 0xFFFF),
+            m_z: 2 + (seed >> 16),
+        }
     }
 
     /// Get random number: "Multiply-With-Carry" generator of G. Marsaglia
@@ -77,7 +167,7 @@ impl RanState {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 struct SymbolStats {
     /* The literal and length symbols. */
     litlens: [usize; ZOPFLI_NUM_LL],
@@ -203,7 +293,7 @@ fn add_weighed_stat_freqs(
 
 /// Finds the minimum possible cost this cost model can return for valid length and
 /// distance symbols.
-fn get_cost_model_min_cost<F: Fn(usize, u16) -> f64>(costmodel: F) -> f64 {
+fn get_cost_model_min_cost<M: CostModel + ?Sized>(costmodel: &M) -> f64 {
     let mut bestlength = 0; // length that has lowest cost in the cost model
     let mut bestdist = 0; // distance that has lowest cost in the cost model
 
@@ -219,7 +309,7 @@ fn get_cost_model_min_cost<F: Fn(usize, u16) -> f64>(costmodel: F) -> f64 {
 
     let mut mincost = f64::INFINITY;
     for i in 3..259 {
-        let c = costmodel(i, 1);
+        let c = costmodel.cost(i, 1);
         if c < mincost {
             bestlength = i;
             mincost = c;
@@ -228,13 +318,13 @@ fn get_cost_model_min_cost<F: Fn(usize, u16) -> f64>(costmodel: F) -> f64 {
 
     mincost = f64::INFINITY;
     for dsym in DSYMBOLS {
-        let c = costmodel(3, dsym);
+        let c = costmodel.cost(3, dsym);
         if c < mincost {
             bestdist = dsym;
             mincost = c;
         }
     }
-    costmodel(bestlength, bestdist)
+    costmodel.cost(bestlength, bestdist)
 }
 
 /// Performs the forward pass for "squeeze". Gets the most optimal length to reach
@@ -247,12 +337,12 @@ fn get_cost_model_min_cost<F: Fn(usize, u16) -> f64>(costmodel: F) -> f64 {
 /// `length_array`: output array of size `(inend - instart)` which will receive the best
 ///     length to reach this byte from a previous byte.
 /// returns the cost that was, according to the `costmodel`, needed to get to the end.
-fn get_best_lengths<F: Fn(usize, u16) -> f64, C: Cache>(
+fn get_best_lengths<M: CostModel + ?Sized, C: Cache>(
     lmc: &mut C,
     in_data: &[u8],
     instart: usize,
     inend: usize,
-    costmodel: F,
+    costmodel: &M,
     h: &mut ZopfliHash,
     costs: &mut Vec<f32>,
 ) -> (f64, Vec<u16>) {
@@ -281,7 +371,7 @@ fn get_best_lengths<F: Fn(usize, u16) -> f64, C: Cache>(
     let mut leng;
     let mut longest_match;
     let mut sublen = vec![0; ZOPFLI_MAX_MATCH + 1];
-    let mincost = get_cost_model_min_cost(&costmodel);
+    let mincost = get_cost_model_min_cost(costmodel);
     while i < inend {
         let mut j = i - instart; // Index in the costs array and length_array.
         h.update(arr, i);
@@ -293,7 +383,7 @@ fn get_best_lengths<F: Fn(usize, u16) -> f64, C: Cache>(
             && i + ZOPFLI_MAX_MATCH * 2 + 1 < inend
             && h.same[(i - ZOPFLI_MAX_MATCH) & ZOPFLI_WINDOW_MASK] > ZOPFLI_MAX_MATCH as u16
         {
-            let symbolcost = costmodel(ZOPFLI_MAX_MATCH, 1);
+            let symbolcost = costmodel.cost(ZOPFLI_MAX_MATCH, 1);
             // Set the length to reach each one to ZOPFLI_MAX_MATCH, and the cost to
             // the cost corresponding to that length. Doing this, we skip
             // ZOPFLI_MAX_MATCH values to avoid calling ZopfliFindLongestMatch.
@@ -321,7 +411,7 @@ fn get_best_lengths<F: Fn(usize, u16) -> f64, C: Cache>(
 
         // Literal.
         if i < inend {
-            let new_cost = costmodel(arr[i] as usize, 0) + costs[j] as f64;
+            let new_cost = costmodel.cost(arr[i] as usize, 0) + costs[j] as f64;
             debug_assert!(new_cost >= 0.0);
             if new_cost < costs[j + 1] as f64 {
                 costs[j + 1] = new_cost as f32;
@@ -339,7 +429,7 @@ fn get_best_lengths<F: Fn(usize, u16) -> f64, C: Cache>(
                 continue;
             }
 
-            let new_cost = costmodel(k, sublength) + costs[j] as f64;
+            let new_cost = costmodel.cost(k, sublength) + costs[j] as f64;
             debug_assert!(new_cost >= 0.0);
             if new_cost < costs[j + k] as f64 {
                 debug_assert!(k <= ZOPFLI_MAX_MATCH);
@@ -390,12 +480,12 @@ fn trace(size: usize, length_array: &[u16]) -> Vec<u16> {
 /// returns the cost that was, according to the `costmodel`, needed to get to the end.
 ///     This is not the actual cost.
 #[allow(clippy::too_many_arguments)] // Not feasible to refactor in a more readable way
-fn lz77_optimal_run<F: Fn(usize, u16) -> f64, C: Cache>(
+fn lz77_optimal_run<M: CostModel + ?Sized, C: Cache>(
     lmc: &mut C,
     in_data: &[u8],
     instart: usize,
     inend: usize,
-    costmodel: F,
+    costmodel: &M,
     store: &mut Lz77Store,
     h: &mut ZopfliHash,
     costs: &mut Vec<f32>,
@@ -420,50 +510,137 @@ pub fn lz77_optimal_fixed<C: Cache>(
     instart: usize,
     inend: usize,
     store: &mut Lz77Store,
+) {
+    lz77_cost_model_run(lmc, in_data, instart, inend, &FixedCostModel, store);
+}
+
+/// Does a single run of `lz77_optimal` with the specified cost model, which
+/// gives the cheapest LZ77 data according to it.
+/// If `instart` is larger than `0`, it uses values before `instart` as starting
+/// dictionary.
+pub fn lz77_cost_model_run<M: CostModel + ?Sized, C: Cache>(
+    lmc: &mut C,
+    in_data: &[u8],
+    instart: usize,
+    inend: usize,
+    costmodel: &M,
+    store: &mut Lz77Store,
 ) {
     let mut h = ZopfliHash::new();
     let mut costs = Vec::with_capacity(inend - instart);
     lz77_optimal_run(
-        lmc,
-        in_data,
-        instart,
-        inend,
-        get_cost_fixed,
-        store,
-        &mut h,
-        &mut costs,
+        lmc, in_data, instart, inend, costmodel, store, &mut h, &mut costs,
     );
 }
 
 /// Calculates lit/len and dist pairs for given data.
 /// If `instart` is larger than 0, it uses values before `instart` as starting
 /// dictionary.
+/// The amount of iterations is limited as configured in `options`, which may
+/// also ask for restarting them with several random seeds, keeping the best
+/// result. Returns the amount of iterations that were run along with the
+/// lit/len and dist pairs.
 pub fn lz77_optimal<C: Cache>(
     lmc: &mut C,
     in_data: &[u8],
     instart: usize,
     inend: usize,
-    max_iterations: u64,
-    max_iterations_without_improvement: u64,
-) -> Lz77Store {
-    /* Dist to get to here with smallest cost. */
-    let mut currentstore = Lz77Store::new();
-    let mut outputstore = currentstore.clone();
+    options: &Options,
+    progress: &ProgressReporter,
+) -> (Lz77Store, u64) {
+    // Budgets too large to be represented as a point in time mean there is
+    // no deadline
+    #[cfg(feature = "std")]
+    let deadline = options.time_budget_per_mib.and_then(|budget| {
+        let mib = (inend - instart) as f64 / (1 << 20) as f64;
+        std::time::Duration::try_from_secs_f64(budget.as_secs_f64() * mib)
+            .ok()
+            .and_then(|budget| std::time::Instant::now().checked_add(budget))
+    });
+    let is_out_of_time = || {
+        #[cfg(feature = "std")]
+        {
+            matches!(deadline, Some(deadline) if std::time::Instant::now() >= deadline)
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            false
+        }
+    };
 
     /* Initial run. */
-    currentstore.greedy(lmc, in_data, instart, inend);
-    let mut stats = SymbolStats::default();
-    stats.get_statistics(&currentstore);
+    let mut greedystore = Lz77Store::new();
+    greedystore.greedy(lmc, in_data, instart, inend);
+    let mut initialstats = SymbolStats::default();
+    initialstats.get_statistics(&greedystore);
 
     let mut h = ZopfliHash::new();
     let mut costs = Vec::with_capacity(inend - instart + 1);
 
+    let mut outputstore = greedystore;
+    let mut bestcost = f64::INFINITY;
+    let mut iterations = 0;
+    for restart in 0..=options.restarts {
+        if restart > 0 && (progress.is_cancelled() || is_out_of_time()) {
+            break;
+        }
+
+        let seed = options.seed.wrapping_add(restart.into());
+        let (store, cost, seed_iterations) = lz77_optimal_seeded(
+            lmc,
+            in_data,
+            instart,
+            inend,
+            options,
+            RanState::new(seed),
+            initialstats,
+            &mut h,
+            &mut costs,
+            &is_out_of_time,
+            progress,
+        );
+        iterations += seed_iterations;
+
+        if cost < bestcost {
+            if restart > 0 {
+                debug!("Seed {}: {} bit", seed, cost);
+            }
+            outputstore = store;
+            bestcost = cost;
+        }
+    }
+    (outputstore, iterations)
+}
+
+/// Does the iterations of `lz77_optimal` for a single random seed, starting
+/// with the cost model given by `stats`. Returns the best lit/len and dist
+/// pairs found, their cost and the amount of iterations that were run.
+#[allow(clippy::too_many_arguments)] // Not feasible to refactor in a more readable way
+fn lz77_optimal_seeded<C: Cache>(
+    lmc: &mut C,
+    in_data: &[u8],
+    instart: usize,
+    inend: usize,
+    options: &Options,
+    mut ran_state: RanState,
+    mut stats: SymbolStats,
+    h: &mut ZopfliHash,
+    costs: &mut Vec<f32>,
+    is_out_of_time: &impl Fn() -> bool,
+    progress: &ProgressReporter,
+) -> (Lz77Store, f64, u64) {
+    let max_iterations = options.iteration_count.get();
+    let max_iterations_without_improvement = options.iterations_without_improvement.get();
+
+    /* Dist to get to here with smallest cost. */
+    let mut currentstore = Lz77Store::new();
+    let mut outputstore = currentstore.clone();
+
     let mut beststats = SymbolStats::default();
 
     let mut bestcost = f64::INFINITY;
     let mut lastcost = 0.0;
     /* Try randomizing the costs a bit once the size stabilizes. */
-    let mut ran_state = RanState::new();
     let mut lastrandomstep = u64::MAX;
 
     /* Do regular deflate, then loop multiple shortest path runs, each time using
@@ -479,10 +656,10 @@ pub fn lz77_optimal<C: Cache>(
             in_data,
             instart,
             inend,
-            |a, b| get_cost_stat(a, b, &stats),
+            &stats,
             &mut currentstore,
-            &mut h,
-            &mut costs,
+            h,
+            costs,
         );
         let cost = calculate_block_size(&currentstore, 0, currentstore.size(), BlockType::Dynamic);
 
@@ -497,14 +674,22 @@ pub fn lz77_optimal<C: Cache>(
         } else {
             iterations_without_improvement += 1;
             trace!("Iteration {}: {} bit", current_iteration, cost);
-            if iterations_without_improvement >= max_iterations_without_improvement {
-                break;
-            }
         }
         current_iteration += 1;
+        if iterations_without_improvement >= max_iterations_without_improvement
+            || progress
+                .report(ProgressStage::SqueezeIteration, 0, bestcost)
+                .is_break()
+        {
+            break;
+        }
         if current_iteration >= max_iterations {
             break;
         }
+        if is_out_of_time() {
+            debug!("Time budget spent after {} iterations", current_iteration);
+            break;
+        }
         let laststats = stats;
         stats.clear_freqs();
         stats.get_statistics(&currentstore);
@@ -523,5 +708,54 @@ pub fn lz77_optimal<C: Cache>(
         }
         lastcost = cost;
     }
-    outputstore
+    (outputstore, bestcost, current_iteration)
+}
+
+#[cfg(test)]
+mod test {
+    use core::num::NonZeroU64;
+
+    use super::*;
+    use crate::cache::ZopfliLongestMatchCache;
+
+    fn optimal_cost(data: &[u8], seed: u32, restarts: u16) -> (f64, u64) {
+        let options = Options {
+            iteration_count: NonZeroU64::new(20).unwrap(),
+            seed,
+            restarts,
+            ..Options::default()
+        };
+        let (store, iterations) = lz77_optimal(
+            &mut ZopfliLongestMatchCache::new(data.len()),
+            data,
+            0,
+            data.len(),
+            &options,
+            &ProgressReporter::new(None, 0),
+        );
+        (
+            calculate_block_size(&store, 0, store.size(), BlockType::Dynamic),
+            iterations,
+        )
+    }
+
+    #[test]
+    fn restarts_keep_the_best_seed() {
+        let data = crate::test_util::lcg(1)
+            .take(20_000)
+            .map(|state| b"zopfli squeeze "[(state >> 16) as usize % 15])
+            .collect::<Vec<_>>();
+
+        let seed_costs = (5..8)
+            .map(|seed| optimal_cost(&data, seed, 0).0)
+            .collect::<Vec<_>>();
+        assert_eq!(optimal_cost(&data, 6, 0).0, seed_costs[1]);
+
+        let (cost, iterations) = optimal_cost(&data, 5, 2);
+        assert_eq!(
+            cost,
+            seed_costs.iter().copied().fold(f64::INFINITY, f64::min)
+        );
+        assert_eq!(iterations, 3 * 20);
+    }
 }
@@ -0,0 +1,197 @@
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::BlockType;
+
+/// Statistics about a compressed stream, returned by [`compress`](crate::compress)
+/// and the `finish_with_stats` method of the encoders, such as
+/// [`DeflateEncoder::finish_with_stats`](crate::DeflateEncoder::finish_with_stats).
+///
+/// They are useful for tuning the [`Options`](crate::Options) for different
+/// kinds of data, and for tracking the compression of a data set over time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CompressionStats {
+    /// The amount of uncompressed data bytes.
+    pub input_size: u64,
+    /// The amount of compressed bytes written to the sink, including the
+    /// headers and trailers of the gzip and Zlib formats.
+    pub output_size: u64,
+    /// The amount of DEFLATE blocks that store their data uncompressed,
+    /// including the empty ones that end the data of each flush.
+    pub uncompressed_blocks: u64,
+    /// The amount of DEFLATE blocks that use the fixed Huffman codes.
+    pub fixed_blocks: u64,
+    /// The amount of DEFLATE blocks that use dynamic Huffman codes.
+    pub dynamic_blocks: u64,
+    /// The positions in the uncompressed data where each block but the first
+    /// starts, in ascending order. Besides the block splitting decisions, this
+    /// includes the boundaries of master blocks and uncompressed blocks, which
+    /// can hold up to 65535 bytes. Empty blocks, such as flush markers, add no
+    /// split points of their own, so there may be fewer split points than
+    /// blocks.
+    pub split_points: Vec<u64>,
+    /// The amount of LZ77 optimization iterations that were run for each
+    /// segment the input was split into, in order. The blocks that are
+    /// written may further refine these segments, so there is not always one
+    /// segment per dynamic block.
+    pub iterations: Vec<u64>,
+    /// The time spent looking for the best block split points. When several
+    /// threads are used, this adds up the time spent by each of them. It is
+    /// always zero without the `std` feature, which provides the clock.
+    pub block_splitting_time: Duration,
+    /// The time spent optimizing the LZ77 representation of the data, which
+    /// is accounted for as [`block_splitting_time`](Self::block_splitting_time)
+    /// is.
+    pub squeeze_time: Duration,
+}
+
+impl CompressionStats {
+    /// Records the start of a block of the specified type, which holds
+    /// `input_size` data bytes.
+    pub(crate) fn add_block(&mut self, btype: BlockType, input_size: usize) {
+        self.add_split_point();
+
+        match btype {
+            BlockType::Uncompressed => self.uncompressed_blocks += 1,
+            BlockType::Fixed => self.fixed_blocks += 1,
+            BlockType::Dynamic => self.dynamic_blocks += 1,
+        }
+        self.input_size += input_size as u64;
+    }
+
+    /// Adds the statistics of a stream that follows the data these
+    /// statistics describe, such as the next member of a gzip file.
+    #[cfg(feature = "gzip")]
+    pub(crate) fn append(&mut self, stats: CompressionStats) {
+        self.add_split_point();
+        let offset = self.input_size;
+        self.split_points
+            .extend(stats.split_points.iter().map(|&point| offset + point));
+
+        self.input_size += stats.input_size;
+        self.output_size += stats.output_size;
+        self.uncompressed_blocks += stats.uncompressed_blocks;
+        self.fixed_blocks += stats.fixed_blocks;
+        self.dynamic_blocks += stats.dynamic_blocks;
+        self.add_optimization(stats);
+    }
+
+    /// Records a split point at the end of the data described so far, unless
+    /// it is the start of the data or was already recorded by an empty block.
+    fn add_split_point(&mut self) {
+        if self.uncompressed_blocks + self.fixed_blocks + self.dynamic_blocks > 0
+            && self.split_points.last() != Some(&self.input_size)
+        {
+            self.split_points.push(self.input_size);
+        }
+    }
+
+    /// Adds the iterations and times recorded while optimizing some data,
+    /// which is done separately from writing its blocks.
+    pub(crate) fn add_optimization(&mut self, stats: CompressionStats) {
+        self.iterations.extend(stats.iterations);
+        self.block_splitting_time += stats.block_splitting_time;
+        self.squeeze_time += stats.squeeze_time;
+    }
+}
+
+/// Measures the time elapsed since it was started, if the standard library
+/// is available to provide a clock.
+pub(crate) struct Stopwatch {
+    #[cfg(feature = "std")]
+    start: std::time::Instant,
+}
+
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Stopwatch {
+            #[cfg(feature = "std")]
+            start: std::time::Instant::now(),
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(feature = "std")]
+        {
+            self.start.elapsed()
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use core::num::NonZeroU64;
+
+    use super::*;
+    use crate::{test_util::block_splittable_data, DeflateEncoder, Options, Write};
+
+    #[test]
+    fn stats_describe_the_written_stream() {
+        let data = block_splittable_data(150_000);
+        let options = Options {
+            iteration_count: NonZeroU64::new(3).unwrap(),
+            ..Options::default()
+        };
+
+        let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, vec![]);
+        encoder.write_all(&data).unwrap();
+        let (compressed_data, stats) = encoder.finish_with_stats().unwrap();
+
+        assert_eq!(stats.input_size, data.len() as u64);
+        assert_eq!(stats.output_size, compressed_data.len() as u64);
+        assert_eq!(
+            stats.uncompressed_blocks + stats.fixed_blocks + stats.dynamic_blocks,
+            stats.split_points.len() as u64 + 1
+        );
+        assert!(stats.dynamic_blocks > 1);
+        assert!(stats
+            .split_points
+            .windows(2)
+            .all(|points| points[0] < points[1]));
+        assert!(!stats.iterations.is_empty());
+        assert!(stats.iterations.iter().all(|&iterations| iterations == 3));
+    }
+
+    #[test]
+    fn empty_blocks_add_no_split_points() {
+        let data = b"Flush me, then finish me".repeat(20);
+
+        let mut encoder = DeflateEncoder::new(Options::default(), BlockType::Dynamic, vec![]);
+        encoder.set_flush_mode(crate::FlushMode::Sync);
+        encoder.write_all(&data).unwrap();
+        encoder.flush().unwrap();
+        let (_, stats) = encoder.finish_with_stats().unwrap();
+
+        // The data block, the flush marker and the empty final block
+        assert_eq!(stats.input_size, data.len() as u64);
+        assert_eq!(stats.uncompressed_blocks, 1);
+        assert_eq!(
+            stats.uncompressed_blocks + stats.fixed_blocks + stats.dynamic_blocks,
+            3
+        );
+        assert_eq!(stats.split_points, [data.len() as u64]);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn container_overhead_is_counted() {
+        let data = b"Count me in, count me in, count me in".repeat(10);
+
+        let mut compressed_data = vec![];
+        let stats = crate::compress(
+            Options::default(),
+            crate::Format::Gzip,
+            &data[..],
+            &mut compressed_data,
+        )
+        .unwrap();
+
+        assert_eq!(stats.input_size, data.len() as u64);
+        assert_eq!(stats.output_size, compressed_data.len() as u64);
+    }
+}
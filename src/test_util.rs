@@ -0,0 +1,29 @@
+//! Data generators shared by the tests of several modules.
+
+use alloc::vec::Vec;
+use core::iter;
+
+/// Returns the endless sequence of states of a linear congruential generator
+/// that starts from `seed`, for deterministic pseudorandom test data.
+pub(crate) fn lcg(seed: u32) -> impl Iterator<Item = u32> {
+    iter::successors(Some(seed), |state| {
+        Some(state.wrapping_mul(1_103_515_245).wrapping_add(12345))
+    })
+    .skip(1)
+}
+
+/// Generates pseudorandom data with sections that use different alphabets,
+/// so that it gets split into several blocks.
+#[cfg(feature = "std")]
+pub(crate) fn block_splittable_data(len: usize) -> Vec<u8> {
+    const ALPHABETS: [&[u8]; 3] = [b"zopfli deflate ", b"0123456789,.\n", b"ABCDEFGHIJ"];
+
+    lcg(1)
+        .take(len)
+        .enumerate()
+        .map(|(i, state)| {
+            let alphabet = ALPHABETS[i / 20_000 % ALPHABETS.len()];
+            alphabet[(state >> 16) as usize % alphabet.len()]
+        })
+        .collect()
+}
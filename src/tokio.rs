@@ -0,0 +1,334 @@
+//! Encoders that implement the [`AsyncWrite`] trait of
+//! [tokio](https://tokio.rs/).
+//!
+//! Written data is buffered until there is a master block of it, of
+//! [`Options::master_block_size`] bytes, and each master block is compressed
+//! in a task spawned with [`spawn_blocking`](::tokio::task::spawn_blocking),
+//! so the encoders must be used from within a runtime. No blocking thread is
+//! held between master blocks. Writes are not accepted while a previous
+//! master block is being compressed, and the next one is not started until
+//! most of the compressed output was written to the sink, so memory usage
+//! stays bounded by the master block size. When it is 0, the whole input is
+//! buffered and compressed in a single task once the encoder is shut down.
+//!
+//! Calling [`shutdown`](::tokio::io::AsyncWriteExt::shutdown) is required to
+//! finish the compressed stream, and then shuts down the sink.
+//!
+//! ```
+//! # use tokio::io::AsyncWriteExt;
+//! # use zopfli::{tokio::GzipEncoder, BlockType, Options};
+//! # async fn compress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+//! let mut encoder = GzipEncoder::new(Options::default(), BlockType::Dynamic, Vec::new());
+//! encoder.write_all(data).await?;
+//! encoder.shutdown().await?;
+//! Ok(encoder.into_inner())
+//! # }
+//! ```
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use ::tokio::io::AsyncWrite;
+
+use crate::{
+    async_encoder::{AsyncEncoder, MakeEncoder},
+    BlockType, Options,
+};
+
+fn spawn_blocking(job: Box<dyn FnOnce() + Send>) {
+    ::tokio::task::spawn_blocking(job);
+}
+
+macro_rules! async_encoder {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        pub struct $name<W: AsyncWrite + Unpin> {
+            encoder: AsyncEncoder,
+            sink: W,
+        }
+
+        impl<W: AsyncWrite + Unpin> $name<W> {
+            fn with_encoder(options: &Options, make_encoder: MakeEncoder, sink: W) -> Self {
+                Self {
+                    encoder: AsyncEncoder::new(options, make_encoder, spawn_blocking),
+                    sink,
+                }
+            }
+
+            /// Gets a reference to the underlying sink.
+            pub fn get_ref(&self) -> &W {
+                &self.sink
+            }
+
+            /// Gets a mutable reference to the underlying sink. Writing to
+            /// it directly is likely to corrupt the compressed stream.
+            pub fn get_mut(&mut self) -> &mut W {
+                &mut self.sink
+            }
+
+            /// Consumes the encoder, returning the underlying sink. The
+            /// compressed stream is only complete if the encoder was shut
+            /// down successfully.
+            pub fn into_inner(self) -> W {
+                self.sink
+            }
+        }
+
+        impl<W: AsyncWrite + Unpin> AsyncWrite for $name<W> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                let this = self.get_mut();
+                let sink = &mut this.sink;
+                this.encoder.poll_write(cx, buf, &mut |cx, buf| {
+                    Pin::new(&mut *sink).poll_write(cx, buf)
+                })
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                let this = self.get_mut();
+                let sink = &mut this.sink;
+                let mut write_sink =
+                    |cx: &mut Context<'_>, buf: &[u8]| Pin::new(&mut *sink).poll_write(cx, buf);
+                match this.encoder.poll_flush(cx, &mut write_sink) {
+                    Poll::Ready(Ok(())) => Pin::new(&mut this.sink).poll_flush(cx),
+                    poll => poll,
+                }
+            }
+
+            fn poll_shutdown(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<io::Result<()>> {
+                let this = self.get_mut();
+                let sink = &mut this.sink;
+                let mut write_sink =
+                    |cx: &mut Context<'_>, buf: &[u8]| Pin::new(&mut *sink).poll_write(cx, buf);
+                match this.encoder.poll_close(cx, &mut write_sink) {
+                    Poll::Ready(Ok(())) => Pin::new(&mut this.sink).poll_shutdown(cx),
+                    poll => poll,
+                }
+            }
+        }
+    };
+}
+
+async_encoder! {
+    /// A DEFLATE encoder powered by the Zopfli algorithm that writes to a
+    /// tokio [`AsyncWrite`] sink. See [`crate::DeflateEncoder`].
+    DeflateEncoder
+}
+
+impl<W: AsyncWrite + Unpin> DeflateEncoder<W> {
+    /// Creates a new Zopfli DEFLATE encoder that will operate according to
+    /// the specified options.
+    pub fn new(options: Options, btype: BlockType, sink: W) -> Self {
+        Self::with_encoder(
+            &options,
+            Box::new(move |output| {
+                Ok(Box::new(crate::DeflateEncoder::new(options, btype, output)))
+            }),
+            sink,
+        )
+    }
+}
+
+#[cfg(feature = "gzip")]
+async_encoder! {
+    /// A gzip encoder powered by the Zopfli algorithm that writes to a tokio
+    /// [`AsyncWrite`] sink. See [`crate::GzipEncoder`].
+    GzipEncoder
+}
+
+#[cfg(feature = "gzip")]
+impl<W: AsyncWrite + Unpin> GzipEncoder<W> {
+    /// Creates a new gzip encoder that will operate according to the
+    /// specified options.
+    pub fn new(options: Options, btype: BlockType, sink: W) -> Self {
+        Self::new_with_header(options, btype, crate::GzipHeader::default(), sink)
+    }
+
+    /// Creates a new gzip encoder that will operate according to the
+    /// specified options, and write the specified metadata in the gzip
+    /// header.
+    pub fn new_with_header(
+        options: Options,
+        btype: BlockType,
+        header: crate::GzipHeader,
+        sink: W,
+    ) -> Self {
+        Self::with_encoder(
+            &options,
+            Box::new(move |output| {
+                Ok(Box::new(crate::GzipEncoder::new_with_header(
+                    options, btype, &header, output,
+                )?))
+            }),
+            sink,
+        )
+    }
+}
+
+#[cfg(feature = "zlib")]
+async_encoder! {
+    /// A zlib encoder powered by the Zopfli algorithm that writes to a tokio
+    /// [`AsyncWrite`] sink. See [`crate::ZlibEncoder`].
+    ZlibEncoder
+}
+
+#[cfg(feature = "zlib")]
+impl<W: AsyncWrite + Unpin> ZlibEncoder<W> {
+    /// Creates a new zlib encoder that will operate according to the
+    /// specified options.
+    pub fn new(options: Options, btype: BlockType, sink: W) -> Self {
+        Self::with_encoder(
+            &options,
+            Box::new(move |output| Ok(Box::new(crate::ZlibEncoder::new(options, btype, output)?))),
+            sink,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZeroU64;
+
+    use ::tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::{util::ZOPFLI_MASTER_BLOCK_SIZE, Write};
+
+    /// A sink that is only ready every other time it is polled, and accepts
+    /// few bytes at a time.
+    #[derive(Default)]
+    struct SlowSink {
+        data: Vec<u8>,
+        ready: bool,
+        shut_down: bool,
+    }
+
+    impl AsyncWrite for SlowSink {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            let written = buf.len().min(1000);
+            self.data.extend_from_slice(&buf[..written]);
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.shut_down = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn matches_blocking_encoder_output() {
+        let options = Options {
+            iteration_count: NonZeroU64::new(1).unwrap(),
+            ..Options::default()
+        };
+        let data = b"Async writes are compressed on a blocking thread. ".repeat(2000);
+
+        let runtime = ::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let sink = runtime.block_on(async {
+            let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, SlowSink::default());
+            for chunk in data.chunks(30_000) {
+                encoder.write_all(chunk).await.unwrap();
+            }
+            encoder.shutdown().await.unwrap();
+            encoder.into_inner()
+        });
+
+        let mut blocking_encoder =
+            crate::DeflateEncoder::new(options, BlockType::Dynamic, Vec::new());
+        for chunk in data.chunks(ZOPFLI_MASTER_BLOCK_SIZE) {
+            blocking_encoder.write_all(chunk).unwrap();
+        }
+        assert!(sink.shut_down);
+        assert_eq!(sink.data, blocking_encoder.finish().unwrap());
+    }
+
+    #[test]
+    fn compresses_each_master_block_in_its_own_job() {
+        let options = Options {
+            iteration_count: NonZeroU64::new(1).unwrap(),
+            ..Options::default()
+        };
+        let data: Vec<u8> = (0..ZOPFLI_MASTER_BLOCK_SIZE * 5 / 2)
+            .map(|i| (i / 7 % 251) as u8)
+            .collect();
+
+        let runtime = ::tokio::runtime::Builder::new_current_thread()
+            .max_blocking_threads(1)
+            .build()
+            .unwrap();
+        let sink = runtime.block_on(async {
+            let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, SlowSink::default());
+            encoder.write_all(&data).await.unwrap();
+            encoder.shutdown().await.unwrap();
+            encoder.into_inner()
+        });
+
+        let mut blocking_encoder =
+            crate::DeflateEncoder::new(options, BlockType::Dynamic, Vec::new());
+        blocking_encoder.write_all(&data).unwrap();
+        assert_eq!(sink.data, blocking_encoder.finish().unwrap());
+
+        // Dropping an unfinished encoder outside of the runtime does not panic
+        let encoder = runtime.block_on(async {
+            let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, Vec::new());
+            encoder.write_all(&data[..1000]).await.unwrap();
+            encoder
+        });
+        drop(runtime);
+        drop(encoder);
+    }
+
+    #[test]
+    fn buffers_master_blocks_of_the_configured_size() {
+        let data = b"Master blocks can be bigger or smaller than the default. ".repeat(500);
+
+        let runtime = ::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        for (master_block_size, accepted) in [(10_000, 10_000), (0, data.len())] {
+            let options = Options {
+                iteration_count: NonZeroU64::new(1).unwrap(),
+                master_block_size,
+                ..Options::default()
+            };
+            let sink = runtime.block_on(async {
+                let mut encoder = DeflateEncoder::new(options, BlockType::Dynamic, Vec::new());
+                assert_eq!(encoder.write(&data).await.unwrap(), accepted);
+                encoder.write_all(&data[accepted..]).await.unwrap();
+                encoder.shutdown().await.unwrap();
+                encoder.into_inner()
+            });
+
+            let mut blocking_encoder =
+                crate::DeflateEncoder::new(options, BlockType::Dynamic, Vec::new());
+            blocking_encoder.write_all(&data).unwrap();
+            assert_eq!(sink, blocking_encoder.finish().unwrap());
+        }
+    }
+}
@@ -9,13 +9,10 @@ pub const ZOPFLI_NUM_D: usize = 32;
 /// maximum possible by the deflate spec. Anything less hurts compression more than
 /// speed.
 pub const ZOPFLI_WINDOW_SIZE: usize = 32768;
-/// A block structure of huge, non-smart, blocks to divide the input into, to allow
-/// operating on huge files without exceeding memory, such as the 1GB wiki9 corpus.
-/// The whole compression algorithm, including the smarter block splitting, will
-/// be executed independently on each huge block.
-/// Dividing into huge blocks hurts compression, but not much relative to the size.
-/// This must be equal or greater than `ZOPFLI_WINDOW_SIZE`.
-#[cfg(feature = "std")]
+/// The default size of the huge, non-smart, blocks to divide the input into, to
+/// allow operating on huge files without exceeding memory, such as the 1GB wiki9
+/// corpus. See [`Options::master_block_size`](crate::Options::master_block_size).
+/// It is also a good size for buffering input data.
 pub const ZOPFLI_MASTER_BLOCK_SIZE: usize = 1_000_000;
 
 /// The window mask used to wrap indices into the window. This is why the
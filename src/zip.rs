@@ -0,0 +1,1007 @@
+//! Writing of ZIP archives, as described in the
+//! [PKWARE APPNOTE](https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT),
+//! whose entries are compressed with Zopfli. This covers file formats based on
+//! ZIP too, such as JAR, APK and EPUB.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{BlockType, DeflateEncoder, Error, Options, Write};
+
+pub(crate) const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+pub(crate) const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+pub(crate) const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+pub(crate) const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0606_4b50;
+pub(crate) const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+pub(crate) const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+
+pub(crate) const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+/// Version 2.0, which introduced DEFLATE compression and directories.
+const VERSION_DEFLATE: u16 = 20;
+/// Version 4.5, which introduced ZIP64.
+const VERSION_ZIP64: u16 = 45;
+/// The "version made by" field: the files attributes are Unix-compatible and
+/// the archive follows version 4.5 of the specification.
+const VERSION_MADE_BY: u16 = 3 << 8 | VERSION_ZIP64;
+
+/// General purpose flag that signals that sizes and CRC-32 follow the data.
+pub(crate) const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+/// General purpose flag that signals that the name is encoded in UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+
+pub(crate) const METHOD_STORED: u16 = 0;
+pub(crate) const METHOD_DEFLATED: u16 = 8;
+
+/// MS-DOS directory attribute.
+const DOS_DIRECTORY_ATTRIBUTE: u32 = 0x10;
+/// Unix file type bits for regular files and directories.
+const UNIX_REGULAR_FILE: u32 = 0o100_000;
+const UNIX_DIRECTORY: u32 = 0o040_000;
+
+/// How to compress the data of a ZIP entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ZipCompression {
+    /// Store the data without compression.
+    Stored,
+    /// Compress the data with Zopfli, unless that makes it bigger, in which
+    /// case it is stored. The data of the entry is buffered in memory until
+    /// the entry is finished, so the local header can contain its sizes and
+    /// CRC-32.
+    #[default]
+    Deflated,
+    /// Compress the data with Zopfli and write it to the sink as soon as
+    /// possible, without buffering the whole entry. The sizes and CRC-32 of the
+    /// data are written after it, in a data descriptor, and the data is never
+    /// stored uncompressed. Some tools, such as the readers of the EPUB
+    /// `mimetype` entry, do not support data descriptors.
+    DeflatedStream,
+}
+
+/// Settings for an entry of a ZIP archive, configured with builder methods:
+///
+/// ```
+/// # use zopfli::zip::{ZipCompression, ZipEntryOptions};
+/// let entry_options = ZipEntryOptions::new()
+///     .compression(ZipCompression::Stored)
+///     .last_modified(2024, 2, 29, 12, 30, 0)
+///     .unix_permissions(0o644);
+/// ```
+///
+/// By default, entries are [`Deflated`](ZipCompression::Deflated), dated
+/// 1980-01-01 00:00:00 (the earliest date ZIP can store, so that archives are
+/// reproducible), have 0644 (file) or 0755 (directory) permissions, and do not
+/// reserve room for ZIP64 sizes when streamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ZipEntryOptions {
+    compression: ZipCompression,
+    dos_date: u16,
+    dos_time: u16,
+    unix_permissions: Option<u32>,
+    large_file: bool,
+}
+
+impl ZipEntryOptions {
+    /// Creates the default entry settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how to compress the data of the entry.
+    pub fn compression(mut self, compression: ZipCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the last modification date and time of the entry, in local time,
+    /// with a precision of two seconds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the date is not between the years 1980 and 2107, both
+    /// inclusive, or any field is out of its range.
+    pub fn last_modified(
+        mut self,
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+    ) -> Self {
+        assert!(
+            (1980..=2107).contains(&year)
+                && (1..=12).contains(&month)
+                && (1..=31).contains(&day)
+                && hour < 24
+                && minute < 60
+                && second < 60,
+            "ZIP entry date and time out of range"
+        );
+
+        self.dos_date = (year - 1980) << 9 | (month as u16) << 5 | day as u16;
+        self.dos_time = (hour as u16) << 11 | (minute as u16) << 5 | (second / 2) as u16;
+        self
+    }
+
+    /// Sets the Unix permission bits of the entry, such as `0o755` for an
+    /// executable file.
+    pub fn unix_permissions(mut self, permissions: u32) -> Self {
+        self.unix_permissions = Some(permissions & 0o7777);
+        self
+    }
+
+    /// Sets whether a [streamed](ZipCompression::DeflatedStream) entry may
+    /// need ZIP64 sizes, i.e., be 4 GiB or bigger. Its size is not known in
+    /// advance, so this has to be set to write such an entry. Other entries
+    /// use ZIP64 automatically when needed.
+    pub fn large_file(mut self, large_file: bool) -> Self {
+        self.large_file = large_file;
+        self
+    }
+}
+
+impl Default for ZipEntryOptions {
+    fn default() -> Self {
+        ZipEntryOptions {
+            compression: ZipCompression::default(),
+            dos_date: 1 << 5 | 1,
+            dos_time: 0,
+            unix_permissions: None,
+            large_file: false,
+        }
+    }
+}
+
+/// A ZIP archive writer that compresses entries with Zopfli.
+///
+/// Entries are started with [`start_file`](Self::start_file), after which
+/// their data is written through the [`Write`] implementation, or added with
+/// [`add_directory`](Self::add_directory). Starting an entry finishes the
+/// previous one.
+///
+/// ```
+/// # use std::io::Write;
+/// # use zopfli::{zip::{ZipEntryOptions, ZipWriter}, Options};
+/// # fn main() -> std::io::Result<()> {
+/// let mut zip_writer = ZipWriter::new(Options::default(), Vec::new());
+/// zip_writer.add_directory("docs/", ZipEntryOptions::new())?;
+/// zip_writer.start_file("docs/README.txt", ZipEntryOptions::new())?;
+/// zip_writer.write_all(b"Hello, Zopfli!")?;
+/// let zip_archive = zip_writer.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ZipWriter<W: Write> {
+    options: Options,
+    state: State<W>,
+    entries: Vec<CentralDirectoryEntry>,
+}
+
+enum State<W: Write> {
+    /// No entry is being written.
+    Idle(CountingWriter<W>),
+    /// A [`ZipCompression::Stored`] or [`ZipCompression::Deflated`] entry.
+    Buffered {
+        sink: CountingWriter<W>,
+        entry: CentralDirectoryEntry,
+        data: Vec<u8>,
+    },
+    /// A [`ZipCompression::DeflatedStream`] entry, whose local header has
+    /// already been written.
+    Streamed {
+        encoder: Box<DeflateEncoder<CountingWriter<W>>>,
+        entry: CentralDirectoryEntry,
+        crc32_hasher: crc32fast::Hasher,
+        data_start: u64,
+    },
+    /// The archive was finished, or an error happened.
+    Done,
+}
+
+/// The fields of an entry that are repeated in the central directory.
+struct CentralDirectoryEntry {
+    name: Vec<u8>,
+    flags: u16,
+    method: u16,
+    dos_time: u16,
+    dos_date: u16,
+    crc32: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    local_header_offset: u64,
+    external_attributes: u32,
+    /// Whether the local header has a ZIP64 extra field.
+    zip64_local_header: bool,
+}
+
+impl<W: Write> ZipWriter<W> {
+    /// Creates a new ZIP archive writer that will compress entries according
+    /// to the specified options.
+    pub fn new(options: Options, sink: W) -> Self {
+        ZipWriter {
+            options,
+            state: State::Idle(CountingWriter {
+                inner: sink,
+                count: 0,
+            }),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Finishes the current entry, if any, and starts a file entry with the
+    /// specified name, which should use `/` as path separator. The data
+    /// written to the writer from now on will be the file contents.
+    ///
+    /// Fails without finishing the current entry if the name is longer than
+    /// 65535 bytes.
+    pub fn start_file(&mut self, name: &str, entry_options: ZipEntryOptions) -> Result<(), Error> {
+        if name.len() > u16::MAX as usize {
+            return Err(name_too_long_error());
+        }
+        let sink = self.finish_entry()?;
+        let entry = CentralDirectoryEntry::new(
+            name,
+            &entry_options,
+            UNIX_REGULAR_FILE | entry_options.unix_permissions.unwrap_or(0o644),
+            sink.count,
+        );
+
+        self.state = match entry_options.compression {
+            ZipCompression::Stored | ZipCompression::Deflated => State::Buffered {
+                sink,
+                entry,
+                data: Vec::new(),
+            },
+            ZipCompression::DeflatedStream => {
+                self.start_streamed_entry(sink, entry, entry_options.large_file)?
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Finishes the current entry, if any, and adds a directory entry with
+    /// the specified name. A `/` is appended to the name if it does not end
+    /// with one.
+    ///
+    /// Fails without finishing the current entry if the name, with its
+    /// trailing `/`, is longer than 65535 bytes.
+    pub fn add_directory(
+        &mut self,
+        name: &str,
+        entry_options: ZipEntryOptions,
+    ) -> Result<(), Error> {
+        if name.len() + !name.ends_with('/') as usize > u16::MAX as usize {
+            return Err(name_too_long_error());
+        }
+        let mut sink = self.finish_entry()?;
+
+        let mut entry = CentralDirectoryEntry::new(
+            name,
+            &entry_options,
+            UNIX_DIRECTORY | entry_options.unix_permissions.unwrap_or(0o755),
+            sink.count,
+        );
+        if !entry.name.ends_with(b"/") {
+            entry.name.push(b'/');
+        }
+        entry.external_attributes |= DOS_DIRECTORY_ATTRIBUTE;
+        entry.method = METHOD_STORED;
+
+        entry.write_local_header(&mut sink)?;
+        self.entries.push(entry);
+        self.state = State::Idle(sink);
+
+        Ok(())
+    }
+
+    /// Finishes the current entry, if any, and writes the central directory,
+    /// consuming the writer and returning the wrapped sink. The sink will
+    /// have received a complete ZIP archive when this method returns.
+    ///
+    /// The writer is automatically finished when dropped, but explicitly
+    /// finishing it with this method allows handling I/O errors.
+    ///
+    /// Fails if writing an entry failed before, as the archive is then
+    /// incomplete.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self._finish()?.ok_or_else(failed_writer_error)
+    }
+
+    fn _finish(&mut self) -> Result<Option<W>, Error> {
+        if matches!(self.state, State::Done) {
+            return Ok(None);
+        }
+
+        let mut sink = self.finish_entry()?;
+        self.state = State::Done;
+
+        let central_directory_offset = sink.count;
+        for entry in &self.entries {
+            entry.write_central_directory_header(&mut sink)?;
+        }
+        let central_directory_size = sink.count - central_directory_offset;
+
+        write_end_of_central_directory(
+            &mut sink,
+            self.entries.len() as u64,
+            central_directory_size,
+            central_directory_offset,
+        )?;
+
+        Ok(Some(sink.inner))
+    }
+
+    fn start_streamed_entry(
+        &self,
+        mut sink: CountingWriter<W>,
+        mut entry: CentralDirectoryEntry,
+        large_file: bool,
+    ) -> Result<State<W>, Error> {
+        entry.flags |= FLAG_DATA_DESCRIPTOR;
+        entry.method = METHOD_DEFLATED;
+        entry.zip64_local_header = large_file;
+        entry.write_local_header(&mut sink)?;
+
+        Ok(State::Streamed {
+            data_start: sink.count,
+            encoder: Box::new(DeflateEncoder::new(self.options, BlockType::Dynamic, sink)),
+            entry,
+            crc32_hasher: crc32fast::Hasher::new(),
+        })
+    }
+
+    /// Writes the pending data of the current entry, and returns the sink so
+    /// that a new entry can be started or the archive finished. The writer
+    /// is left unusable if this fails.
+    fn finish_entry(&mut self) -> Result<CountingWriter<W>, Error> {
+        match core::mem::replace(&mut self.state, State::Done) {
+            State::Idle(sink) => Ok(sink),
+            State::Buffered {
+                mut sink,
+                mut entry,
+                data,
+            } => {
+                entry.crc32 = crc32fast::hash(&data);
+                entry.uncompressed_size = data.len() as u64;
+
+                let compressed_data = if entry.method == METHOD_DEFLATED {
+                    let mut encoder =
+                        DeflateEncoder::new(self.options, BlockType::Dynamic, Vec::new());
+                    encoder.write_all(&data)?;
+                    Some(encoder.finish()?).filter(|compressed| compressed.len() < data.len())
+                } else {
+                    None
+                };
+
+                let stored_data = match &compressed_data {
+                    Some(compressed_data) => compressed_data,
+                    None => {
+                        entry.method = METHOD_STORED;
+                        &data
+                    }
+                };
+                entry.compressed_size = stored_data.len() as u64;
+                entry.zip64_local_header = entry.needs_zip64_sizes();
+
+                entry.write_local_header(&mut sink)?;
+                sink.write_all(stored_data)?;
+                self.entries.push(entry);
+                Ok(sink)
+            }
+            State::Streamed {
+                encoder,
+                mut entry,
+                crc32_hasher,
+                data_start,
+            } => {
+                let mut sink = encoder.finish()?;
+
+                entry.crc32 = crc32_hasher.finalize();
+                entry.compressed_size = sink.count - data_start;
+                if entry.needs_zip64_sizes() && !entry.zip64_local_header {
+                    return Err(entry_too_large_error());
+                }
+
+                entry.write_data_descriptor(&mut sink)?;
+                self.entries.push(entry);
+                Ok(sink)
+            }
+            State::Done => Err(failed_writer_error()),
+        }
+    }
+}
+
+impl<W: Write> Write for ZipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match &mut self.state {
+            State::Buffered { data, .. } => data.extend_from_slice(buf),
+            State::Streamed {
+                encoder,
+                entry,
+                crc32_hasher,
+                ..
+            } => {
+                encoder.write_all(buf)?;
+                crc32_hasher.update(buf);
+                entry.uncompressed_size += buf.len() as u64;
+            }
+            State::Idle(_) | State::Done => return Err(no_entry_error()),
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match &mut self.state {
+            State::Idle(sink) | State::Buffered { sink, .. } => sink.flush(),
+            State::Streamed { encoder, .. } => encoder.flush(),
+            State::Done => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for ZipWriter<W> {
+    fn drop(&mut self) {
+        self._finish().ok();
+    }
+}
+
+// Boilerplate to make latest Rustdoc happy: https://github.com/rust-lang/rust/issues/117796
+#[cfg(all(doc, feature = "std"))]
+impl<W: crate::io::Write> std::io::Write for ZipWriter<W> {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        unimplemented!()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        unimplemented!()
+    }
+}
+
+impl CentralDirectoryEntry {
+    fn new(
+        name: &str,
+        entry_options: &ZipEntryOptions,
+        unix_mode: u32,
+        local_header_offset: u64,
+    ) -> Self {
+        CentralDirectoryEntry {
+            name: name.as_bytes().to_vec(),
+            flags: if name.is_ascii() { 0 } else { FLAG_UTF8 },
+            method: match entry_options.compression {
+                ZipCompression::Stored => METHOD_STORED,
+                _ => METHOD_DEFLATED,
+            },
+            dos_time: entry_options.dos_time,
+            dos_date: entry_options.dos_date,
+            crc32: 0,
+            compressed_size: 0,
+            uncompressed_size: 0,
+            local_header_offset,
+            external_attributes: unix_mode << 16,
+            zip64_local_header: false,
+        }
+    }
+
+    fn needs_zip64_sizes(&self) -> bool {
+        self.compressed_size >= u32::MAX as u64 || self.uncompressed_size >= u32::MAX as u64
+    }
+
+    fn version_needed(&self, zip64: bool) -> u16 {
+        if zip64 {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFLATE
+        }
+    }
+
+    fn write_local_header<W: Write>(&self, sink: &mut W) -> Result<(), Error> {
+        let zip64 = self.zip64_local_header;
+        let (crc32, compressed_size, uncompressed_size) = if self.flags & FLAG_DATA_DESCRIPTOR != 0
+        {
+            (0, 0, 0)
+        } else {
+            (self.crc32, self.compressed_size, self.uncompressed_size)
+        };
+
+        let mut header = Vec::with_capacity(30 + self.name.len() + 20);
+        put_u32(&mut header, LOCAL_FILE_HEADER_SIGNATURE);
+        put_u16(&mut header, self.version_needed(zip64));
+        put_u16(&mut header, self.flags);
+        put_u16(&mut header, self.method);
+        put_u16(&mut header, self.dos_time);
+        put_u16(&mut header, self.dos_date);
+        put_u32(&mut header, crc32);
+        if zip64 {
+            put_u32(&mut header, u32::MAX);
+            put_u32(&mut header, u32::MAX);
+        } else {
+            put_u32(&mut header, compressed_size as u32);
+            put_u32(&mut header, uncompressed_size as u32);
+        }
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(&mut header, if zip64 { 20 } else { 0 });
+        header.extend_from_slice(&self.name);
+        if zip64 {
+            put_u16(&mut header, ZIP64_EXTRA_FIELD_ID);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, uncompressed_size);
+            put_u64(&mut header, compressed_size);
+        }
+
+        sink.write_all(&header)
+    }
+
+    fn write_data_descriptor<W: Write>(&self, sink: &mut W) -> Result<(), Error> {
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, self.crc32);
+        // Readers expect ZIP64 sizes if the local header has ZIP64 fields
+        if self.zip64_local_header {
+            put_u64(&mut descriptor, self.compressed_size);
+            put_u64(&mut descriptor, self.uncompressed_size);
+        } else {
+            put_u32(&mut descriptor, self.compressed_size as u32);
+            put_u32(&mut descriptor, self.uncompressed_size as u32);
+        }
+
+        sink.write_all(&descriptor)
+    }
+
+    fn write_central_directory_header<W: Write>(&self, sink: &mut W) -> Result<(), Error> {
+        // Only the fields that do not fit are stored in the ZIP64 extra field,
+        // in this order
+        let mut zip64_extra_field = Vec::new();
+        let mut zip64_field = |value: u64| {
+            if value >= u32::MAX as u64 {
+                put_u64(&mut zip64_extra_field, value);
+                u32::MAX
+            } else {
+                value as u32
+            }
+        };
+        let uncompressed_size = zip64_field(self.uncompressed_size);
+        let compressed_size = zip64_field(self.compressed_size);
+        let local_header_offset = zip64_field(self.local_header_offset);
+        let zip64 = !zip64_extra_field.is_empty() || self.zip64_local_header;
+
+        let mut header = Vec::with_capacity(46 + self.name.len() + 28);
+        put_u32(&mut header, CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+        put_u16(&mut header, VERSION_MADE_BY);
+        put_u16(&mut header, self.version_needed(zip64));
+        put_u16(&mut header, self.flags);
+        put_u16(&mut header, self.method);
+        put_u16(&mut header, self.dos_time);
+        put_u16(&mut header, self.dos_date);
+        put_u32(&mut header, self.crc32);
+        put_u32(&mut header, compressed_size);
+        put_u32(&mut header, uncompressed_size);
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(
+            &mut header,
+            if zip64_extra_field.is_empty() {
+                0
+            } else {
+                4 + zip64_extra_field.len() as u16
+            },
+        );
+        put_u16(&mut header, 0); // File comment length
+        put_u16(&mut header, 0); // Disk number start
+        put_u16(&mut header, 0); // Internal file attributes
+        put_u32(&mut header, self.external_attributes);
+        put_u32(&mut header, local_header_offset);
+        header.extend_from_slice(&self.name);
+        if !zip64_extra_field.is_empty() {
+            put_u16(&mut header, ZIP64_EXTRA_FIELD_ID);
+            put_u16(&mut header, zip64_extra_field.len() as u16);
+            header.extend_from_slice(&zip64_extra_field);
+        }
+
+        sink.write_all(&header)
+    }
+}
+
+/// Writes the end of central directory record, preceded by its ZIP64
+/// counterpart and locator if any of its fields do not fit.
+fn write_end_of_central_directory<W: Write>(
+    sink: &mut CountingWriter<W>,
+    entry_count: u64,
+    central_directory_size: u64,
+    central_directory_offset: u64,
+) -> Result<(), Error> {
+    let mut record = Vec::with_capacity(98);
+
+    let zip64 = entry_count >= u16::MAX as u64
+        || central_directory_size >= u32::MAX as u64
+        || central_directory_offset >= u32::MAX as u64;
+    if zip64 {
+        let zip64_record_offset = sink.count;
+
+        put_u32(&mut record, ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        put_u64(&mut record, 44); // Size of the rest of the record
+        put_u16(&mut record, VERSION_MADE_BY);
+        put_u16(&mut record, VERSION_ZIP64);
+        put_u32(&mut record, 0); // Number of this disk
+        put_u32(&mut record, 0); // Disk where the central directory starts
+        put_u64(&mut record, entry_count); // Entries on this disk
+        put_u64(&mut record, entry_count);
+        put_u64(&mut record, central_directory_size);
+        put_u64(&mut record, central_directory_offset);
+
+        put_u32(
+            &mut record,
+            ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE,
+        );
+        put_u32(&mut record, 0); // Disk where the ZIP64 record is
+        put_u64(&mut record, zip64_record_offset);
+        put_u32(&mut record, 1); // Total number of disks
+    }
+
+    let entry_count = entry_count.min(u16::MAX as u64) as u16;
+    put_u32(&mut record, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+    put_u16(&mut record, 0); // Number of this disk
+    put_u16(&mut record, 0); // Disk where the central directory starts
+    put_u16(&mut record, entry_count); // Entries on this disk
+    put_u16(&mut record, entry_count);
+    put_u32(
+        &mut record,
+        central_directory_size.min(u32::MAX as u64) as u32,
+    );
+    put_u32(
+        &mut record,
+        central_directory_offset.min(u32::MAX as u64) as u32,
+    );
+    put_u16(&mut record, 0); // Comment length
+
+    sink.write_all(&record)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn no_entry_error() -> Error {
+    #[cfg(all(not(doc), feature = "std"))]
+    {
+        Error::new(std::io::ErrorKind::Other, "no ZIP entry was started")
+    }
+    #[cfg(any(doc, not(feature = "std")))]
+    {
+        crate::io::ErrorKind::Other.into()
+    }
+}
+
+fn name_too_long_error() -> Error {
+    #[cfg(all(not(doc), feature = "std"))]
+    {
+        Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "ZIP entry name is longer than 65535 bytes",
+        )
+    }
+    #[cfg(any(doc, not(feature = "std")))]
+    {
+        crate::io::ErrorKind::Other.into()
+    }
+}
+
+fn failed_writer_error() -> Error {
+    #[cfg(all(not(doc), feature = "std"))]
+    {
+        Error::new(
+            std::io::ErrorKind::Other,
+            "ZIP writer can not be used after an error",
+        )
+    }
+    #[cfg(any(doc, not(feature = "std")))]
+    {
+        crate::io::ErrorKind::Other.into()
+    }
+}
+
+fn entry_too_large_error() -> Error {
+    #[cfg(all(not(doc), feature = "std"))]
+    {
+        Error::new(
+            std::io::ErrorKind::Other,
+            "streamed ZIP entry is too large without the large file option",
+        )
+    }
+    #[cfg(any(doc, not(feature = "std")))]
+    {
+        crate::io::ErrorKind::Other.into()
+    }
+}
+
+/// A writer that counts the bytes written to the wrapped one, to know the
+/// offsets of the entries in the archive.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+pub(crate) mod test {
+    use miniz_oxide::inflate::decompress_to_vec;
+
+    use super::*;
+
+    pub(crate) struct ReadEntry {
+        pub(crate) name: String,
+        pub(crate) method: u16,
+        pub(crate) external_attributes: u32,
+        pub(crate) data: Vec<u8>,
+    }
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Reads the entries of a ZIP archive through its central directory,
+    /// checking that the local headers and data descriptors agree with it.
+    pub(crate) fn read_entries(zip: &[u8]) -> Vec<ReadEntry> {
+        let mut eocd = (0..=zip.len() - 22)
+            .rev()
+            .find(|&offset| u32_at(zip, offset) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+            .unwrap();
+        let mut entry_count = u16_at(zip, eocd + 10) as u64;
+        let mut offset = u32_at(zip, eocd + 16) as u64;
+        if entry_count == u16::MAX as u64 {
+            let locator = eocd - 20;
+            assert_eq!(
+                u32_at(zip, locator),
+                ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE
+            );
+            eocd = u64_at(zip, locator + 8) as usize;
+            assert_eq!(u32_at(zip, eocd), ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+            entry_count = u64_at(zip, eocd + 32);
+            offset = u64_at(zip, eocd + 48);
+        }
+
+        let mut offset = offset as usize;
+        let mut entries = vec![];
+        for _ in 0..entry_count {
+            assert_eq!(u32_at(zip, offset), CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+            let flags = u16_at(zip, offset + 8);
+            let method = u16_at(zip, offset + 10);
+            let crc32 = u32_at(zip, offset + 16);
+            let mut compressed_size = u32_at(zip, offset + 20) as u64;
+            let mut uncompressed_size = u32_at(zip, offset + 24) as u64;
+            let name_length = u16_at(zip, offset + 28) as usize;
+            let extra_length = u16_at(zip, offset + 30) as usize;
+            let external_attributes = u32_at(zip, offset + 38);
+            let mut local_header = u32_at(zip, offset + 42) as u64;
+            let name = &zip[offset + 46..offset + 46 + name_length];
+
+            let mut zip64_fields = offset + 46 + name_length + 4;
+            for field in [
+                &mut uncompressed_size,
+                &mut compressed_size,
+                &mut local_header,
+            ] {
+                if *field == u32::MAX as u64 {
+                    *field = u64_at(zip, zip64_fields);
+                    zip64_fields += 8;
+                }
+            }
+
+            let local_header = local_header as usize;
+            assert_eq!(u32_at(zip, local_header), LOCAL_FILE_HEADER_SIGNATURE);
+            assert_eq!(u16_at(zip, local_header + 6), flags);
+            let local_name_length = u16_at(zip, local_header + 26) as usize;
+            let local_extra_length = u16_at(zip, local_header + 28) as usize;
+            assert_eq!(
+                &zip[local_header + 30..local_header + 30 + local_name_length],
+                name
+            );
+            let data_start = local_header + 30 + local_name_length + local_extra_length;
+            let stored_data = &zip[data_start..data_start + compressed_size as usize];
+
+            if flags & FLAG_DATA_DESCRIPTOR != 0 {
+                let descriptor = data_start + compressed_size as usize;
+                assert_eq!(u32_at(zip, descriptor), DATA_DESCRIPTOR_SIGNATURE);
+                assert_eq!(u32_at(zip, descriptor + 4), crc32);
+                if local_extra_length > 0 {
+                    assert_eq!(u64_at(zip, descriptor + 8), compressed_size);
+                    assert_eq!(u64_at(zip, descriptor + 16), uncompressed_size);
+                } else {
+                    assert_eq!(u32_at(zip, descriptor + 8) as u64, compressed_size);
+                }
+            } else {
+                assert_eq!(u32_at(zip, local_header + 14), crc32);
+            }
+
+            let data = match method {
+                METHOD_STORED => stored_data.to_vec(),
+                METHOD_DEFLATED => decompress_to_vec(stored_data).unwrap(),
+                _ => panic!("Unexpected compression method {}", method),
+            };
+            assert_eq!(data.len() as u64, uncompressed_size);
+            assert_eq!(crc32fast::hash(&data), crc32);
+
+            entries.push(ReadEntry {
+                name: String::from_utf8(name.to_vec()).unwrap(),
+                method,
+                external_attributes,
+                data,
+            });
+            offset += 46 + name_length + extra_length;
+        }
+
+        entries
+    }
+
+    #[test]
+    fn writes_every_kind_of_entry() {
+        let text = b"Zopfli compresses text like this very well. ".repeat(50);
+        let incompressible: Vec<u8> = crate::test_util::lcg(0x2545_f491)
+            .take(2000)
+            .map(|state| (state >> 24) as u8)
+            .collect();
+
+        let mut zip_writer = ZipWriter::new(Options::default(), Vec::new());
+        zip_writer
+            .start_file(
+                "mimetype",
+                ZipEntryOptions::new().compression(ZipCompression::Stored),
+            )
+            .unwrap();
+        zip_writer.write_all(b"application/epub+zip").unwrap();
+        zip_writer
+            .add_directory("tëxt", ZipEntryOptions::new())
+            .unwrap();
+        zip_writer
+            .start_file("tëxt/deflated.txt", ZipEntryOptions::new())
+            .unwrap();
+        zip_writer.write_all(&text).unwrap();
+        zip_writer
+            .start_file("incompressible.bin", ZipEntryOptions::new())
+            .unwrap();
+        zip_writer.write_all(&incompressible).unwrap();
+        for large_file in [false, true] {
+            zip_writer
+                .start_file(
+                    "streamed.txt",
+                    ZipEntryOptions::new()
+                        .compression(ZipCompression::DeflatedStream)
+                        .large_file(large_file)
+                        .unix_permissions(0o755),
+                )
+                .unwrap();
+            for chunk in text.chunks(100) {
+                zip_writer.write_all(chunk).unwrap();
+            }
+        }
+        let zip = zip_writer.finish().unwrap();
+
+        let entries = read_entries(&zip);
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.method, entry.data.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("mimetype", METHOD_STORED, 20),
+                ("tëxt/", METHOD_STORED, 0),
+                ("tëxt/deflated.txt", METHOD_DEFLATED, text.len()),
+                ("incompressible.bin", METHOD_STORED, incompressible.len()),
+                ("streamed.txt", METHOD_DEFLATED, text.len()),
+                ("streamed.txt", METHOD_DEFLATED, text.len()),
+            ]
+        );
+        assert_eq!(entries[2].data, text);
+        assert_eq!(entries[3].data, incompressible);
+        assert_eq!(entries[4].data, text);
+        assert_eq!(
+            entries[1].external_attributes,
+            (UNIX_DIRECTORY | 0o755) << 16 | DOS_DIRECTORY_ATTRIBUTE
+        );
+        assert_eq!(
+            entries[4].external_attributes,
+            (UNIX_REGULAR_FILE | 0o755) << 16
+        );
+        // The EPUB mimetype entry must start at a fixed offset
+        assert_eq!(&zip[30..38], b"mimetype");
+    }
+
+    #[test]
+    fn writes_zip64_end_of_central_directory_for_many_entries() {
+        let mut zip_writer = ZipWriter::new(Options::default(), Vec::new());
+        for i in 0..u16::MAX as u32 + 1 {
+            zip_writer
+                .add_directory(&i.to_string(), ZipEntryOptions::new())
+                .unwrap();
+        }
+        let zip = zip_writer.finish().unwrap();
+
+        let entries = read_entries(&zip);
+        assert_eq!(entries.len(), u16::MAX as usize + 1);
+        assert_eq!(entries.last().unwrap().name, "65535/");
+    }
+
+    #[test]
+    fn writing_without_entry_fails() {
+        let mut zip_writer = ZipWriter::new(Options::default(), Vec::new());
+        assert!(zip_writer.write_all(b"data").is_err());
+    }
+
+    #[test]
+    fn rejects_names_longer_than_65535_bytes() {
+        let mut zip_writer = ZipWriter::new(Options::default(), Vec::new());
+        let name = "a".repeat(u16::MAX as usize);
+
+        let error = zip_writer
+            .start_file(&format!("{}b", name), ZipEntryOptions::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        let error = zip_writer
+            .add_directory(&name, ZipEntryOptions::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        zip_writer
+            .add_directory(&format!("{}/", &name[1..]), ZipEntryOptions::new())
+            .unwrap();
+        assert_eq!(read_entries(&zip_writer.finish().unwrap()).len(), 1);
+    }
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> Result<usize, Error> {
+            Err(std::io::ErrorKind::Other.into())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fails_instead_of_panicking_after_an_error() {
+        let mut zip_writer = ZipWriter::new(Options::default(), FailingWriter);
+        zip_writer
+            .start_file("a.txt", ZipEntryOptions::new())
+            .unwrap();
+        zip_writer.write_all(b"data").unwrap();
+
+        assert!(zip_writer
+            .start_file("b.txt", ZipEntryOptions::new())
+            .is_err());
+        assert!(zip_writer
+            .add_directory("c/", ZipEntryOptions::new())
+            .is_err());
+        assert!(zip_writer.write_all(b"data").is_err());
+        assert!(zip_writer.finish().is_err());
+    }
+}
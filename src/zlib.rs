@@ -1,40 +1,62 @@
-use crate::{BlockType, DeflateEncoder, Error, Options, Write};
+use crate::{
+    deflate_slice, BlockType, CompressionStats, DeflateEncoder, Error, FlushMode, Options,
+    Progress, Write,
+};
 
 /// A Zlib encoder powered by the Zopfli algorithm, that compresses data using
 /// a [`DeflateEncoder`]. Most users will find using [`compress`](crate::compress)
 /// easier and more performant.
 ///
-/// The caveats about short writes in [`DeflateEncoder`]s carry over to `ZlibEncoder`s:
-/// for best performance and compression, it is best to avoid them. One way to ensure
-/// this is to use the [`new_buffered`](ZlibEncoder::new_buffered) method.
+/// Like [`DeflateEncoder`]s, `ZlibEncoder`s collect written data into master
+/// blocks, so short writes do not hurt compression.
 pub struct ZlibEncoder<W: Write> {
     deflate_encoder: Option<DeflateEncoder<W>>,
     adler_hasher: simd_adler32::Adler32,
+    header_size: usize,
 }
 
 impl<W: Write> ZlibEncoder<W> {
     /// Creates a new Zlib encoder that will operate according to the
     /// specified options.
     pub fn new(options: Options, btype: BlockType, mut sink: W) -> Result<Self, Error> {
-        let cmf = 120; // CM 8, CINFO 7. See zlib spec.
-        let flevel = 3;
-        let fdict = 0;
-        let mut cmfflg: u16 = 256 * cmf + fdict * 32 + flevel * 64;
-        let fcheck = 31 - cmfflg % 31;
-        cmfflg += fcheck;
-
-        sink.write_all(&cmfflg.to_be_bytes())?;
+        write_header(false, &mut sink)?;
 
         Ok(Self {
             deflate_encoder: Some(DeflateEncoder::new(options, btype, sink)),
             adler_hasher: simd_adler32::Adler32::new(),
+            header_size: 2,
+        })
+    }
+
+    /// Creates a new Zlib encoder that will operate according to the
+    /// specified options, using the specified preset dictionary as initial
+    /// backreference window. The Adler-32 checksum of the dictionary is stored
+    /// in the header, so that decoders can tell which dictionary they need.
+    /// See [`DeflateEncoder::new_with_dictionary`] for more details.
+    pub fn new_with_dictionary(
+        options: Options,
+        btype: BlockType,
+        dictionary: &[u8],
+        mut sink: W,
+    ) -> Result<Self, Error> {
+        write_header(true, &mut sink)?;
+
+        let mut dictionary_hasher = simd_adler32::Adler32::new();
+        dictionary_hasher.write(dictionary);
+        sink.write_all(&dictionary_hasher.finish().to_be_bytes())?;
+
+        Ok(Self {
+            deflate_encoder: Some(DeflateEncoder::new_with_dictionary(
+                options, btype, dictionary, sink,
+            )),
+            adler_hasher: simd_adler32::Adler32::new(),
+            header_size: 6,
         })
     }
 
     /// Creates a new Zlib encoder that operates according to the specified
-    /// options and is wrapped with a buffer to guarantee that data is
-    /// compressed in large chunks, which is necessary for decent performance
-    /// and good compression ratio.
+    /// options and is wrapped with a buffer of the default master block size,
+    /// which reduces the number of calls to the encoder.
     #[cfg(feature = "std")]
     pub fn new_buffered(
         options: Options,
@@ -47,6 +69,23 @@ impl<W: Write> ZlibEncoder<W> {
         ))
     }
 
+    /// Creates a new Zlib encoder that operates according to the specified
+    /// options and preset dictionary, as described in
+    /// [`new_with_dictionary`](Self::new_with_dictionary), and is wrapped with
+    /// a buffer of the default master block size.
+    #[cfg(feature = "std")]
+    pub fn new_buffered_with_dictionary(
+        options: Options,
+        btype: BlockType,
+        dictionary: &[u8],
+        sink: W,
+    ) -> Result<std::io::BufWriter<Self>, Error> {
+        Ok(std::io::BufWriter::with_capacity(
+            crate::util::ZOPFLI_MASTER_BLOCK_SIZE,
+            Self::new_with_dictionary(options, btype, dictionary, sink)?,
+        ))
+    }
+
     /// Encodes any pending chunks of data and writes them to the sink,
     /// consuming the encoder and returning the wrapped sink. The sink
     /// will have received a complete Zlib stream when this method
@@ -56,22 +95,95 @@ impl<W: Write> ZlibEncoder<W> {
     /// dropped, but explicitly finishing it with this method allows
     /// handling I/O errors.
     pub fn finish(mut self) -> Result<W, Error> {
-        self._finish().map(|sink| sink.unwrap())
+        self._finish().map(|finished| finished.unwrap().0)
+    }
+
+    /// Like [`finish`](Self::finish), but also returns statistics about the
+    /// compressed stream.
+    pub fn finish_with_stats(mut self) -> Result<(W, CompressionStats), Error> {
+        self._finish().map(Option::unwrap)
+    }
+
+    /// Sets a callback that will be notified of the progress of the
+    /// compression, and may cancel it, as described in [`Progress`].
+    /// This replaces any previously set callback.
+    pub fn set_progress(&mut self, progress: impl Progress + Send + 'static) {
+        self.deflate_encoder
+            .as_mut()
+            .unwrap()
+            .set_progress(progress);
+    }
+
+    /// Sets what flushing the encoder does with the data written to it, as
+    /// described in [`FlushMode`].
+    pub fn set_flush_mode(&mut self, flush_mode: FlushMode) {
+        self.deflate_encoder
+            .as_mut()
+            .unwrap()
+            .set_flush_mode(flush_mode);
+    }
+
+    /// Gets a mutable reference to the sink, as described in
+    /// [`DeflateEncoder::get_mut`].
+    #[cfg(feature = "std")]
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        self.deflate_encoder.as_mut().unwrap().get_mut()
     }
 
-    fn _finish(&mut self) -> Result<Option<W>, Error> {
+    /// Drops the data written so far without compressing it, as described in
+    /// [`DeflateEncoder::discard`].
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub(crate) fn discard(&mut self) {
+        if let Some(mut deflate_encoder) = self.deflate_encoder.take() {
+            deflate_encoder.discard();
+        }
+    }
+
+    fn _finish(&mut self) -> Result<Option<(W, CompressionStats)>, Error> {
         if self.deflate_encoder.is_none() {
             return Ok(None);
         }
 
-        let mut sink = self.deflate_encoder.take().unwrap().finish()?;
+        let (mut sink, mut stats) = self.deflate_encoder.take().unwrap().finish_with_stats()?;
 
         sink.write_all(&self.adler_hasher.finish().to_be_bytes())?;
+        stats.output_size += self.header_size as u64 + 4;
 
-        Ok(Some(sink))
+        Ok(Some((sink, stats)))
     }
 }
 
+/// Writes the CMF and FLG bytes of a Zlib stream header, setting the FDICT
+/// flag if a preset dictionary is used.
+fn write_header<W: Write>(use_dictionary: bool, sink: &mut W) -> Result<(), Error> {
+    let cmf = 120; // CM 8, CINFO 7. See zlib spec.
+    let flevel = 3;
+    let fdict = use_dictionary as u16;
+    let mut cmfflg: u16 = 256 * cmf + fdict * 32 + flevel * 64;
+    let fcheck = 31 - cmfflg % 31;
+    cmfflg += fcheck;
+
+    sink.write_all(&cmfflg.to_be_bytes())
+}
+
+/// Writes a Zlib stream that stores data compressed with [`deflate_slice`].
+pub(crate) fn zlib_slice<W: Write>(
+    options: Options,
+    btype: BlockType,
+    data: &[u8],
+    mut sink: W,
+) -> Result<W, Error> {
+    write_header(false, &mut sink)?;
+
+    let mut sink = deflate_slice(options, btype, data, sink)?;
+
+    let mut adler_hasher = simd_adler32::Adler32::new();
+    adler_hasher.write(data);
+    sink.write_all(&adler_hasher.finish().to_be_bytes())?;
+
+    Ok(sink)
+}
+
 impl<W: Write> Write for ZlibEncoder<W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         self.deflate_encoder
@@ -106,3 +218,40 @@ impl<W: crate::io::Write> std::io::Write for ZlibEncoder<W> {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deflate::test::inflate_with_dictionary;
+
+    #[test]
+    fn preset_dictionary_is_identified_in_header() {
+        let dictionary = b"zopfli zlib dictionary";
+        let data = b"zopfli zlib data";
+
+        let mut encoder = ZlibEncoder::new_with_dictionary(
+            Options::default(),
+            BlockType::Dynamic,
+            dictionary,
+            vec![],
+        )
+        .unwrap();
+        encoder.write_all(data).unwrap();
+        let compressed_data = encoder.finish().unwrap();
+
+        let cmfflg = u16::from_be_bytes([compressed_data[0], compressed_data[1]]);
+        assert_eq!(cmfflg % 31, 0, "FCHECK should be valid");
+        assert_ne!(cmfflg & 0x20, 0, "FDICT should be set");
+
+        let mut dictionary_hasher = simd_adler32::Adler32::new();
+        dictionary_hasher.write(dictionary);
+        assert_eq!(
+            compressed_data[2..6],
+            dictionary_hasher.finish().to_be_bytes(),
+            "DICTID should be the Adler-32 of the dictionary"
+        );
+
+        let deflate_data = &compressed_data[6..compressed_data.len() - 4];
+        assert_eq!(inflate_with_dictionary(deflate_data, dictionary), data);
+    }
+}
@@ -5,11 +5,32 @@ set -o pipefail
 # Clean output from previous runs
 rm -f test/data/*.gz
 
-# Test all input cases individually so we can report which failed
+# Compressing the standard input saves no file name nor time stamp, even
+# though they are saved by default. This runs before any compressed file is
+# written to test/data, so only the original inputs are compressed
+for input in test/data/*; do
+	printf "Compressing ${input} from stdin... "
+	cat ${input} | ./zopfli -c - | cmp - test/results/$(basename ${input}).gz || exit 1
+	echo "done"
+done
+
+# Test all input cases individually so we can report which failed. The file
+# name and time stamp are not saved, so the output is the same on every checkout
 for input in test/data/*; do
 	printf "Compressing ${input}... "
-	env RUST_BACKTRACE=1 ./zopfli ${input} && echo "done"
+	env RUST_BACKTRACE=1 ./zopfli -n ${input} && echo "done"
+done
+
+# Files named like the commands are compressed when they follow --
+commands_dir=$(mktemp -d)
+for command in png recompress precompress; do
+	printf "Compressing a file named ${command}... "
+	echo "Not a command" > ${commands_dir}/${command}
+	(cd ${commands_dir} && "${OLDPWD}/zopfli" -- ${command})
+	gzip -dc ${commands_dir}/${command}.gz | cmp - ${commands_dir}/${command} || exit 1
+	echo "done"
 done
+rm -rf ${commands_dir}
 
 # Move newly compressed data to its own directory
 mkdir -p test/temp_compressed/